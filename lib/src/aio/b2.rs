@@ -15,29 +15,17 @@ use hyper::Client;
 use hyper_native_tls::NativeTlsClient;
 use sgdata::SGData;
 
+use super::lease::{LeaseLock, LeasePoison};
 use super::Metadata;
 use super::{Backend, BackendThread};
 use crate::aio;
 use crate::config;
 
-// TODO: make a thread, that keeps updating
-// a timestamp file on the backend
-pub struct Lock {
-    path: PathBuf,
-}
-
-impl Lock {
-    fn new(path: PathBuf) -> Self {
-        Lock { path }
-    }
-}
-
-impl aio::Lock for Lock {}
-
 #[derive(Debug)]
 pub struct B2 {
     cred: B2Credentials,
     bucket: String,
+    poison: LeasePoison,
 }
 
 pub struct Auth {
@@ -50,6 +38,7 @@ pub struct B2Thread {
     auth: RefCell<Option<Auth>>,
     client: Client,
     bucket: String,
+    poison: LeasePoison,
 }
 
 /// Retry operations that can fail due to network/service issues
@@ -57,6 +46,9 @@ fn retry<F, R>(instance: Option<&B2Thread>, f: F) -> io::Result<R>
 where
     F: Fn() -> Result<R, B2Error>,
 {
+    if let Some(instance) = instance {
+        instance.poison.check()?;
+    }
     let mut backoff = 1;
     let mut err_counter = 0;
     loop {
@@ -112,7 +104,11 @@ impl B2Thread {
         Ok(())
     }
 
-    fn new_from_cred(cred: &B2Credentials, bucket: String) -> io::Result<Self> {
+    fn new_from_cred(
+        cred: &B2Credentials,
+        bucket: String,
+        poison: LeasePoison,
+    ) -> io::Result<Self> {
         let ssl = NativeTlsClient::new().map_err(|e| {
             io::Error::new(
                 io::ErrorKind::ConnectionAborted,
@@ -127,6 +123,7 @@ impl B2Thread {
             client,
             auth: RefCell::new(None),
             bucket,
+            poison,
         };
 
         i.reauth()?;
@@ -137,17 +134,26 @@ impl B2Thread {
 
 impl Backend for B2 {
     fn lock_exclusive(&self) -> io::Result<Box<dyn aio::Lock>> {
-        Ok(Box::new(Lock::new(PathBuf::from(config::LOCK_FILE))))
+        Ok(Box::new(LeaseLock::acquire(
+            self.new_thread()?,
+            true,
+            &self.poison,
+        )?))
     }
 
     fn lock_shared(&self) -> io::Result<Box<dyn aio::Lock>> {
-        Ok(Box::new(Lock::new(PathBuf::from(config::LOCK_FILE))))
+        Ok(Box::new(LeaseLock::acquire(
+            self.new_thread()?,
+            false,
+            &self.poison,
+        )?))
     }
 
     fn new_thread(&self) -> io::Result<Box<dyn BackendThread>> {
         Ok(Box::new(B2Thread::new_from_cred(
             &self.cred,
             self.bucket.clone(),
+            self.poison.clone(),
         )?))
     }
 }
@@ -162,6 +168,7 @@ impl B2 {
         B2 {
             cred,
             bucket: bucket.into(),
+            poison: LeasePoison::default(),
         }
     }
 }
@@ -6,8 +6,9 @@ use sgdata::SGData;
 
 /// A lock held on the backend
 ///
-/// It doesn't do much, except unlock on `drop`.
-pub trait Lock {}
+/// It doesn't do much, except unlock on `drop`. It can be moved to another
+/// thread along with the reader it protects.
+pub trait Lock: Send {}
 
 /// Backend API
 ///
@@ -1,312 +1,463 @@
-use crate::aio::Metadata;
-use crate::backends::{Backend, BackendThread, Lock};
-use reqwest::blocking::Client;
-use serde::Deserialize;
-use sgdata::SGData;
-use std::path::PathBuf;
-use std::sync::mpsc::Sender;
-use std::{io, mem};
-use url::Url;
-
-// TODO: This is meant to be a "read-only" http backend that can get data from a remote repository,
-// ideally served very efficiently with a static web server (for instance, rust's static-web-server
-// crate/binary).
-// Therefore a mechanism to implement a file lock is not really possible in such an architecture...
-// For now we ignore it because in practice only GC and rm operations need an exclusive lock (which
-// waits for all exclusive locks to have expired); so we can get quite far running like this as long as
-// we are careful. Eventually I would like to find a solution of sorts.
-struct NoLock {}
-
-impl Lock for NoLock {}
-
-#[derive(Deserialize)]
-struct FileInfo {
-    name: String,
-    mtime: String,
-    size: Option<u64>,
-    #[serde(rename = "type")]
-    file_type: String,
-}
-
-pub struct HttpReadOnly {
-    base_url: Url,
-    client: Client,
-}
-
-impl HttpReadOnly {
-    pub fn new(base_url: Url) -> Self {
-        HttpReadOnly {
-            base_url,
-            client: Client::new(),
-        }
-    }
-}
-
-pub struct HttpReadOnlyThread {
-    base_url: Url,
-    client: Client,
-}
-
-impl HttpReadOnlyThread {
-    pub fn new(base_url: Url, client: Client) -> Self {
-        HttpReadOnlyThread { base_url, client }
-    }
-
-    fn get_endpoint(&self, path: PathBuf) -> Result<Url, io::Error> {
-        self.base_url.join(path.to_str().unwrap()).map_err(|e| {
-            io::Error::new(
-                io::ErrorKind::InvalidData,
-                format!("Failed to build endpoint: {}", e),
-            )
-        })
-    }
-
-    fn get_blocking(
-        &self,
-        path: PathBuf,
-    ) -> io::Result<reqwest::blocking::Response> {
-        self.client
-            .get(self.get_endpoint(path)?)
-            .send()
-            .map_err(|e| {
-                io::Error::new(
-                    io::ErrorKind::ConnectionAborted,
-                    format!("Request failed: {:?}", e),
-                )
-            })
-    }
-}
-
-impl Backend for HttpReadOnly {
-    fn lock_exclusive(&self) -> io::Result<Box<dyn Lock>> {
-        Err(io::Error::new(
-            io::ErrorKind::ReadOnlyFilesystem,
-            "Static HTTP endpoint is read-only",
-        ))
-    }
-
-    fn lock_shared(&self) -> io::Result<Box<dyn Lock>> {
-        Ok(Box::new(NoLock {}))
-    }
-
-    fn new_thread(&self) -> io::Result<Box<dyn BackendThread>> {
-        Ok(Box::new(HttpReadOnlyThread::new(
-            self.base_url.clone(),
-            self.client.clone(),
-        )))
-    }
-}
-
-fn get_content_type(
-    response: &reqwest::blocking::Response,
-) -> io::Result<&reqwest::header::HeaderValue> {
-    response
-        .headers()
-        .get(reqwest::header::CONTENT_TYPE)
-        .ok_or(io::Error::new(
-            io::ErrorKind::InvalidData,
-            "No content type header",
-        ))
-}
-
-fn is_file_content_type(header: &reqwest::header::HeaderValue) -> bool {
-    header == "application/octet-stream" || header == "text/x-yaml"
-}
-
-fn is_directory_content_type(header: &reqwest::header::HeaderValue) -> bool {
-    header == "application/json"
-}
-
-impl BackendThread for HttpReadOnlyThread {
-    fn remove_dir_all(&mut self, _path: PathBuf) -> io::Result<()> {
-        Err(io::Error::new(
-            io::ErrorKind::ReadOnlyFilesystem,
-            "Static HTTP endpoint is read-only",
-        ))
-    }
-
-    fn rename(
-        &mut self,
-        _src_path: PathBuf,
-        _dst_path: PathBuf,
-    ) -> io::Result<()> {
-        Err(io::Error::new(
-            io::ErrorKind::ReadOnlyFilesystem,
-            "Static HTTP endpoint is read-only",
-        ))
-    }
-
-    fn write(
-        &mut self,
-        _path: PathBuf,
-        _sg: SGData,
-        _idempotent: bool,
-    ) -> io::Result<()> {
-        Err(io::Error::new(
-            io::ErrorKind::ReadOnlyFilesystem,
-            "Static HTTP endpoint is read-only",
-        ))
-    }
-
-    fn read(&mut self, path: PathBuf) -> io::Result<SGData> {
-        let response = self.get_blocking(path)?;
-        let content_type = get_content_type(&response)?;
-        if !is_file_content_type(content_type) {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                format!(
-                    "Invalid content type {}",
-                    content_type.to_str().unwrap()
-                ),
-            ));
-        }
-
-        let data = response.bytes().map_err(|e| {
-            io::Error::new(
-                io::ErrorKind::ConnectionAborted,
-                format!("Failed to read response as bytes: {}", e),
-            )
-        })?;
-
-        Ok(SGData::from_single(data.into()))
-    }
-
-    fn remove(&mut self, _path: PathBuf) -> io::Result<()> {
-        Err(io::Error::new(
-            io::ErrorKind::ReadOnlyFilesystem,
-            "Static HTTP endpoint is read-only",
-        ))
-    }
-
-    fn read_metadata(&mut self, path: PathBuf) -> io::Result<Metadata> {
-        let as_path = path.as_path();
-        let filename =
-            as_path.file_name().unwrap().to_str().unwrap().to_string();
-        let response =
-            self.get_blocking(PathBuf::from(as_path.parent().unwrap()))?;
-        let content_type = get_content_type(&response)?;
-
-        if !is_directory_content_type(content_type) {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                format!(
-                    "Invalid content type '{:?}'",
-                    content_type.to_str().unwrap()
-                ),
-            ));
-        }
-
-        let file_list: Vec<FileInfo> = response.json().map_err(|e| {
-            io::Error::new(
-                io::ErrorKind::InvalidData,
-                format!("Failed to parse response as JSON: {:?}", e),
-            )
-        })?;
-        let file: &FileInfo = file_list
-            .iter()
-            .find(|f| f.name == filename)
-            .ok_or(io::Error::new(io::ErrorKind::NotFound, "File not found"))?;
-        if !file.file_type.eq("file") {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                format!("{} is not a file", filename),
-            ));
-        }
-        let m_datetime = chrono::DateTime::parse_from_rfc3339(
-            file.mtime.as_str(),
-        )
-        .map_err(|e| {
-            io::Error::new(
-                io::ErrorKind::InvalidData,
-                format!("Failed to parse mtime to a datetime: {:?}", e),
-            )
-        })?;
-        Ok(Metadata {
-            len: file
-                .size
-                .expect("This is a file, so it should have a size."),
-            is_file: true,
-            created: m_datetime.into(),
-        })
-    }
-
-    fn list(&mut self, path: PathBuf) -> io::Result<Vec<PathBuf>> {
-        let response = self.get_blocking(path.clone())?;
-        if !response.status().is_success() {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                format!("Bad response code: {}", response.status()),
-            ));
-        }
-        let content_type = get_content_type(&response)?;
-        if !is_directory_content_type(content_type) {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                format!(
-                    "Invalid content type {}",
-                    content_type.to_str().unwrap()
-                ),
-            ));
-        }
-        let file_list: Vec<FileInfo> = response.json().map_err(|e| {
-            io::Error::new(
-                io::ErrorKind::InvalidData,
-                format!("Failed to parse response as JSON: {:?}", e),
-            )
-        })?;
-        let mut result = Vec::with_capacity(128);
-        for file in file_list {
-            result.push(path.join(file.name));
-        }
-        Ok(result)
-    }
-
-    fn list_recursively(
-        &mut self,
-        path: PathBuf,
-        tx: Sender<io::Result<Vec<PathBuf>>>,
-    ) {
-        let mut to_process = vec![path];
-        let mut result = Vec::with_capacity(128);
-        while let Some(path) = to_process.pop() {
-            let response = self.get_blocking(path.clone()).unwrap();
-            if !response.status().is_success() {
-                tx.send(Err(io::Error::new(
-                    io::ErrorKind::InvalidData,
-                    format!("Bad response code: {}", response.status()),
-                )))
-                .expect("Send failed");
-                continue;
-            }
-            let content_type = get_content_type(&response).unwrap();
-            if !is_directory_content_type(content_type) {
-                tx.send(Err(io::Error::new(
-                    io::ErrorKind::InvalidData,
-                    format!(
-                        "Invalid content type {}",
-                        content_type.to_str().unwrap()
-                    ),
-                )))
-                .expect("Send failed");
-                continue;
-            }
-
-            let file_list: Vec<FileInfo> = response.json().unwrap_or_default();
-            for file in file_list {
-                let file_path = path.join(file.name);
-                if file.file_type == "file" {
-                    result.push(file_path);
-                } else {
-                    to_process.push(file_path)
-                }
-            }
-
-            if result.len() > 100 {
-                tx.send(Ok(mem::take(&mut result))).expect("Send failed");
-            }
-        }
-        if !result.is_empty() {
-            tx.send(Ok(result)).expect("Send failed");
-        }
-    }
-}
+//! HTTP backend
+//!
+//! Reading works with any static web server producing `static-web-server`
+//! compatible JSON directory listings. Writing and locking requires a server
+//! implementing the methods of `rdedup-server` (see `rdedup_lib::server`);
+//! against a static server, all the mutating operations fail with
+//! `ReadOnlyFilesystem`.
+use crate::aio::lease::LeasePoison;
+use crate::aio::Metadata;
+use crate::backends::{Backend, BackendThread, Lock};
+use reqwest::blocking::{Client, RequestBuilder, Response};
+use reqwest::{Method, StatusCode};
+use serde::Deserialize;
+use sgdata::SGData;
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Sender};
+use std::time::{Duration, Instant};
+use std::{io, mem, thread};
+use url::Url;
+
+// A static web server can't implement a file lock, so when the server
+// doesn't support locking we ignore it for reading, because in practice only
+// GC and rm operations need an exclusive lock (which waits for all exclusive
+// locks to have expired); so we can get quite far running like this as long
+// as we are careful.
+struct NoLock {}
+
+impl Lock for NoLock {}
+
+/// Lock held by `rdedup-server` on our behalf
+///
+/// It's refreshed from a background thread, and released on drop. If the
+/// server doesn't hold it anymore, or it couldn't be refreshed before it
+/// expired, the backend gets poisoned, failing all its further operations.
+struct HttpLock {
+    stop_tx: Option<Sender<()>>,
+    refresher: Option<thread::JoinHandle<()>>,
+}
+
+#[derive(Deserialize)]
+struct LockInfo {
+    id: String,
+    ttl_secs: u64,
+}
+
+impl HttpLock {
+    /// Acquire a lock, blocking until it's possible
+    ///
+    /// Returns `None` if the server doesn't support locking.
+    fn acquire(
+        base_url: &Url,
+        exclusive: bool,
+        poison: &LeasePoison,
+    ) -> io::Result<Option<Self>> {
+        // Waiting for the lock can take arbitrarily long
+        let client = Client::builder()
+            .timeout(None)
+            .build()
+            .map_err(request_error)?;
+        let mode = if exclusive { "exclusive" } else { "shared" };
+        let response = client
+            .post(endpoint(base_url, &format!(".lock/{}", mode))?)
+            .send()
+            .map_err(request_error)?;
+
+        if matches!(
+            response.status(),
+            StatusCode::NOT_FOUND
+                | StatusCode::METHOD_NOT_ALLOWED
+                | StatusCode::NOT_IMPLEMENTED
+        ) {
+            return Ok(None);
+        }
+        let info: LockInfo = check_status(response)?.json().map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Failed to parse response as JSON: {:?}", e),
+            )
+        })?;
+
+        let lock_url = endpoint(base_url, &format!(".lock/{}", info.id))?;
+        let ttl = Duration::from_secs(info.ttl_secs);
+        let poison = poison.clone();
+        let (stop_tx, stop_rx) = mpsc::channel::<()>();
+        let refresher = thread::spawn(move || {
+            let mut expires = Instant::now() + ttl;
+            while let Err(mpsc::RecvTimeoutError::Timeout) =
+                stop_rx.recv_timeout(ttl / 3)
+            {
+                let sent = Instant::now();
+                match client.put(lock_url.clone()).send() {
+                    Ok(response) if response.status().is_success() => {
+                        expires = sent + ttl;
+                        continue;
+                    }
+                    // Transport failures are retried on the next tick, as
+                    // long as the lock is still held
+                    Err(_) if Instant::now() < expires => continue,
+                    // The server released the lock (or never had it), or
+                    // it expired; someone else could hold it now
+                    _ => {}
+                }
+                poison.poison();
+                return;
+            }
+            // Best effort; the server will release it after `ttl_secs`
+            let _ = client.delete(lock_url).send();
+        });
+
+        Ok(Some(HttpLock {
+            stop_tx: Some(stop_tx),
+            refresher: Some(refresher),
+        }))
+    }
+}
+
+impl Lock for HttpLock {}
+
+impl Drop for HttpLock {
+    fn drop(&mut self) {
+        drop(self.stop_tx.take());
+        if let Some(refresher) = self.refresher.take() {
+            let _ = refresher.join();
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct FileInfo {
+    name: String,
+    #[serde(rename = "type")]
+    file_type: String,
+}
+
+pub struct Http {
+    base_url: Url,
+    client: Client,
+    /// Set once a lock is lost
+    poison: LeasePoison,
+}
+
+impl Http {
+    pub fn new(base_url: Url) -> Self {
+        Http {
+            base_url,
+            client: Client::new(),
+            poison: LeasePoison::default(),
+        }
+    }
+}
+
+pub struct HttpThread {
+    base_url: Url,
+    client: Client,
+    poison: LeasePoison,
+}
+
+fn endpoint(base_url: &Url, path: &str) -> io::Result<Url> {
+    base_url.join(path).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Failed to build endpoint: {}", e),
+        )
+    })
+}
+
+fn request_error(e: reqwest::Error) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::ConnectionAborted,
+        format!("Request failed: {:?}", e),
+    )
+}
+
+fn read_only_error() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::ReadOnlyFilesystem,
+        "Static HTTP endpoint is read-only",
+    )
+}
+
+/// Convert unsuccessful responses to errors
+fn check_status(response: Response) -> io::Result<Response> {
+    match response.status() {
+        s if s.is_success() => Ok(response),
+        StatusCode::NOT_FOUND => {
+            Err(io::Error::new(io::ErrorKind::NotFound, "File not found"))
+        }
+        StatusCode::METHOD_NOT_ALLOWED | StatusCode::NOT_IMPLEMENTED => {
+            Err(read_only_error())
+        }
+        StatusCode::FORBIDDEN => Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "Access forbidden",
+        )),
+        s => Err(io::Error::other(format!("Bad response code: {}", s))),
+    }
+}
+
+impl HttpThread {
+    pub fn new(base_url: Url, client: Client, poison: LeasePoison) -> Self {
+        HttpThread {
+            base_url,
+            client,
+            poison,
+        }
+    }
+
+    fn get_endpoint(&self, path: PathBuf) -> Result<Url, io::Error> {
+        endpoint(&self.base_url, path.to_str().unwrap())
+    }
+
+    fn send(&self, request: RequestBuilder) -> io::Result<Response> {
+        self.poison.check()?;
+        request.send().map_err(request_error)
+    }
+
+    fn get_blocking(
+        &self,
+        path: PathBuf,
+    ) -> io::Result<reqwest::blocking::Response> {
+        self.send(self.client.get(self.get_endpoint(path)?))
+    }
+
+    /// List a directory; a missing directory is empty
+    fn list_dir(&self, path: PathBuf) -> io::Result<Vec<FileInfo>> {
+        let response = match check_status(self.get_blocking(path)?) {
+            Ok(response) => response,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                return Ok(vec![])
+            }
+            Err(e) => return Err(e),
+        };
+        let content_type = get_content_type(&response)?;
+        if !is_directory_content_type(content_type) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Invalid content type {}",
+                    content_type.to_str().unwrap()
+                ),
+            ));
+        }
+        response.json().map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Failed to parse response as JSON: {:?}", e),
+            )
+        })
+    }
+}
+
+impl Backend for Http {
+    fn lock_exclusive(&self) -> io::Result<Box<dyn Lock>> {
+        match HttpLock::acquire(&self.base_url, true, &self.poison)? {
+            Some(lock) => Ok(Box::new(lock)),
+            None => Err(read_only_error()),
+        }
+    }
+
+    fn lock_shared(&self) -> io::Result<Box<dyn Lock>> {
+        match HttpLock::acquire(&self.base_url, false, &self.poison)? {
+            Some(lock) => Ok(Box::new(lock)),
+            None => Ok(Box::new(NoLock {})),
+        }
+    }
+
+    fn new_thread(&self) -> io::Result<Box<dyn BackendThread>> {
+        Ok(Box::new(HttpThread::new(
+            self.base_url.clone(),
+            self.client.clone(),
+            self.poison.clone(),
+        )))
+    }
+}
+
+fn get_content_type(
+    response: &reqwest::blocking::Response,
+) -> io::Result<&reqwest::header::HeaderValue> {
+    response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .ok_or(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "No content type header",
+        ))
+}
+
+fn is_file_content_type(header: &reqwest::header::HeaderValue) -> bool {
+    header == "application/octet-stream" || header == "text/x-yaml"
+}
+
+fn is_directory_content_type(header: &reqwest::header::HeaderValue) -> bool {
+    header == "application/json"
+}
+
+impl BackendThread for HttpThread {
+    fn remove_dir_all(&mut self, path: PathBuf) -> io::Result<()> {
+        let url = endpoint(
+            &self.base_url,
+            &format!("{}/", path.to_str().unwrap().trim_end_matches('/')),
+        )?;
+        check_status(self.send(self.client.delete(url))?)?;
+        Ok(())
+    }
+
+    fn rename(
+        &mut self,
+        src_path: PathBuf,
+        dst_path: PathBuf,
+    ) -> io::Result<()> {
+        let request = self
+            .client
+            .request(
+                Method::from_bytes(b"MOVE").expect("valid method"),
+                self.get_endpoint(src_path)?,
+            )
+            .header("Destination", self.get_endpoint(dst_path)?.as_str());
+        check_status(self.send(request)?)?;
+        Ok(())
+    }
+
+    fn write(
+        &mut self,
+        path: PathBuf,
+        sg: SGData,
+        idempotent: bool,
+    ) -> io::Result<()> {
+        let mut request = self
+            .client
+            .put(self.get_endpoint(path)?)
+            .body(sg.into_linear_vec());
+        if idempotent {
+            request = request.header("If-None-Match", "*");
+        }
+        let response = self.send(request)?;
+        if idempotent && response.status() == StatusCode::PRECONDITION_FAILED {
+            return Ok(());
+        }
+        check_status(response)?;
+        Ok(())
+    }
+
+    fn read(&mut self, path: PathBuf) -> io::Result<SGData> {
+        let response = check_status(self.get_blocking(path)?)?;
+        let content_type = get_content_type(&response)?;
+        if !is_file_content_type(content_type) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Invalid content type {}",
+                    content_type.to_str().unwrap()
+                ),
+            ));
+        }
+
+        let data = response.bytes().map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                format!("Failed to read response as bytes: {}", e),
+            )
+        })?;
+
+        Ok(SGData::from_single(data.into()))
+    }
+
+    fn remove(&mut self, path: PathBuf) -> io::Result<()> {
+        check_status(self.send(self.client.delete(self.get_endpoint(path)?))?)?;
+        Ok(())
+    }
+
+    fn read_metadata(&mut self, path: PathBuf) -> io::Result<Metadata> {
+        let url = self.get_endpoint(path.clone())?;
+        let response = check_status(self.send(self.client.head(url))?)?;
+        if !is_file_content_type(get_content_type(&response)?) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} is not a file", path.display()),
+            ));
+        }
+        let header = |name: reqwest::header::HeaderName| {
+            response
+                .headers()
+                .get(&name)
+                .and_then(|value| value.to_str().ok())
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("No {} header", name),
+                    )
+                })
+        };
+        let len =
+            header(reqwest::header::CONTENT_LENGTH)?
+                .parse()
+                .map_err(|e| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("Failed to parse the length: {:?}", e),
+                    )
+                })?;
+        let m_datetime = chrono::DateTime::parse_from_rfc2822(header(
+            reqwest::header::LAST_MODIFIED,
+        )?)
+        .map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Failed to parse mtime to a datetime: {:?}", e),
+            )
+        })?;
+        Ok(Metadata {
+            len,
+            is_file: true,
+            created: m_datetime.into(),
+        })
+    }
+
+    fn list(&mut self, path: PathBuf) -> io::Result<Vec<PathBuf>> {
+        let file_list = self.list_dir(path.clone())?;
+        let mut result = Vec::with_capacity(128);
+        for file in file_list {
+            result.push(path.join(file.name));
+        }
+        Ok(result)
+    }
+
+    fn list_recursively(
+        &mut self,
+        path: PathBuf,
+        tx: Sender<io::Result<Vec<PathBuf>>>,
+    ) {
+        let mut to_process = vec![path];
+        let mut result = Vec::with_capacity(128);
+        while let Some(path) = to_process.pop() {
+            let file_list = match self.list_dir(path.clone()) {
+                Ok(file_list) => file_list,
+                Err(e) => {
+                    if tx.send(Err(e)).is_err() {
+                        return;
+                    }
+                    continue;
+                }
+            };
+            for file in file_list {
+                let file_path = path.join(file.name);
+                if file.file_type == "file" {
+                    result.push(file_path);
+                } else {
+                    to_process.push(file_path)
+                }
+            }
+
+            // Stop listing once nobody is waiting for the rest
+            if result.len() > 100
+                && tx.send(Ok(mem::take(&mut result))).is_err()
+            {
+                return;
+            }
+        }
+        if !result.is_empty() {
+            let _ = tx.send(Ok(result));
+        }
+    }
+}
@@ -0,0 +1,306 @@
+//! Lease-based repository lock for backends without native locking
+//!
+//! Every lock holder writes a lease object into the `.lock` directory of
+//! the backend, and keeps refreshing it from a background thread until
+//! the lock is dropped. A lease that was not refreshed before its expiry
+//! time is considered stale (its owner crashed or lost connectivity) and
+//! is removed by whoever finds it.
+//!
+//! To acquire a lock, the lease is written first, and only then the other
+//! leases are checked. If any of them conflicts, the own lease is removed
+//! again and the whole thing is retried after a randomized backoff. This
+//! relies only on `BackendThread` operations and read-after-write
+//! consistency of the backend, so any backend can use it.
+//!
+//! Expiry times are compared between hosts, so their clocks are expected to
+//! be roughly in sync (well within the lease duration).
+//!
+//! A holder whose lease was removed, or went stale before being refreshed,
+//! can't rely on the lock anymore; it poisons the `LeasePoison` of its
+//! backend, which fails all the further operations of the backend.
+
+// {{{ use and mod
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::time::Duration;
+use std::{io, process, thread};
+
+use rand::distr::Alphanumeric;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sgdata::SGData;
+
+use super::{BackendThread, Lock};
+use crate::config;
+// }}}
+
+/// Default time for which a lease is valid without being refreshed
+pub const DEFAULT_LEASE_DURATION: Duration = Duration::from_secs(5 * 60);
+
+/// Longest wait between attempts to acquire a lock
+const MAX_BACKOFF: Duration = Duration::from_secs(10);
+
+/// Content of a lease object
+#[derive(Serialize, Deserialize)]
+struct Lease {
+    owner: String,
+    exclusive: bool,
+    expires: chrono::DateTime<chrono::Utc>,
+}
+
+impl Lease {
+    fn is_expired(&self) -> bool {
+        self.expires < chrono::Utc::now()
+    }
+
+    fn conflicts_with(&self, exclusive: bool) -> bool {
+        self.exclusive || exclusive
+    }
+}
+
+/// Flag of a backend, set once any of its leases is lost
+#[derive(Clone, Debug, Default)]
+pub struct LeasePoison(Arc<AtomicBool>);
+
+impl LeasePoison {
+    /// Fail if a lease was lost
+    ///
+    /// Backends call it before every operation.
+    pub fn check(&self) -> io::Result<()> {
+        if self.0.load(Ordering::SeqCst) {
+            return Err(io::Error::other(
+                "repository lock lost, its lease was removed or went stale",
+            ));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn poison(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Lock held as long as its lease is being refreshed
+///
+/// Dropping it stops the refreshing and removes the lease.
+pub struct LeaseLock {
+    owner: String,
+    stop_tx: Option<mpsc::Sender<()>>,
+    refresher: Option<thread::JoinHandle<()>>,
+}
+
+impl LeaseLock {
+    /// Acquire a lock using `DEFAULT_LEASE_DURATION`
+    ///
+    /// Blocks until no conflicting lease exists. `thread` is used for all
+    /// the operations on the lease, including refreshing it; `poison` is
+    /// set if the lease gets lost.
+    pub fn acquire(
+        thread: Box<dyn BackendThread>,
+        exclusive: bool,
+        poison: &LeasePoison,
+    ) -> io::Result<Self> {
+        Self::acquire_with_duration(
+            thread,
+            exclusive,
+            DEFAULT_LEASE_DURATION,
+            poison,
+        )
+    }
+
+    /// Like `acquire`, with a custom lease duration
+    ///
+    /// The lease is refreshed every third of `duration`.
+    pub fn acquire_with_duration(
+        mut thread: Box<dyn BackendThread>,
+        exclusive: bool,
+        duration: Duration,
+        poison: &LeasePoison,
+    ) -> io::Result<Self> {
+        let owner = format!("{}-{}", process::id(), random_string());
+        let lock_dir = PathBuf::from(config::LOCK_FILE);
+        let path = lock_dir.join(&owner);
+
+        let mut backoff = Duration::from_millis(100);
+        let expires = loop {
+            let expires =
+                write_lease(&mut *thread, &path, &owner, exclusive, duration)?;
+
+            if !conflicting_lease_exists(
+                &mut *thread,
+                &owner,
+                exclusive,
+                duration,
+            )? {
+                break expires;
+            }
+
+            // Back off completely, so two competing lockers don't wait
+            // for each other forever
+            thread.remove(path.clone())?;
+            let jitter = rand::rng().random_range(0..100);
+            thread::sleep(backoff + Duration::from_millis(jitter));
+            backoff = std::cmp::min(backoff * 2, MAX_BACKOFF);
+        };
+
+        let (stop_tx, stop_rx) = mpsc::channel();
+        let refresher = {
+            let lease = Lease {
+                owner: owner.clone(),
+                exclusive,
+                expires,
+            };
+            let poison = poison.clone();
+            thread::spawn(move || {
+                refresh_lease(thread, path, lease, duration, poison, stop_rx)
+            })
+        };
+
+        Ok(LeaseLock {
+            owner,
+            stop_tx: Some(stop_tx),
+            refresher: Some(refresher),
+        })
+    }
+
+    /// Unique id of the holder of this lock
+    pub fn owner(&self) -> &str {
+        &self.owner
+    }
+}
+
+impl Lock for LeaseLock {}
+
+impl Drop for LeaseLock {
+    fn drop(&mut self) {
+        drop(self.stop_tx.take());
+        if let Some(refresher) = self.refresher.take() {
+            let _ = refresher.join();
+        }
+    }
+}
+
+fn random_string() -> String {
+    rand::rng()
+        .sample_iter(&Alphanumeric)
+        .take(20)
+        .map(char::from)
+        .collect()
+}
+
+/// Write a lease, returning its expiry time
+fn write_lease(
+    thread: &mut dyn BackendThread,
+    path: &Path,
+    owner: &str,
+    exclusive: bool,
+    duration: Duration,
+) -> io::Result<chrono::DateTime<chrono::Utc>> {
+    let lease = Lease {
+        owner: owner.to_owned(),
+        exclusive,
+        expires: chrono::Utc::now()
+            + chrono::Duration::from_std(duration)
+                .expect("lease duration out of range"),
+    };
+    let data = serde_json::to_vec(&lease)?;
+    thread.write(path.to_owned(), SGData::from_single(data), false)?;
+    Ok(lease.expires)
+}
+
+/// Check for leases of other owners conflicting with ours, removing the
+/// stale ones
+///
+/// A lease that can't be parsed is stale once it wasn't written for
+/// `duration`.
+fn conflicting_lease_exists(
+    thread: &mut dyn BackendThread,
+    owner: &str,
+    exclusive: bool,
+    duration: Duration,
+) -> io::Result<bool> {
+    for path in thread.list(PathBuf::from(config::LOCK_FILE))? {
+        if path.file_name().is_some_and(|name| name == owner) {
+            continue;
+        }
+
+        let lease = match thread.read(path.clone()) {
+            Ok(data) => data.into_linear_vec(),
+            // released in the meantime
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        let expired = match serde_json::from_slice::<Lease>(&lease) {
+            Ok(lease) if !lease.is_expired() => {
+                if lease.conflicts_with(exclusive) {
+                    return Ok(true);
+                }
+                continue;
+            }
+            Ok(_) => true,
+            // possibly caught in the middle of being written; as it can't
+            // be told whether it's stale, go by the time of the write
+            Err(_) => match thread.read_metadata(path.clone()) {
+                Ok(md) => {
+                    md.created
+                        + chrono::Duration::from_std(duration)
+                            .expect("lease duration out of range")
+                        < chrono::Utc::now()
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            },
+        };
+
+        if !expired {
+            return Ok(true);
+        }
+        match thread.remove(path) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+            res => res?,
+        }
+    }
+    Ok(false)
+}
+
+fn refresh_lease(
+    mut thread: Box<dyn BackendThread>,
+    path: PathBuf,
+    mut lease: Lease,
+    duration: Duration,
+    poison: LeasePoison,
+    stop_rx: mpsc::Receiver<()>,
+) {
+    while let Err(mpsc::RecvTimeoutError::Timeout) =
+        stop_rx.recv_timeout(duration / 3)
+    {
+        // If the lease is gone, or went stale in the meantime, someone
+        // could have taken over the lock; recreating the lease now could
+        // hide the conflict, so stop refreshing and fail the backend
+        let removed = matches!(
+            thread.read_metadata(path.clone()),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound
+        );
+        if removed || lease.is_expired() {
+            poison.poison();
+            return;
+        }
+        // Failures are retried on the next tick, which is still well
+        // before the lease expires
+        if let Ok(expires) = write_lease(
+            &mut *thread,
+            &path,
+            &lease.owner,
+            lease.exclusive,
+            duration,
+        ) {
+            lease.expires = expires;
+        }
+    }
+
+    // Best effort; a left-over lease will expire eventually
+    let _ = thread.remove(path);
+}
+
+// vim: foldmethod=marker foldmarker={{{,}}}
@@ -160,8 +160,7 @@ impl BackendThread for LocalThread {
         } else if let Ok(modified) = md.modified().map(Into::into) {
             modified
         } else {
-            return Err(io::Error::new(
-                    io::ErrorKind::Other,
+            return Err(io::Error::other(
                     format!("filesystem metadata does not contain `created` or `modified` for {}", path.display())));
         };
         Ok(Metadata {
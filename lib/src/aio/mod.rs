@@ -1,9 +1,10 @@
 //! Asynchronous IO operations & backends
 use std::cell::RefCell;
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashSet};
 use std::path::{Path, PathBuf};
 use std::sync::mpsc;
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use std::{io, thread};
 
 use dangerous_option::DangerousOption as AutoOption;
@@ -20,7 +21,12 @@ pub(crate) use self::local::Local;
 #[cfg(feature = "backend-http")]
 pub(crate) mod http;
 #[cfg(feature = "backend-http")]
-pub(crate) use self::http::HttpReadOnly;
+pub(crate) use self::http::Http;
+
+#[cfg(feature = "backend-s3")]
+pub(crate) mod s3;
+#[cfg(feature = "backend-s3")]
+pub(crate) use self::s3::S3;
 
 #[cfg(feature = "backend-b2")]
 pub(crate) mod b2;
@@ -28,6 +34,7 @@ pub(crate) mod b2;
 pub(crate) use self::b2::B2;
 
 pub(crate) mod backend;
+pub(crate) mod lease;
 pub(crate) mod local_cache;
 
 use self::backend::*;
@@ -68,6 +75,9 @@ impl<T> AsyncIOResult<T> {
 pub struct WriteStats {
     pub new_chunks: usize,
     pub new_bytes: u64,
+    /// Number of new chunks compressed at every level, with `auto`
+    /// compression
+    pub compression_levels: BTreeMap<i32, usize>,
 }
 // }}}
 
@@ -320,6 +330,12 @@ impl Drop for AsyncIOShared {
 struct AsyncIOSharedInner {
     /// Keeps tracks of `write` stats.
     write_stats: WriteStats,
+    /// Number of `write`s being currently processed by the pool
+    writes_in_progress: usize,
+    /// Since when there's been a `write` in progress, if any
+    write_busy_since: Option<Instant>,
+    /// Total time with any `write` in progress, until `write_busy_since`
+    write_busy_time: Duration,
     /// PathBufs being currently processed by the pool.
     /// Used to synchronize operations between each other.
     in_progress: HashSet<PathBuf>,
@@ -342,7 +358,11 @@ impl AsyncIOThreadShared {
             write_stats: WriteStats {
                 new_bytes: 0,
                 new_chunks: 0,
+                compression_levels: BTreeMap::new(),
             },
+            writes_in_progress: 0,
+            write_busy_since: None,
+            write_busy_time: Duration::ZERO,
             in_progress: Default::default(),
         };
 
@@ -355,6 +375,28 @@ impl AsyncIOThreadShared {
         let sh = self.inner.lock().unwrap();
         sh.write_stats.clone()
     }
+
+    /// Bytes written so far, along with the time the backend was busy
+    /// writing anything
+    ///
+    /// As writes run concurrently, their ratio is the throughput of the
+    /// backend, rather than of a single write.
+    pub(crate) fn write_throughput(&self) -> (u64, Duration) {
+        let sh = self.inner.lock().unwrap();
+        let busy_time = match sh.write_busy_since {
+            Some(since) => sh.write_busy_time + since.elapsed(),
+            None => sh.write_busy_time,
+        };
+        (sh.write_stats.new_bytes, busy_time)
+    }
+
+    /// Account for `bytes` written in `time`, without writing anything
+    #[cfg(test)]
+    pub(crate) fn record_write(&self, bytes: u64, time: Duration) {
+        let mut sh = self.inner.lock().unwrap();
+        sh.write_stats.new_bytes += bytes;
+        sh.write_busy_time += time;
+    }
 }
 // }}}
 
@@ -455,6 +497,10 @@ impl AsyncIOThread {
                 }
             } else {
                 sh.in_progress.insert(path.clone());
+                if sh.writes_in_progress == 0 {
+                    sh.write_busy_since = Some(Instant::now());
+                }
+                sh.writes_in_progress += 1;
                 break;
             }
         }
@@ -469,6 +515,12 @@ impl AsyncIOThread {
             sh.in_progress.remove(&path);
             sh.write_stats.new_bytes += len as u64;
             sh.write_stats.new_chunks += 1;
+            sh.writes_in_progress -= 1;
+            if sh.writes_in_progress == 0 {
+                if let Some(since) = sh.write_busy_since.take() {
+                    sh.write_busy_time += since.elapsed();
+                }
+            }
         }
 
         res
@@ -639,13 +691,23 @@ pub(crate) fn backend_from_url(
     } else if url.scheme() == "http" || url.scheme() == "https" {
         #[cfg(feature = "backend-http")]
         {
-            return Ok(Box::new(HttpReadOnly::new(url.clone())));
+            return Ok(Box::new(Http::new(url.clone())));
         }
 
         #[cfg(not(feature = "backend-http"))]
         {
             panic!("HTTP backend feature is not enabled");
         }
+    } else if url.scheme() == "s3" {
+        #[cfg(feature = "backend-s3")]
+        {
+            return Ok(Box::new(S3::new(url)?));
+        }
+
+        #[cfg(not(feature = "backend-s3"))]
+        {
+            panic!("S3 backend feature is not enabled");
+        }
     } else if url.scheme() == "b2" {
         #[cfg(feature = "backend-b2")]
         {
@@ -0,0 +1,658 @@
+//! Backend storing the repository in an S3-compatible object storage
+//!
+//! Url format is `s3://bucket/prefix`. The rest of the configuration is
+//! taken from the environment, like most S3 tools do:
+//!
+//! * `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY` and optional
+//!   `AWS_SESSION_TOKEN` - credentials,
+//! * `AWS_REGION` or `AWS_DEFAULT_REGION` - region (`us-east-1` by default),
+//! * `AWS_ENDPOINT_URL` - endpoint of an S3-compatible service (eg. MinIO),
+//!   accessed with path-style addressing; AWS itself is used if not set.
+//!
+//! There are no directories in S3, so they are emulated with `/`-separated
+//! key prefixes. Requests are signed with AWS Signature Version 4. The
+//! repository is locked with leases kept under `.lock/`.
+
+// {{{ use and mod
+use std::io;
+use std::ops::ControlFlow;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{mpsc, Arc};
+use std::time::Duration;
+use std::{env, mem, thread};
+
+use hmac::{Hmac, Mac};
+use reqwest::blocking::{Client, Response};
+use reqwest::{Method, StatusCode};
+use serde::Deserialize;
+use sgdata::SGData;
+use sha2::{Digest, Sha256};
+use url::Url;
+
+use super::lease::{LeaseLock, LeasePoison};
+use super::Metadata;
+use super::{Backend, BackendThread, Lock};
+// }}}
+
+/// How many times to retry a request failing due to network/service issues
+const RETRIES: u32 = 5;
+
+pub(crate) struct Credentials {
+    pub(crate) access_key: String,
+    pub(crate) secret_key: String,
+    pub(crate) session_token: Option<String>,
+}
+
+struct Config {
+    /// Custom endpoint; path-style addressing is used with it
+    endpoint: Option<Url>,
+    region: String,
+    bucket: String,
+    /// Prefix of all the keys, without trailing `/`
+    prefix: String,
+    credentials: Credentials,
+}
+
+fn env_var(name: &str) -> io::Result<String> {
+    env::var(name).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} environment variable not found", name),
+        )
+    })
+}
+
+/// Percent-encode `s` as required by AWS Signature Version 4
+///
+/// `/` is left as is, unless `encode_slash` is set.
+pub(crate) fn uri_encode(s: &str, encode_slash: bool) -> String {
+    let mut encoded = String::with_capacity(s.len());
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z'
+            | b'a'..=b'z'
+            | b'0'..=b'9'
+            | b'-'
+            | b'_'
+            | b'.'
+            | b'~' => encoded.push(b as char),
+            b'/' if !encode_slash => encoded.push('/'),
+            b => encoded.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    encoded
+}
+
+fn hmac_sha256(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key)
+        .expect("HMAC accepts keys of any size");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Calculate `Authorization` header value of a request
+///
+/// `headers` must contain all the headers to be signed (including `host`,
+/// `x-amz-date` and `x-amz-content-sha256`), with lowercase names.
+pub(crate) fn sign(
+    credentials: &Credentials,
+    region: &str,
+    method: &str,
+    url: &Url,
+    headers: &[(String, String)],
+    payload_hash: &str,
+    now: chrono::DateTime<chrono::Utc>,
+) -> String {
+    let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+    let date = now.format("%Y%m%d").to_string();
+
+    let mut query: Vec<_> = url
+        .query_pairs()
+        .map(|(k, v)| (uri_encode(&k, true), uri_encode(&v, true)))
+        .collect();
+    query.sort();
+    let query: Vec<_> =
+        query.iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+
+    let mut headers: Vec<_> = headers
+        .iter()
+        .map(|(k, v)| (k.to_lowercase(), v.trim().to_owned()))
+        .collect();
+    headers.sort();
+    let signed_headers: Vec<_> =
+        headers.iter().map(|(k, _)| k.as_str()).collect();
+    let signed_headers = signed_headers.join(";");
+
+    let canonical_request = format!(
+        "{}\n{}\n{}\n{}\n{}\n{}",
+        method,
+        url.path(),
+        query.join("&"),
+        headers
+            .iter()
+            .map(|(k, v)| format!("{}:{}\n", k, v))
+            .collect::<String>(),
+        signed_headers,
+        payload_hash,
+    );
+
+    let scope = format!("{}/{}/s3/aws4_request", date, region);
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+        amz_date,
+        scope,
+        hex::encode(Sha256::digest(canonical_request.as_bytes())),
+    );
+
+    let key = hmac_sha256(
+        format!("AWS4{}", credentials.secret_key).as_bytes(),
+        &date,
+    );
+    let key = hmac_sha256(&key, region);
+    let key = hmac_sha256(&key, "s3");
+    let key = hmac_sha256(&key, "aws4_request");
+    let signature = hex::encode(hmac_sha256(&key, &string_to_sign));
+
+    format!(
+        "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+        credentials.access_key, scope, signed_headers, signature
+    )
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ListBucketResult {
+    #[serde(default)]
+    contents: Vec<ListObject>,
+    #[serde(default)]
+    common_prefixes: Vec<ListCommonPrefix>,
+    #[serde(default)]
+    is_truncated: bool,
+    next_continuation_token: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ListObject {
+    key: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ListCommonPrefix {
+    prefix: String,
+}
+
+pub struct S3 {
+    config: Arc<Config>,
+    client: Client,
+    /// Shared with the threads, so they fail once a lease is lost
+    poison: LeasePoison,
+}
+
+impl S3 {
+    /// Create a backend for `s3://bucket/prefix` url
+    ///
+    /// See the module documentation for the environment variables used.
+    pub fn new(url: &Url) -> io::Result<Self> {
+        let bucket = url
+            .host_str()
+            .filter(|bucket| !bucket.is_empty())
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "bucket in the url missing",
+                )
+            })?
+            .to_owned();
+
+        let endpoint = match env::var("AWS_ENDPOINT_URL") {
+            Ok(endpoint) => Some(Url::parse(&endpoint).map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("invalid AWS_ENDPOINT_URL: {}", e),
+                )
+            })?),
+            Err(_) => None,
+        };
+
+        let config = Config {
+            endpoint,
+            region: env_var("AWS_REGION")
+                .or_else(|_| env_var("AWS_DEFAULT_REGION"))
+                .unwrap_or_else(|_| "us-east-1".into()),
+            bucket,
+            prefix: url.path().trim_matches('/').to_owned(),
+            credentials: Credentials {
+                access_key: env_var("AWS_ACCESS_KEY_ID")?,
+                secret_key: env_var("AWS_SECRET_ACCESS_KEY")?,
+                session_token: env::var("AWS_SESSION_TOKEN").ok(),
+            },
+        };
+
+        Ok(S3 {
+            config: Arc::new(config),
+            client: Client::new(),
+            poison: LeasePoison::default(),
+        })
+    }
+
+    fn thread(&self) -> S3Thread {
+        S3Thread {
+            config: Arc::clone(&self.config),
+            client: self.client.clone(),
+            poison: self.poison.clone(),
+        }
+    }
+}
+
+impl Backend for S3 {
+    fn lock_exclusive(&self) -> io::Result<Box<dyn Lock>> {
+        Ok(Box::new(LeaseLock::acquire(
+            self.new_thread()?,
+            true,
+            &self.poison,
+        )?))
+    }
+
+    fn lock_shared(&self) -> io::Result<Box<dyn Lock>> {
+        Ok(Box::new(LeaseLock::acquire(
+            self.new_thread()?,
+            false,
+            &self.poison,
+        )?))
+    }
+
+    fn new_thread(&self) -> io::Result<Box<dyn BackendThread>> {
+        Ok(Box::new(self.thread()))
+    }
+}
+
+pub struct S3Thread {
+    config: Arc<Config>,
+    client: Client,
+    poison: LeasePoison,
+}
+
+impl S3Thread {
+    /// Key of the object at `path`
+    fn key(&self, path: &Path) -> String {
+        let mut key = self.config.prefix.clone();
+        for component in path.components() {
+            if let Component::Normal(c) = component {
+                if !key.is_empty() {
+                    key.push('/');
+                }
+                key.push_str(&c.to_string_lossy());
+            }
+        }
+        key
+    }
+
+    /// `path` of the object with `key`
+    fn path(&self, key: &str) -> PathBuf {
+        let key = key.strip_prefix(&self.config.prefix).unwrap_or(key);
+        PathBuf::from(key.trim_start_matches('/'))
+    }
+
+    fn url(&self, key: &str, query: &[(&str, &str)]) -> io::Result<Url> {
+        let config = &self.config;
+        let mut url = match config.endpoint {
+            Some(ref endpoint) => format!(
+                "{}/{}/{}",
+                endpoint.as_str().trim_end_matches('/'),
+                uri_encode(&config.bucket, true),
+                uri_encode(key, false)
+            ),
+            None => format!(
+                "https://{}.s3.{}.amazonaws.com/{}",
+                config.bucket,
+                config.region,
+                uri_encode(key, false)
+            ),
+        };
+
+        for (i, (k, v)) in query.iter().enumerate() {
+            url.push(if i == 0 { '?' } else { '&' });
+            url.push_str(&uri_encode(k, true));
+            url.push('=');
+            url.push_str(&uri_encode(v, true));
+        }
+
+        Url::parse(&url).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Failed to build url: {}", e),
+            )
+        })
+    }
+
+    /// Send a signed request, retrying on network and server errors
+    ///
+    /// Unsuccessful responses are turned into errors.
+    fn request(
+        &self,
+        method: Method,
+        key: &str,
+        query: &[(&str, &str)],
+        extra_headers: &[(&str, String)],
+        body: Vec<u8>,
+    ) -> io::Result<Response> {
+        self.poison.check()?;
+        let url = self.url(key, query)?;
+        let payload_hash = hex::encode(Sha256::digest(&body));
+        let host = match url.port() {
+            Some(port) => format!("{}:{}", url.host_str().unwrap(), port),
+            None => url.host_str().unwrap().to_owned(),
+        };
+
+        let mut backoff = Duration::from_millis(100);
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+
+            let now = chrono::Utc::now();
+            let mut headers = vec![
+                ("host".to_owned(), host.clone()),
+                ("x-amz-content-sha256".to_owned(), payload_hash.clone()),
+                (
+                    "x-amz-date".to_owned(),
+                    now.format("%Y%m%dT%H%M%SZ").to_string(),
+                ),
+            ];
+            if let Some(ref token) = self.config.credentials.session_token {
+                headers
+                    .push(("x-amz-security-token".to_owned(), token.clone()));
+            }
+            for (k, v) in extra_headers {
+                headers.push((k.to_string(), v.clone()));
+            }
+            let authorization = sign(
+                &self.config.credentials,
+                &self.config.region,
+                method.as_str(),
+                &url,
+                &headers,
+                &payload_hash,
+                now,
+            );
+
+            let mut request = self
+                .client
+                .request(method.clone(), url.clone())
+                .header("authorization", authorization)
+                .body(body.clone());
+            for (k, v) in headers.iter().filter(|(k, _)| k != "host") {
+                request = request.header(k.as_str(), v.as_str());
+            }
+
+            let res = request.send();
+            let retriable = match res {
+                Ok(ref response) => response.status().is_server_error(),
+                Err(_) => true,
+            };
+            if retriable && attempt < RETRIES {
+                thread::sleep(backoff);
+                backoff *= 2;
+                continue;
+            }
+
+            let response = res.map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::ConnectionAborted,
+                    format!("Request failed: {}", e),
+                )
+            })?;
+
+            let status = response.status();
+            if status.is_success() {
+                return Ok(response);
+            }
+
+            let kind = match status {
+                StatusCode::NOT_FOUND => io::ErrorKind::NotFound,
+                StatusCode::FORBIDDEN => io::ErrorKind::PermissionDenied,
+                _ => io::ErrorKind::Other,
+            };
+            let text = response.text().unwrap_or_default();
+            return Err(io::Error::new(
+                kind,
+                format!("{} {} failed: {} {}", method, key, status, text),
+            ));
+        }
+    }
+
+    /// List keys starting with `prefix`
+    ///
+    /// With `delimiter`, keys are grouped by the next `/` after the prefix,
+    /// and a group is returned as a single key ending with `/`. `f` is called
+    /// with every page of the results, until it breaks.
+    fn list_keys<F>(
+        &self,
+        prefix: &str,
+        delimiter: bool,
+        mut f: F,
+    ) -> io::Result<()>
+    where
+        F: FnMut(Vec<String>) -> ControlFlow<()>,
+    {
+        let mut continuation_token: Option<String> = None;
+        loop {
+            let mut query = vec![("list-type", "2"), ("prefix", prefix)];
+            if delimiter {
+                query.push(("delimiter", "/"));
+            }
+            if let Some(ref token) = continuation_token {
+                query.push(("continuation-token", token.as_str()));
+            }
+
+            let text = self
+                .request(Method::GET, "", &query, &[], vec![])?
+                .text()
+                .map_err(|e| {
+                    io::Error::new(
+                        io::ErrorKind::ConnectionAborted,
+                        format!("Failed to read response: {}", e),
+                    )
+                })?;
+            let result: ListBucketResult = quick_xml::de::from_str(&text)
+                .map_err(|e| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("Failed to parse list response: {}", e),
+                    )
+                })?;
+
+            let page = result
+                .contents
+                .into_iter()
+                .map(|o| o.key)
+                .chain(result.common_prefixes.into_iter().map(|p| p.prefix))
+                .collect();
+            if f(page).is_break() {
+                return Ok(());
+            }
+
+            match result.next_continuation_token {
+                Some(token) if result.is_truncated => {
+                    continuation_token = Some(token)
+                }
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    /// Prefix of all the keys "inside" `path`
+    fn dir_prefix(&self, path: &Path) -> String {
+        let mut prefix = self.key(path);
+        if !prefix.is_empty() {
+            prefix.push('/');
+        }
+        prefix
+    }
+}
+
+impl BackendThread for S3Thread {
+    fn remove_dir_all(&mut self, path: PathBuf) -> io::Result<()> {
+        let mut keys = vec![];
+        self.list_keys(&self.dir_prefix(&path), false, |mut page| {
+            keys.append(&mut page);
+            ControlFlow::Continue(())
+        })?;
+
+        if keys.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} not found", path.display()),
+            ));
+        }
+
+        for key in keys {
+            self.request(Method::DELETE, &key, &[], &[], vec![])?;
+        }
+        Ok(())
+    }
+
+    fn rename(
+        &mut self,
+        src_path: PathBuf,
+        dst_path: PathBuf,
+    ) -> io::Result<()> {
+        let src_key = self.key(&src_path);
+        let copy_source = format!(
+            "/{}/{}",
+            uri_encode(&self.config.bucket, true),
+            uri_encode(&src_key, false)
+        );
+        self.request(
+            Method::PUT,
+            &self.key(&dst_path),
+            &[],
+            &[("x-amz-copy-source", copy_source)],
+            vec![],
+        )?;
+        self.request(Method::DELETE, &src_key, &[], &[], vec![])?;
+        Ok(())
+    }
+
+    fn write(
+        &mut self,
+        path: PathBuf,
+        sg: SGData,
+        idempotent: bool,
+    ) -> io::Result<()> {
+        if idempotent && self.read_metadata(path.clone()).is_ok() {
+            return Ok(());
+        }
+
+        self.request(
+            Method::PUT,
+            &self.key(&path),
+            &[],
+            &[],
+            sg.into_linear_vec(),
+        )?;
+        Ok(())
+    }
+
+    fn read(&mut self, path: PathBuf) -> io::Result<SGData> {
+        let data = self
+            .request(Method::GET, &self.key(&path), &[], &[], vec![])?
+            .bytes()
+            .map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::ConnectionAborted,
+                    format!("Failed to read response as bytes: {}", e),
+                )
+            })?;
+
+        Ok(SGData::from_single(data.into()))
+    }
+
+    fn remove(&mut self, path: PathBuf) -> io::Result<()> {
+        // Deleting a non-existing object succeeds, but callers depend
+        // on `NotFound`
+        self.read_metadata(path.clone())?;
+        self.request(Method::DELETE, &self.key(&path), &[], &[], vec![])?;
+        Ok(())
+    }
+
+    fn read_metadata(&mut self, path: PathBuf) -> io::Result<Metadata> {
+        let response =
+            self.request(Method::HEAD, &self.key(&path), &[], &[], vec![])?;
+        let header = |name: &str| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("No {} header", name),
+                    )
+                })
+        };
+
+        let len = header("content-length")?.parse().map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Failed to parse content length: {}", e),
+            )
+        })?;
+        let created =
+            chrono::DateTime::parse_from_rfc2822(header("last-modified")?)
+                .map_err(|e| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("Failed to parse last modified time: {}", e),
+                    )
+                })?;
+
+        Ok(Metadata {
+            len,
+            is_file: true,
+            created: created.into(),
+        })
+    }
+
+    fn list(&mut self, path: PathBuf) -> io::Result<Vec<PathBuf>> {
+        let prefix = self.dir_prefix(&path);
+        let mut result = Vec::with_capacity(128);
+        self.list_keys(&prefix, true, |page| {
+            for key in page {
+                let name = key[prefix.len()..].trim_end_matches('/');
+                if !name.is_empty() {
+                    result.push(path.join(name));
+                }
+            }
+            ControlFlow::Continue(())
+        })?;
+        Ok(result)
+    }
+
+    fn list_recursively(
+        &mut self,
+        path: PathBuf,
+        tx: mpsc::Sender<io::Result<Vec<PathBuf>>>,
+    ) {
+        let mut result = Vec::with_capacity(128);
+        let res = self.list_keys(&self.dir_prefix(&path), false, |page| {
+            result.extend(page.iter().map(|key| self.path(key)));
+            if result.len() > 100
+                && tx.send(Ok(mem::take(&mut result))).is_err()
+            {
+                // Nobody is waiting for the rest of the list
+                return ControlFlow::Break(());
+            }
+            ControlFlow::Continue(())
+        });
+        if let Err(e) = res {
+            let _ = tx.send(Err(e));
+        }
+        if !result.is_empty() {
+            let _ = tx.send(Ok(result));
+        }
+    }
+}
+
+// vim: foldmethod=marker foldmarker={{{,}}}
@@ -0,0 +1,610 @@
+//! Checking the integrity of the whole repository
+//!
+//! Unlike `Repo::verify`, which follows the chunks reachable from a single
+//! name, `Repo::check` starts from what is actually stored: every file in
+//! the chunk directory of every generation is read and re-hashed. Only then
+//! all the names are traversed, to find the chunks they reference but which
+//! are not stored, and the stored chunks nobody references.
+//!
+//! Nothing is modified; in particular, unlike on `read`, chunks are not
+//! moved to the current generation.
+//!
+//! The damage found can be then fixed by `Repo::repair_from`, copying the
+//! chunks from another repository with the same configuration.
+
+// {{{ use and mod
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use hex::FromHex;
+use serde::Serialize;
+use slog::{info, trace, FnValue};
+
+use crate::config;
+use crate::name::Name;
+use crate::reading::{ChunkAccessor, ReadContext, ReadRequest};
+use crate::{
+    ArcDecrypter, DataAddress, DataType, DigestRef, Generation, Repo, SGData,
+    DIGEST_SIZE,
+};
+// }}}
+
+/// Problem found by `Repo::check`
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum CheckIssue {
+    /// Generation directory without a config, ignored by everything else
+    DeadGeneration { generation: String },
+    /// File that is not a chunk (or not at the path of the chunk its name
+    /// says it is), or an unknown entry in the repository root
+    UnparseableFile { path: PathBuf },
+    /// Chunk that can't be read or decoded, or whose content doesn't match
+    /// its digest
+    CorruptedChunk {
+        path: PathBuf,
+        digest: String,
+        error: String,
+    },
+    /// Chunk not referenced by any name; not an error, `gc` removes these
+    ///
+    /// Not reported if any name is broken, as it could reference the chunk.
+    OrphanedChunk { path: PathBuf },
+    /// Name that can't be parsed, or whose index can't be traversed
+    BrokenName {
+        name: String,
+        path: PathBuf,
+        error: String,
+    },
+    /// Chunk referenced by a name, but not stored in any generation
+    MissingChunk { name: String, digest: String },
+}
+
+impl CheckIssue {
+    /// Whether the issue means something is damaged, or it's just garbage
+    pub fn is_error(&self) -> bool {
+        !matches!(self, CheckIssue::OrphanedChunk { .. })
+    }
+}
+
+impl fmt::Display for CheckIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CheckIssue::DeadGeneration { generation } => {
+                write!(f, "dead generation {} (config missing)", generation)
+            }
+            CheckIssue::UnparseableFile { path } => {
+                write!(f, "unparseable file {}", path.display())
+            }
+            CheckIssue::CorruptedChunk { path, error, .. } => {
+                write!(f, "corrupted chunk {} - {}", path.display(), error)
+            }
+            CheckIssue::OrphanedChunk { path } => {
+                write!(f, "orphaned chunk {}", path.display())
+            }
+            CheckIssue::BrokenName { name, error, .. } => {
+                write!(f, "broken name {} - {}", name, error)
+            }
+            CheckIssue::MissingChunk { name, digest } => {
+                write!(f, "name {} is missing chunk {}", name, digest)
+            }
+        }
+    }
+}
+
+/// Results of `Repo::check`
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct CheckResults {
+    /// Number of chunk files checked
+    pub chunks: usize,
+    /// Number of names checked
+    pub names: usize,
+    pub issues: Vec<CheckIssue>,
+}
+
+impl CheckResults {
+    /// Whether any of the issues found is an error
+    pub fn has_errors(&self) -> bool {
+        self.issues.iter().any(CheckIssue::is_error)
+    }
+
+    /// Digests of the chunks found corrupted or missing, sorted
+    pub fn damaged_chunks(&self) -> Vec<Vec<u8>> {
+        let mut digests: Vec<_> = self
+            .issues
+            .iter()
+            .filter_map(|issue| match issue {
+                CheckIssue::CorruptedChunk { digest, .. }
+                | CheckIssue::MissingChunk { digest, .. } => {
+                    Some(Vec::from_hex(digest).expect("digests are hex"))
+                }
+                _ => None,
+            })
+            .collect();
+        digests.sort();
+        digests.dedup();
+        digests
+    }
+}
+
+/// Results of `Repo::repair_from`
+#[derive(Debug, Default)]
+pub struct RepairResults {
+    /// Digests of the chunks replaced
+    pub repaired: Vec<Vec<u8>>,
+    /// Digests of the chunks that couldn't be replaced, with the reason
+    pub errors: Vec<(Vec<u8>, io::Error)>,
+}
+
+/// Chunk file found in a chunk directory
+struct StoredChunk {
+    path: PathBuf,
+    digest: Vec<u8>,
+    /// Index of the generation it's stored in
+    gen_i: usize,
+    res: io::Result<Verified>,
+}
+
+/// Parse the digest from the path of a chunk file
+///
+/// Returns `None` if the file name isn't a digest, or the file is not where
+/// a chunk with that digest belongs.
+fn chunk_digest(repo: &Repo, path: &Path, gen_str: &str) -> Option<Vec<u8>> {
+    let digest = Vec::from_hex(path.file_name()?.to_str()?).ok()?;
+    if digest.len() != DIGEST_SIZE {
+        return None;
+    }
+    // Some backends list full paths, others paths relative to the repository
+    path.ends_with(repo.chunk_rel_path_by_digest(DigestRef(&digest), gen_str))
+        .then_some(digest)
+}
+
+/// How a chunk matched its digest in `verify_chunk_data`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Verified {
+    /// Stored as it is, which is valid only for an index chunk
+    Raw,
+    /// Decrypted (and decompressed) first
+    Decoded,
+}
+
+/// Check the content of a chunk against its digest
+///
+/// Index chunks are stored as they are (or just encrypted, with encrypted
+/// index); everything else is decrypted and decompressed first.
+fn verify_chunk_data(
+    repo: &Repo,
+    decrypter: &ArcDecrypter,
+    data: SGData,
+    digest: &[u8],
+) -> io::Result<Verified> {
+    let hasher = repo
+        .chunk_hasher(Some(decrypter))
+        .expect("decrypter holds the hashing key");
+    if !repo.is_index_encrypted() && hasher.calculate_digest(&data) == digest {
+        return Ok(Verified::Raw);
+    }
+
+    let data = decrypter.decrypt(data, digest)?;
+    // Encrypted index chunk
+    if repo.is_index_encrypted() && hasher.calculate_digest(&data) == digest {
+        return Ok(Verified::Decoded);
+    }
+    let data = repo.compression.decompress(data)?;
+    let data_digest = hasher.calculate_digest(&data);
+    if data_digest != digest {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("data read: {}", hex::encode(data_digest)),
+        ));
+    }
+    Ok(Verified::Decoded)
+}
+
+fn verify_chunk(
+    repo: &Repo,
+    decrypter: &ArcDecrypter,
+    path: &Path,
+    digest: &[u8],
+) -> io::Result<Verified> {
+    let data = repo.aio.read(path.to_owned()).wait()?;
+    verify_chunk_data(repo, decrypter, data, digest)
+}
+
+/// Read and verify all the files in chunk directories of `generations`
+fn scan_chunks(
+    repo: &Repo,
+    decrypter: &ArcDecrypter,
+    generations: &[Generation],
+    workers: usize,
+    issues: &mut Vec<CheckIssue>,
+) -> io::Result<Vec<StoredChunk>> {
+    let gen_strings: Vec<_> =
+        generations.iter().map(|g| g.to_string()).collect();
+    let (path_tx, path_rx) =
+        crossbeam_channel::bounded::<(usize, PathBuf, Vec<u8>)>(workers);
+    let (chunk_tx, chunk_rx) = crossbeam_channel::unbounded();
+
+    crossbeam::scope(|scope| {
+        for _ in 0..workers {
+            let path_rx = path_rx.clone();
+            let chunk_tx = chunk_tx.clone();
+            scope.spawn(move |_| {
+                for (gen_i, path, digest) in path_rx {
+                    let res = verify_chunk(repo, decrypter, &path, &digest);
+                    chunk_tx
+                        .send(StoredChunk {
+                            path,
+                            digest,
+                            gen_i,
+                            res,
+                        })
+                        .expect("receiver outlives the workers");
+                }
+            });
+        }
+        drop(chunk_tx);
+
+        let mut res = Ok(());
+        for (gen_i, gen_str) in gen_strings.iter().enumerate() {
+            info!(repo.log, "checking chunks"; "gen" => gen_str);
+            let chunk_dir = Path::new(gen_str).join(config::DATA_SUBDIR);
+            // The listing has to be drained even after an error, or the
+            // backend thread fails sending the rest of it
+            for path in repo.aio.list_recursively(chunk_dir) {
+                let path = match (path, &res) {
+                    (Ok(path), Ok(())) => path,
+                    (Err(e), Ok(())) => {
+                        res = Err(e);
+                        continue;
+                    }
+                    (_, Err(_)) => continue,
+                };
+                trace!(repo.log, "checking"; "path" => %path.display());
+                match chunk_digest(repo, &path, gen_str) {
+                    Some(digest) => path_tx
+                        .send((gen_i, path, digest))
+                        .expect("workers outlive the sender"),
+                    None => issues.push(CheckIssue::UnparseableFile { path }),
+                }
+            }
+        }
+        drop(path_tx);
+
+        let chunks: Vec<_> = chunk_rx.iter().collect();
+        res.map(|_| chunks)
+    })
+    .expect("chunk checking thread panicked")
+}
+
+/// Digests of the chunks referenced by the names
+#[derive(Default)]
+struct References {
+    reachable: HashSet<Vec<u8>>,
+    /// Those referenced as index chunks
+    indexes: HashSet<Vec<u8>>,
+}
+
+/// `ChunkAccessor` traversing a name using only the chunks found by
+/// `scan_chunks`, recording the chunks it references
+struct CheckingChunkAccessor<'a> {
+    repo: &'a Repo,
+    /// Decrypter of the index chunks, if they're encrypted
+    index_decrypter: Option<&'a ArcDecrypter>,
+    /// Path of an intact copy of every chunk
+    intact: &'a HashMap<Vec<u8>, PathBuf>,
+    /// Digests of all the chunk files, intact or not
+    stored: &'a HashSet<Vec<u8>>,
+    references: RefCell<&'a mut References>,
+    missing: RefCell<Vec<Vec<u8>>>,
+}
+
+impl ChunkAccessor for CheckingChunkAccessor<'_> {
+    fn read_chunk_into(
+        &self,
+        digest: DigestRef<'_>,
+        _data_type: DataType,
+        writer: &mut dyn Write,
+    ) -> io::Result<()> {
+        self.touch(digest)?;
+        self.references
+            .borrow_mut()
+            .indexes
+            .insert(digest.0.to_owned());
+        let path = match self.intact.get(digest.0) {
+            Some(path) => path,
+            // Already reported, and nothing more can be found below it
+            None if !self.stored.contains(digest.0) => return Ok(()),
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("index chunk {} corrupted", hex::encode(digest.0)),
+                ))
+            }
+        };
+        let data = self.repo.aio.read(path.clone()).wait()?;
+        let data = match self.index_decrypter {
+            Some(decrypter) => decrypter.decrypt(data, digest.0)?,
+            None => data,
+        };
+        for part in data.as_parts() {
+            writer.write_all(part)?;
+        }
+        Ok(())
+    }
+
+    fn touch(&self, digest: DigestRef<'_>) -> io::Result<()> {
+        self.references
+            .borrow_mut()
+            .reachable
+            .insert(digest.0.to_owned());
+        if !self.stored.contains(digest.0) {
+            self.missing.borrow_mut().push(digest.0.to_owned());
+        }
+        Ok(())
+    }
+}
+
+/// Check every name in `generations`, recording the chunks they reference
+fn check_names(
+    repo: &Repo,
+    decrypter: &ArcDecrypter,
+    generations: &[Generation],
+    intact: &HashMap<Vec<u8>, PathBuf>,
+    stored: &HashSet<Vec<u8>>,
+    references: &mut References,
+    issues: &mut Vec<CheckIssue>,
+) -> io::Result<usize> {
+    let mut checked = HashSet::new();
+    let index_decrypter = repo.index_decrypter(Some(decrypter))?;
+
+    for gen in generations.iter().rev() {
+        for name_str in Name::list(*gen, &repo.aio)? {
+            let path = Name::path(&name_str, *gen);
+            let name = match Name::load_from(
+                &name_str,
+                *gen,
+                &repo.aio,
+                index_decrypter.as_ref(),
+            ) {
+                Ok(name) => name,
+                Err(e) => {
+                    issues.push(CheckIssue::BrokenName {
+                        name: name_str,
+                        path,
+                        error: e.to_string(),
+                    });
+                    continue;
+                }
+            };
+            // Names not moved to the current generation yet by `gc` are
+            // shadowed by the newer ones
+            if !checked.insert(name_str.clone()) {
+                continue;
+            }
+
+            info!(repo.log, "checking name"; "name" => &name_str);
+            let data_address: DataAddress = name.into();
+            let accessor = CheckingChunkAccessor {
+                repo,
+                index_decrypter: index_decrypter.as_ref(),
+                intact,
+                stored,
+                references: RefCell::new(references),
+                missing: RefCell::new(vec![]),
+            };
+            let res =
+                ReadContext::new(&accessor).read_recursively(ReadRequest::new(
+                    DataType::Data,
+                    data_address.as_ref(),
+                    None,
+                    repo.log.clone(),
+                ));
+
+            let mut missing = accessor.missing.into_inner();
+            missing.sort();
+            missing.dedup();
+            issues.extend(missing.into_iter().map(|digest| {
+                CheckIssue::MissingChunk {
+                    name: name_str.clone(),
+                    digest: hex::encode(digest),
+                }
+            }));
+            if let Err(e) = res {
+                issues.push(CheckIssue::BrokenName {
+                    name: name_str,
+                    path,
+                    error: e.to_string(),
+                });
+            }
+        }
+    }
+
+    Ok(checked.len())
+}
+
+/// Check the whole repository, using `workers` threads to verify chunks
+pub(crate) fn check(
+    repo: &Repo,
+    decrypter: &ArcDecrypter,
+    workers: usize,
+) -> io::Result<CheckResults> {
+    let mut results = CheckResults::default();
+
+    let (generations, dead, unknown) = repo.scan_generations()?;
+    results.issues.extend(dead.into_iter().map(|gen| {
+        CheckIssue::DeadGeneration {
+            generation: gen.to_string(),
+        }
+    }));
+    results.issues.extend(
+        unknown
+            .into_iter()
+            .map(|(item, _)| CheckIssue::UnparseableFile { path: item.into() }),
+    );
+
+    let mut chunks = scan_chunks(
+        repo,
+        decrypter,
+        &generations,
+        workers,
+        &mut results.issues,
+    )?;
+    // The workers report in random order
+    chunks.sort_by(|a, b| (a.gen_i, &a.path).cmp(&(b.gen_i, &b.path)));
+    results.chunks = chunks.len();
+
+    let mut intact = HashMap::new();
+    let mut stored = HashSet::new();
+    for chunk in &chunks {
+        stored.insert(chunk.digest.clone());
+        // Copies in newer generations come later, and take precedence
+        if chunk.res.is_ok() {
+            intact.insert(chunk.digest.clone(), chunk.path.clone());
+        }
+    }
+
+    let mut references = References::default();
+    results.names = check_names(
+        repo,
+        decrypter,
+        &generations,
+        &intact,
+        &stored,
+        &mut references,
+        &mut results.issues,
+    )?;
+
+    // What a broken name references is unknown, so any chunk could be its
+    let reachability_known = !results
+        .issues
+        .iter()
+        .any(|i| matches!(i, CheckIssue::BrokenName { .. }));
+    for chunk in chunks {
+        let res = chunk.res.and_then(|verified| {
+            // Data chunks are always encrypted, so a plaintext one was
+            // planted, or written by a broken client
+            if verified == Verified::Raw
+                && references.reachable.contains(&chunk.digest)
+                && !references.indexes.contains(&chunk.digest)
+            {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "data chunk stored unencrypted",
+                ));
+            }
+            Ok(())
+        });
+        if let Err(e) = res {
+            results.issues.push(CheckIssue::CorruptedChunk {
+                path: chunk.path,
+                digest: hex::encode(chunk.digest),
+                error: e.to_string(),
+            });
+        } else if reachability_known
+            && !references.reachable.contains(&chunk.digest)
+        {
+            results
+                .issues
+                .push(CheckIssue::OrphanedChunk { path: chunk.path });
+        }
+    }
+
+    Ok(results)
+}
+
+/// Copy an intact `digest` chunk from `source` to the current generation of
+/// `repo`, removing any other copies of it
+fn repair_chunk(
+    repo: &Repo,
+    source: &Repo,
+    decrypter: &ArcDecrypter,
+    generations: &[Generation],
+    source_generations: &[Generation],
+    digest: &[u8],
+) -> io::Result<()> {
+    let mut data = None;
+    for gen in source_generations.iter().rev() {
+        let path = source
+            .chunk_rel_path_by_digest(DigestRef(digest), &gen.to_string());
+        match source.aio.read(path).wait() {
+            Ok(d) => {
+                data = Some(d);
+                break;
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+    }
+    let data = data.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            "not found in the source repository",
+        )
+    })?;
+    // The chunk is stored as it is, so it must be readable with our keys
+    // and settings
+    verify_chunk_data(repo, decrypter, data.clone(), digest).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("damaged in the source repository too: {}", e),
+        )
+    })?;
+
+    let (cur_gen, old_gens) = generations.split_last().ok_or_else(|| {
+        io::Error::new(io::ErrorKind::NotFound, "repository is empty")
+    })?;
+    repo.aio
+        .write(
+            repo.chunk_rel_path_by_digest(
+                DigestRef(digest),
+                &cur_gen.to_string(),
+            ),
+            data,
+        )
+        .wait()?;
+    // Older copies are shadowed now anyway, and `check` would keep reporting
+    // the damaged ones until `gc`
+    for gen in old_gens {
+        let path =
+            repo.chunk_rel_path_by_digest(DigestRef(digest), &gen.to_string());
+        match repo.aio.remove(path).wait() {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+            res => res?,
+        }
+    }
+    Ok(())
+}
+
+/// Replace `digests` in `repo` with intact copies from `source`
+pub(crate) fn repair(
+    repo: &Repo,
+    source: &Repo,
+    decrypter: &ArcDecrypter,
+    digests: &[Vec<u8>],
+) -> io::Result<RepairResults> {
+    let generations = repo.read_generations()?;
+    let source_generations = source.read_generations()?;
+
+    let mut results = RepairResults::default();
+    for digest in digests {
+        info!(repo.log, "repairing chunk";
+              "digest" => FnValue(|_| hex::encode(digest)));
+        match repair_chunk(
+            repo,
+            source,
+            decrypter,
+            &generations,
+            &source_generations,
+            digest,
+        ) {
+            Ok(()) => results.repaired.push(digest.clone()),
+            Err(e) => results.errors.push((digest.clone(), e)),
+        }
+    }
+    Ok(results)
+}
+
+// vim: foldmethod=marker foldmarker={{{,}}}
@@ -1,8 +1,10 @@
+use std::collections::BTreeMap;
 use std::io;
-use std::sync::mpsc;
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use sgdata::SGData;
-use slog::{trace, Level, Logger};
+use slog::{debug, trace, Level, Logger};
 use slog_perf::TimeReporter;
 
 use super::aio;
@@ -14,8 +16,129 @@ use crate::{Digest, Generation};
 
 pub(crate) struct Message {
     pub data: (u64, SGData),
+    /// Size of the data represented by the chunk
+    pub data_size: u64,
     pub data_type: DataType,
-    pub response_tx: mpsc::Sender<(u64, Digest)>,
+    pub response_tx: mpsc::Sender<(u64, (Digest, u64))>,
+}
+
+/// Level of `auto` compression, adjusted as the data is written
+///
+/// Every `ADJUST_INTERVAL` chunks, the share of the wall-clock time since
+/// the last adjustment that the chunk processors spent compressing is
+/// compared with the share the backend spent writing. Both handled the same
+/// data in that time, so the busier one holds up the other. While the
+/// backend is much busier, there's time to compress better, so the level is
+/// raised; once compression is busier, the level is lowered.
+///
+/// Starts with the lowest level.
+pub(crate) struct AdaptiveLevel {
+    min_level: i32,
+    max_level: i32,
+    /// Number of chunk processors compressing concurrently
+    threads: usize,
+    log: Logger,
+    inner: Mutex<AdaptiveLevelInner>,
+}
+
+/// Measurements since the level was last adjusted
+struct AdaptiveLevelInner {
+    level: i32,
+    /// Number of chunks compressed at every level so far
+    chunks_per_level: BTreeMap<i32, usize>,
+    chunks: usize,
+    since: Instant,
+    compress_time: Duration,
+    /// `AsyncIOThreadShared::write_throughput` when the level was last
+    /// adjusted
+    written_bytes: u64,
+    write_time: Duration,
+}
+
+impl AdaptiveLevel {
+    const ADJUST_INTERVAL: usize = 32;
+    /// How many times busier the backend has to be for the level to be
+    /// raised, so it doesn't flip back and forth
+    const RAISE_MARGIN: f64 = 2.0;
+
+    pub(crate) fn new(
+        min_level: i32,
+        max_level: i32,
+        threads: usize,
+        log: Logger,
+    ) -> Self {
+        AdaptiveLevel {
+            min_level,
+            max_level,
+            threads,
+            log,
+            inner: Mutex::new(AdaptiveLevelInner {
+                level: min_level,
+                chunks_per_level: BTreeMap::new(),
+                chunks: 0,
+                since: Instant::now(),
+                compress_time: Duration::ZERO,
+                written_bytes: 0,
+                write_time: Duration::ZERO,
+            }),
+        }
+    }
+
+    pub(crate) fn level(&self) -> i32 {
+        self.inner.lock().unwrap().level
+    }
+
+    /// Number of chunks compressed at every level so far
+    pub(crate) fn chunks_per_level(&self) -> BTreeMap<i32, usize> {
+        self.inner.lock().unwrap().chunks_per_level.clone()
+    }
+
+    /// Record that a chunk was compressed at `level` in `time`, adjusting
+    /// the level once in a while
+    pub(crate) fn record(
+        &self,
+        level: i32,
+        time: Duration,
+        stats: &aio::AsyncIOThreadShared,
+    ) {
+        let mut inner = self.inner.lock().unwrap();
+        *inner.chunks_per_level.entry(level).or_insert(0) += 1;
+        inner.chunks += 1;
+        inner.compress_time += time;
+        if inner.chunks < Self::ADJUST_INTERVAL {
+            return;
+        }
+
+        let (written_bytes, write_time) = stats.write_throughput();
+        let elapsed = inner.since.elapsed().as_secs_f64();
+        let mut level = inner.level;
+        // Nothing to compare with, if nothing was written in the meantime
+        if written_bytes > inner.written_bytes && elapsed > 0.0 {
+            // Every chunk processor can compress all the time
+            let compress_load = inner.compress_time.as_secs_f64()
+                / (elapsed * self.threads as f64);
+            let write_load =
+                (write_time - inner.write_time).as_secs_f64() / elapsed;
+            if write_load > compress_load * Self::RAISE_MARGIN {
+                level = (level + 1).min(self.max_level);
+            } else if compress_load > write_load {
+                level = (level - 1).max(self.min_level);
+            }
+            if level != inner.level {
+                debug!(self.log, "Adjusting compression level";
+                       "level" => level,
+                       "compress-load" => compress_load,
+                       "write-load" => write_load);
+            }
+        }
+
+        inner.level = level;
+        inner.chunks = 0;
+        inner.since = Instant::now();
+        inner.compress_time = Duration::ZERO;
+        inner.written_bytes = written_bytes;
+        inner.write_time = write_time;
+    }
 }
 
 pub(crate) struct ChunkProcessor {
@@ -25,8 +148,13 @@ pub(crate) struct ChunkProcessor {
     log: Logger,
     encrypter: ArcEncrypter,
     compressor: ArcCompression,
+    /// Picks the compression level of every chunk, with `auto` compression
+    adaptive_level: Option<Arc<AdaptiveLevel>>,
     hasher: ArcHasher,
     generations: Vec<Generation>,
+    /// First error compressing or encrypting a chunk, returned by the
+    /// write once all the chunks are processed
+    error: Arc<Mutex<Option<io::Error>>>,
 }
 
 impl ChunkProcessor {
@@ -35,23 +163,41 @@ impl ChunkProcessor {
         rx: crossbeam_channel::Receiver<Message>,
         aio: aio::AsyncIO,
         encrypter: ArcEncrypter,
-        compressor: ArcCompression,
-        hasher: ArcHasher,
+        adaptive_level: Option<Arc<AdaptiveLevel>>,
         generations: Vec<Generation>,
+        error: Arc<Mutex<Option<io::Error>>>,
     ) -> Self {
         assert!(!generations.is_empty());
+        let hasher = encrypter
+            .keyed_hasher()
+            .unwrap_or_else(|| Arc::clone(&repo.hasher));
         ChunkProcessor {
             log: repo.log.clone(),
+            compressor: repo.compression.clone(),
             repo,
             rx,
             aio,
             encrypter,
-            compressor,
+            adaptive_level,
             hasher,
             generations,
+            error,
         }
     }
 
+    fn compress(&self, sg: SGData) -> io::Result<SGData> {
+        let adaptive_level = match self.adaptive_level {
+            Some(ref adaptive_level) => adaptive_level,
+            None => return self.compressor.compress(sg),
+        };
+
+        let level = adaptive_level.level();
+        let start = Instant::now();
+        let sg = self.compressor.compress_at(sg, level)?;
+        adaptive_level.record(level, start.elapsed(), &self.aio.stats());
+        Ok(sg)
+    }
+
     pub fn run(&self) {
         let mut timer = TimeReporter::new_with_level(
             "chunk-processing",
@@ -71,6 +217,7 @@ impl ChunkProcessor {
 
                 let Message {
                     data,
+                    data_size,
                     response_tx,
                     data_type,
                 } = input;
@@ -151,31 +298,36 @@ impl ChunkProcessor {
                     let sg = if data_type.should_compress() {
                         trace!(self.log, "compress"; "path" => %chunk_path.display());
                         timer.start("compress");
-                        self.compressor.compress(sg).unwrap()
+                        self.compress(sg)
                     } else {
-                        sg
+                        Ok(sg)
                     };
 
-                    let sg = if data_type.should_encrypt() {
-                        trace!(self.log, "encrypt"; "path" => %chunk_path.display());
-                        timer.start("encrypt");
-                        self.encrypter.encrypt(sg, &digest.0).unwrap()
-                    } else {
-                        sg
-                    };
+                    let sg = sg.and_then(|sg| {
+                        if self.repo.should_encrypt(data_type) {
+                            trace!(self.log, "encrypt"; "path" => %chunk_path.display());
+                            timer.start("encrypt");
+                            self.encrypter.encrypt(sg, &digest.0)
+                        } else {
+                            Ok(sg)
+                        }
+                    });
 
-                    timer.start("tx-writer");
-                    self.aio.write_checked_idempotent(
-                        self.repo.chunk_rel_path_by_digest(
-                            digest.as_digest_ref(),
-                            &last_gen_str,
-                        ),
-                        sg,
-                    );
+                    match sg {
+                        Ok(sg) => {
+                            timer.start("tx-writer");
+                            self.aio.write_checked_idempotent(chunk_path, sg);
+                        }
+                        // The digest is still sent back, so the rest of the
+                        // data goes through and the write can fail as a whole
+                        Err(e) => {
+                            self.error.lock().unwrap().get_or_insert(e);
+                        }
+                    }
                 }
                 timer.start("tx-digest");
                 response_tx
-                    .send((sg_id, digest))
+                    .send((sg_id, (digest, data_size)))
                     .expect("chunk_processor: digests_tx.send")
             } else {
                 return;
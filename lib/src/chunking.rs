@@ -5,22 +5,28 @@ use owning_ref::ArcRef;
 
 use crate::rollsum;
 use crate::rollsum::CDC;
-use crate::SGData;
+use crate::{IndexFormat, SGData};
 
 /// Abstraction over the specific chunking algorithms being used
 pub(crate) trait Chunking {
     fn find_chunk<'a>(&mut self, buf: &'a [u8])
         -> Option<(&'a [u8], &'a [u8])>;
+
+    /// Forget any state accumulated so far, as if the engine was just
+    /// created
+    fn reset(&mut self);
 }
 
 pub(crate) struct Bup {
     engine: rollsum::Bup,
+    bits: u32,
 }
 
 impl Bup {
     pub fn new(bits: u32) -> Self {
         Bup {
             engine: rollsum::Bup::new_with_chunk_bits(bits),
+            bits,
         }
     }
 }
@@ -32,16 +38,22 @@ impl Chunking for Bup {
     ) -> Option<(&'a [u8], &'a [u8])> {
         self.engine.find_chunk(buf)
     }
+
+    fn reset(&mut self) {
+        self.engine = rollsum::Bup::new_with_chunk_bits(self.bits);
+    }
 }
 
 pub(crate) struct Gear {
     engine: rollsum::Gear,
+    bits: u32,
 }
 
 impl Gear {
     pub fn new(bits: u32) -> Self {
         Gear {
             engine: rollsum::Gear::new_with_chunk_bits(bits),
+            bits,
         }
     }
 }
@@ -53,16 +65,22 @@ impl Chunking for Gear {
     ) -> Option<(&'a [u8], &'a [u8])> {
         self.engine.find_chunk(buf)
     }
+
+    fn reset(&mut self) {
+        self.engine = rollsum::Gear::new_with_chunk_bits(self.bits);
+    }
 }
 
 pub(crate) struct FastCDC {
     engine: rollsum::FastCDC,
+    bits: u32,
 }
 
 impl FastCDC {
     pub fn new(bits: u32) -> Self {
         FastCDC {
             engine: rollsum::FastCDC::new_with_chunk_bits(bits),
+            bits,
         }
     }
 }
@@ -74,6 +92,10 @@ impl Chunking for FastCDC {
     ) -> Option<(&'a [u8], &'a [u8])> {
         self.engine.find_chunk(buf)
     }
+
+    fn reset(&mut self) {
+        self.engine = rollsum::FastCDC::new_with_chunk_bits(self.bits);
+    }
 }
 
 pub(crate) struct Chunker<I> {
@@ -153,3 +175,77 @@ impl<I: Iterator<Item = Vec<u8>>> Iterator for Chunker<I> {
         }
     }
 }
+
+/// Chunker of an index stream in `IndexFormat::DigestSize` format
+///
+/// Every item is expected to be a single, whole entry; it's stored
+/// in `index_format`, which drops the size with `IndexFormat::Digest`. Chunk
+/// boundaries are only placed between entries, so every index chunk can be
+/// interpreted on its own. Along with every chunk, the total size of data
+/// represented by its entries is returned.
+pub(crate) struct IndexChunker<I> {
+    iter: I,
+    /// Format the entries are stored in
+    index_format: IndexFormat,
+    incomplete_chunk: Vec<u8>,
+    incomplete_size: u64,
+    chunks_returned: usize,
+    chunking: Box<dyn Chunking>,
+}
+
+impl<I> IndexChunker<I> {
+    pub fn new(
+        iter: I,
+        chunking: Box<dyn Chunking>,
+        index_format: IndexFormat,
+    ) -> Self {
+        IndexChunker {
+            iter,
+            index_format,
+            incomplete_chunk: vec![],
+            incomplete_size: 0,
+            chunks_returned: 0,
+            chunking,
+        }
+    }
+
+    fn take_chunk(&mut self) -> (SGData, u64) {
+        self.chunks_returned += 1;
+        self.chunking.reset();
+        (
+            SGData::from_single(mem::take(&mut self.incomplete_chunk)),
+            mem::replace(&mut self.incomplete_size, 0),
+        )
+    }
+}
+
+impl<I: Iterator<Item = Vec<u8>>> Iterator for IndexChunker<I> {
+    type Item = (SGData, u64);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.iter.next() {
+                Some(entry) => {
+                    let (_, size) = IndexFormat::DigestSize.parse_entry(&entry);
+                    let entry = &entry[..self.index_format.entry_size()];
+                    let cut = self.chunking.find_chunk(entry).is_some();
+                    self.incomplete_chunk.extend_from_slice(entry);
+                    self.incomplete_size += size.expect("sized entry");
+
+                    // See the 64-byte minimum comment in `Chunker`
+                    if cut && self.incomplete_chunk.len() >= 64 {
+                        return Some(self.take_chunk());
+                    }
+                }
+                None => {
+                    if !self.incomplete_chunk.is_empty()
+                        || self.chunks_returned == 0
+                    {
+                        return Some(self.take_chunk());
+                    }
+                    return None;
+                }
+            }
+        }
+    }
+}
@@ -1,16 +1,13 @@
 #[cfg(feature = "with-xz2")]
 use std::cmp;
+use std::collections::HashMap;
 use std::io;
-#[cfg(feature = "with-zstd")]
+#[cfg(any(feature = "with-lz4", feature = "with-zstd"))]
 use std::io::Read;
-#[cfg(any(
-    feature = "with-bzip2",
-    feature = "with-deflate",
-    feature = "with-xz2",
-    feature = "with-zstd"
-))]
 use std::io::Write;
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
+#[cfg(feature = "with-zstd")]
+use std::sync::{Mutex, OnceLock};
 
 use owning_ref::ArcRef;
 use sgdata::SGData;
@@ -19,7 +16,35 @@ pub type ArcCompression = Arc<dyn Compression + Send + Sync>;
 
 pub trait Compression {
     fn compress(&self, buf: SGData) -> io::Result<SGData>;
-    fn decompress(&self, bug: SGData) -> io::Result<SGData>;
+
+    /// Compress `buf` at `level`, for engines whose level can be picked for
+    /// every chunk (see `AutoZstd`); others ignore it
+    fn compress_at(&self, buf: SGData, _level: i32) -> io::Result<SGData> {
+        self.compress(buf)
+    }
+
+    /// Decompress `buf` into `writer` as it goes, without holding all the
+    /// decompressed data at once
+    ///
+    /// On error, some of the data may have been written already.
+    fn decompress_into(
+        &self,
+        buf: SGData,
+        writer: &mut dyn Write,
+    ) -> io::Result<()>;
+
+    fn decompress(&self, buf: SGData) -> io::Result<SGData> {
+        let mut backing: Vec<u8> = Vec::with_capacity(buf.len());
+        self.decompress_into(buf, &mut backing)?;
+        Ok(SGData::from_single(backing))
+    }
+}
+
+fn write_parts(buf: &SGData, writer: &mut dyn Write) -> io::Result<()> {
+    for part in buf.as_parts() {
+        writer.write_all(part)?;
+    }
+    Ok(())
 }
 
 pub struct NoCompression;
@@ -28,6 +53,13 @@ impl Compression for NoCompression {
     fn compress(&self, buf: SGData) -> io::Result<SGData> {
         Ok(buf)
     }
+    fn decompress_into(
+        &self,
+        buf: SGData,
+        writer: &mut dyn Write,
+    ) -> io::Result<()> {
+        write_parts(&buf, writer)
+    }
     fn decompress(&self, buf: SGData) -> io::Result<SGData> {
         Ok(buf)
     }
@@ -58,21 +90,21 @@ impl Compression for Deflate {
             self.level,
         );
 
-        for sg_part in buf.as_parts() {
-            compressor.write_all(sg_part).unwrap();
-        }
+        write_parts(&buf, &mut compressor)?;
 
-        Ok(SGData::from_single(compressor.finish().unwrap()))
+        Ok(SGData::from_single(compressor.finish()?))
     }
 
-    fn decompress(&self, buf: SGData) -> io::Result<SGData> {
-        let mut decompressor =
-            flate2::write::DeflateDecoder::new(Vec::with_capacity(buf.len()));
+    fn decompress_into(
+        &self,
+        buf: SGData,
+        writer: &mut dyn Write,
+    ) -> io::Result<()> {
+        let mut decompressor = flate2::write::DeflateDecoder::new(writer);
 
-        for part in buf.as_parts() {
-            decompressor.write_all(part)?;
-        }
-        Ok(SGData::from_single(decompressor.finish()?))
+        write_parts(&buf, &mut decompressor)?;
+        decompressor.finish()?;
+        Ok(())
     }
 }
 
@@ -101,21 +133,21 @@ impl Compression for Bzip2 {
             self.level,
         );
 
-        for sg_part in buf.as_parts() {
-            compressor.write_all(sg_part).unwrap();
-        }
+        write_parts(&buf, &mut compressor)?;
 
-        Ok(SGData::from_single(compressor.finish().unwrap()))
+        Ok(SGData::from_single(compressor.finish()?))
     }
 
-    fn decompress(&self, buf: SGData) -> io::Result<SGData> {
-        let mut decompressor =
-            bzip2::write::BzDecoder::new(Vec::with_capacity(buf.len()));
+    fn decompress_into(
+        &self,
+        buf: SGData,
+        writer: &mut dyn Write,
+    ) -> io::Result<()> {
+        let mut decompressor = bzip2::write::BzDecoder::new(writer);
 
-        for sg_part in buf.as_parts() {
-            decompressor.write_all(sg_part)?;
-        }
-        Ok(SGData::from_single(decompressor.finish()?))
+        write_parts(&buf, &mut decompressor)?;
+        decompressor.finish()?;
+        Ok(())
     }
 }
 
@@ -132,68 +164,158 @@ impl Xz2 {
     }
 }
 #[cfg(feature = "with-xz2")]
-impl Compression for Xz2 {
-    fn compress(&self, buf: SGData) -> io::Result<SGData> {
-        let mut backing: Vec<u8> = Vec::with_capacity(buf.len());
-        {
-            let mut compressor =
-                lzma::LzmaWriter::new_compressor(&mut backing, self.level)
-                    .unwrap();
-            for sg_part in buf.as_parts() {
-                // compressor.write can sometimes return zero, so we can't just
-                // use write_all; see
-                // https://github.com/fpgaminer/rust-lzma/issues/13
-                let todo = sg_part.len();
-                let mut index = 0;
-                while index < todo {
-                    let bytes = compressor.write(&sg_part[index..]).unwrap();
-                    index += bytes;
-                }
-            }
-            compressor.finish().unwrap();
+fn lzma_error(e: lzma::LzmaError) -> io::Error {
+    match e {
+        lzma::LzmaError::Io(e) => e,
+        e @ (lzma::LzmaError::Format
+        | lzma::LzmaError::Data
+        | lzma::LzmaError::Buf) => {
+            io::Error::new(io::ErrorKind::InvalidData, e)
         }
-        Ok(SGData::from_single(backing))
+        e => io::Error::other(e),
     }
+}
 
-    fn decompress(&self, buf: SGData) -> io::Result<SGData> {
-        let mut backing: Vec<u8> = Vec::with_capacity(buf.len());
-        {
-            let mut decompressor =
-                lzma::LzmaWriter::new_decompressor(&mut backing).unwrap();
-            for sg_part in buf.as_parts() {
-                // compressor.write can sometimes return zero, so we can't just
-                // use write_all; see
-                // https://github.com/fpgaminer/rust-lzma/issues/13
-                let todo = sg_part.len();
-                let mut index = 0;
-                while index < todo {
-                    let bytes = decompressor.write(&sg_part[index..]).unwrap();
-                    index += bytes;
-                }
-            }
-            decompressor.finish().unwrap();
+/// `write_all` for `lzma::LzmaWriter`
+///
+/// Its `write` can sometimes return zero, so we can't just use `write_all`;
+/// see https://github.com/fpgaminer/rust-lzma/issues/13
+#[cfg(feature = "with-xz2")]
+fn lzma_write_parts<W: Write>(
+    buf: &SGData,
+    writer: &mut lzma::LzmaWriter<W>,
+) -> io::Result<()> {
+    for sg_part in buf.as_parts() {
+        let mut sg_part = &sg_part[..];
+        while !sg_part.is_empty() {
+            let bytes = writer.write(sg_part)?;
+            sg_part = &sg_part[bytes..];
         }
-        Ok(SGData::from_single(backing))
+    }
+    Ok(())
+}
+
+#[cfg(feature = "with-xz2")]
+impl Compression for Xz2 {
+    fn compress(&self, buf: SGData) -> io::Result<SGData> {
+        let mut compressor = lzma::LzmaWriter::new_compressor(
+            Vec::with_capacity(buf.len()),
+            self.level,
+        )
+        .map_err(lzma_error)?;
+        lzma_write_parts(&buf, &mut compressor)?;
+        Ok(SGData::from_single(
+            compressor.finish().map_err(lzma_error)?,
+        ))
+    }
+
+    fn decompress_into(
+        &self,
+        buf: SGData,
+        writer: &mut dyn Write,
+    ) -> io::Result<()> {
+        let mut decompressor =
+            lzma::LzmaWriter::new_decompressor(writer).map_err(lzma_error)?;
+        lzma_write_parts(&buf, &mut decompressor)?;
+        decompressor.finish().map_err(lzma_error)?;
+        Ok(())
+    }
+}
+
+/// Zstd dictionary, trained from the chunks of a repository
+pub(crate) struct Dictionary {
+    #[cfg_attr(not(feature = "with-zstd"), allow(dead_code))]
+    data: Vec<u8>,
+    #[cfg(feature = "with-zstd")]
+    decoder: zstd::dict::DecoderDictionary<'static>,
+}
+
+/// Zstd dictionaries of a repository loaded so far, by id
+///
+/// Shared by all the handles of a repository and their compression engines;
+/// the dictionaries are stored encrypted, so they're loaded only as the
+/// repository is unlocked.
+#[derive(Clone, Default)]
+pub(crate) struct Dictionaries(Arc<RwLock<HashMap<u32, Arc<Dictionary>>>>);
+
+impl Dictionaries {
+    pub(crate) fn contains(&self, id: u32) -> bool {
+        self.0.read().unwrap().contains_key(&id)
+    }
+
+    pub(crate) fn insert(&self, id: u32, data: Vec<u8>) {
+        let dict = Dictionary {
+            #[cfg(feature = "with-zstd")]
+            decoder: zstd::dict::DecoderDictionary::copy(&data),
+            data,
+        };
+        self.0.write().unwrap().insert(id, Arc::new(dict));
+    }
+
+    #[cfg_attr(not(feature = "with-zstd"), allow(dead_code))]
+    fn get(&self, id: u32) -> Option<Arc<Dictionary>> {
+        self.0.read().unwrap().get(&id).cloned()
     }
 }
 
 #[cfg(feature = "with-zstd")]
 pub struct Zstd {
     level: i32,
+    /// Id of the dictionary to compress against
+    dict: Option<u32>,
+    dicts: Dictionaries,
+    /// `dict` prepared for compression, once loaded
+    encoder_dict: OnceLock<zstd::dict::EncoderDictionary<'static>>,
 }
 #[cfg(feature = "with-zstd")]
 impl Zstd {
-    pub fn new(level: i32) -> Self {
-        Zstd { level }
+    /// Compress against the dictionary `dict`, and decompress with any of
+    /// `dicts`
+    ///
+    /// Compressing fails until `dict` is loaded.
+    pub(crate) fn with_dictionary(
+        level: i32,
+        dict: Option<u32>,
+        dicts: Dictionaries,
+    ) -> Self {
+        Zstd {
+            level,
+            dict,
+            dicts,
+            encoder_dict: OnceLock::new(),
+        }
+    }
+
+    fn encoder_dict(
+        &self,
+    ) -> io::Result<Option<&zstd::dict::EncoderDictionary<'static>>> {
+        let id = match self.dict {
+            Some(id) => id,
+            None => return Ok(None),
+        };
+        if let Some(encoder_dict) = self.encoder_dict.get() {
+            return Ok(Some(encoder_dict));
+        }
+        let dict = self.dicts.get(id).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("zstd dictionary {:08x} not loaded", id),
+            )
+        })?;
+        Ok(Some(self.encoder_dict.get_or_init(|| {
+            zstd::dict::EncoderDictionary::copy(&dict.data, self.level)
+        })))
     }
 }
 
+#[cfg(any(feature = "with-lz4", feature = "with-zstd"))]
 struct SGReader<'a> {
     parts: &'a [ArcRef<Vec<u8>, [u8]>],
     parts_i: usize,
     part_offset: usize,
 }
 
+#[cfg(any(feature = "with-lz4", feature = "with-zstd"))]
 impl<'a> SGReader<'a> {
     fn new(parts: &'a SGData) -> Self {
         SGReader {
@@ -204,6 +326,7 @@ impl<'a> SGReader<'a> {
     }
 }
 
+#[cfg(any(feature = "with-lz4", feature = "with-zstd"))]
 impl Read for SGReader<'_> {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         loop {
@@ -226,29 +349,382 @@ impl Read for SGReader<'_> {
     }
 }
 
+#[cfg(feature = "with-zstd")]
+fn zstd_compress(
+    buf: &SGData,
+    level: i32,
+    dict: Option<&zstd::dict::EncoderDictionary<'static>>,
+) -> io::Result<SGData> {
+    let backing: Vec<u8> = Vec::with_capacity(buf.len());
+    let mut compressor = match dict {
+        Some(dict) => zstd::Encoder::with_prepared_dictionary(backing, dict)?,
+        None => zstd::Encoder::new(backing, level)?,
+    };
+    write_parts(buf, &mut compressor)?;
+    Ok(SGData::from_single(compressor.finish()?))
+}
+
 #[cfg(feature = "with-zstd")]
 impl Compression for Zstd {
     fn compress(&self, buf: SGData) -> io::Result<SGData> {
-        let mut backing: Vec<u8> = Vec::with_capacity(buf.len());
-        {
-            let mut compressor =
-                zstd::Encoder::new(&mut backing, self.level).unwrap();
-            for sg_part in buf.as_parts() {
-                compressor.write_all(sg_part).unwrap()
+        zstd_compress(&buf, self.level, self.encoder_dict()?)
+    }
+
+    fn decompress_into(
+        &self,
+        buf: SGData,
+        writer: &mut dyn Write,
+    ) -> io::Result<()> {
+        // Ehh... https://github.com/gyscos/zstd-rs/issues/34
+        let mut reader = SGReader::new(&buf);
+        // Frames compressed against a dictionary record its id
+        let dict_id = buf
+            .as_parts()
+            .first()
+            .and_then(|part| zstd::zstd_safe::get_dict_id_from_frame(part));
+        match dict_id {
+            Some(id) => {
+                let dict = self.dicts.get(id.get()).ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!("zstd dictionary {:08x} not loaded", id),
+                    )
+                })?;
+                let mut decompressor = zstd::Decoder::with_prepared_dictionary(
+                    io::BufReader::new(&mut reader),
+                    &dict.decoder,
+                )?;
+                io::copy(&mut decompressor, writer)?;
+            }
+            None => {
+                let mut decompressor = zstd::Decoder::new(&mut reader)?;
+                io::copy(&mut decompressor, writer)?;
             }
-            compressor.finish().unwrap();
         }
-        Ok(SGData::from_single(backing))
+        Ok(())
     }
+}
 
-    fn decompress(&self, buf: SGData) -> io::Result<SGData> {
-        let mut backing: Vec<u8> = Vec::with_capacity(buf.len());
-        {
-            // Ehh... https://github.com/gyscos/zstd-rs/issues/34
-            let mut reader = SGReader::new(&buf);
-            let mut decompressor = zstd::Decoder::new(&mut reader).unwrap();
-            let _ = decompressor.read_to_end(&mut backing)?;
+/// Zstd, at a level picked by the writer for every chunk
+///
+/// Zstd frames don't depend on the level, so reading doesn't either.
+#[cfg(feature = "with-zstd")]
+pub struct AutoZstd {
+    min_level: i32,
+    max_level: i32,
+    /// Level of the chunks compressed without one being picked
+    default_level: i32,
+    dict: Option<u32>,
+    dicts: Dictionaries,
+    /// Engine of every level used so far, as the dictionary has to be
+    /// prepared for every level separately
+    levels: Mutex<HashMap<i32, Arc<Zstd>>>,
+}
+#[cfg(feature = "with-zstd")]
+impl AutoZstd {
+    /// Compress against the dictionary `dict`, and decompress with any of
+    /// `dicts`
+    pub(crate) fn new(
+        min_level: i32,
+        max_level: i32,
+        dict: Option<u32>,
+        dicts: Dictionaries,
+    ) -> Self {
+        // Chunks compressed outside of `ChunkProcessor`, like when
+        // migrating, don't get a level picked
+        let default_level =
+            zstd::DEFAULT_COMPRESSION_LEVEL.clamp(min_level, max_level);
+        AutoZstd {
+            min_level,
+            max_level,
+            default_level,
+            dict,
+            dicts,
+            levels: Mutex::new(HashMap::new()),
         }
+    }
+
+    fn at_level(&self, level: i32) -> Arc<Zstd> {
+        let level = level.clamp(self.min_level, self.max_level);
+        let mut levels = self.levels.lock().unwrap();
+        Arc::clone(levels.entry(level).or_insert_with(|| {
+            Arc::new(Zstd::with_dictionary(
+                level,
+                self.dict,
+                self.dicts.clone(),
+            ))
+        }))
+    }
+}
+#[cfg(feature = "with-zstd")]
+impl Compression for AutoZstd {
+    fn compress(&self, buf: SGData) -> io::Result<SGData> {
+        self.at_level(self.default_level).compress(buf)
+    }
+
+    fn compress_at(&self, buf: SGData, level: i32) -> io::Result<SGData> {
+        self.at_level(level).compress(buf)
+    }
+
+    fn decompress_into(
+        &self,
+        buf: SGData,
+        writer: &mut dyn Write,
+    ) -> io::Result<()> {
+        self.at_level(self.default_level)
+            .decompress_into(buf, writer)
+    }
+}
+
+#[cfg(feature = "with-lz4")]
+pub struct Lz4 {
+    level: u32,
+}
+#[cfg(feature = "with-lz4")]
+impl Lz4 {
+    pub fn new(level: i32) -> Self {
+        // 0 is the fast mode, with higher levels compressing better
+        let level = level.clamp(0, 12) as u32;
+
+        Lz4 { level }
+    }
+}
+#[cfg(feature = "with-lz4")]
+impl Compression for Lz4 {
+    fn compress(&self, buf: SGData) -> io::Result<SGData> {
+        let mut compressor = lz4::EncoderBuilder::new()
+            .level(self.level)
+            .build(Vec::with_capacity(buf.len()))?;
+        write_parts(&buf, &mut compressor)?;
+        let (backing, res) = compressor.finish();
+        res?;
         Ok(SGData::from_single(backing))
     }
+
+    fn decompress_into(
+        &self,
+        buf: SGData,
+        writer: &mut dyn Write,
+    ) -> io::Result<()> {
+        let mut decompressor = lz4::Decoder::new(SGReader::new(&buf))?;
+        io::copy(&mut decompressor, writer)?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "with-brotli")]
+pub struct Brotli {
+    quality: u32,
+}
+#[cfg(feature = "with-brotli")]
+impl Brotli {
+    /// Size of the sliding window, as log2
+    const LGWIN: u32 = 22;
+    const BUFFER_SIZE: usize = 4096;
+
+    pub fn new(level: i32) -> Self {
+        let quality = (level + 6).clamp(0, 11) as u32;
+
+        Brotli { quality }
+    }
+}
+#[cfg(feature = "with-brotli")]
+impl Compression for Brotli {
+    fn compress(&self, buf: SGData) -> io::Result<SGData> {
+        let mut compressor = brotli::CompressorWriter::new(
+            Vec::with_capacity(buf.len()),
+            Brotli::BUFFER_SIZE,
+            self.quality,
+            Brotli::LGWIN,
+        );
+        write_parts(&buf, &mut compressor)?;
+        Ok(SGData::from_single(compressor.into_inner()))
+    }
+
+    fn decompress_into(
+        &self,
+        buf: SGData,
+        writer: &mut dyn Write,
+    ) -> io::Result<()> {
+        let mut decompressor =
+            brotli::DecompressorWriter::new(writer, Brotli::BUFFER_SIZE);
+        write_parts(&buf, &mut decompressor)?;
+        decompressor.into_inner().map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidData, "truncated brotli data")
+        })?;
+        Ok(())
+    }
+}
+
+/// Codec of a data chunk, recorded in its first byte
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Codec {
+    /// Stored as it is
+    Stored,
+    Deflate,
+    Xz2,
+    Bzip2,
+    Zstd,
+    Lz4,
+    Brotli,
+}
+
+impl Codec {
+    fn tag(self) -> u8 {
+        match self {
+            Codec::Stored => 0,
+            Codec::Deflate => 1,
+            Codec::Xz2 => 2,
+            Codec::Bzip2 => 3,
+            Codec::Zstd => 4,
+            Codec::Lz4 => 5,
+            Codec::Brotli => 6,
+        }
+    }
+
+    fn from_tag(tag: u8) -> io::Result<Self> {
+        Ok(match tag {
+            0 => Codec::Stored,
+            1 => Codec::Deflate,
+            2 => Codec::Xz2,
+            3 => Codec::Bzip2,
+            4 => Codec::Zstd,
+            5 => Codec::Lz4,
+            6 => Codec::Brotli,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unknown chunk codec: {}", tag),
+                ))
+            }
+        })
+    }
+
+    /// Engine decompressing the chunks of this codec
+    ///
+    /// The level only matters when compressing.
+    #[cfg_attr(not(feature = "with-zstd"), allow(unused_variables))]
+    fn decompressor(self, dicts: &Dictionaries) -> io::Result<ArcCompression> {
+        Ok(match self {
+            Codec::Stored => Arc::new(NoCompression),
+            #[cfg(feature = "with-deflate")]
+            Codec::Deflate => Arc::new(Deflate::new(0)),
+            #[cfg(feature = "with-xz2")]
+            Codec::Xz2 => Arc::new(Xz2::new(0)),
+            #[cfg(feature = "with-bzip2")]
+            Codec::Bzip2 => Arc::new(Bzip2::new(0)),
+            #[cfg(feature = "with-zstd")]
+            Codec::Zstd => {
+                Arc::new(Zstd::with_dictionary(0, None, dicts.clone()))
+            }
+            #[cfg(feature = "with-lz4")]
+            Codec::Lz4 => Arc::new(Lz4::new(0)),
+            #[cfg(feature = "with-brotli")]
+            Codec::Brotli => Arc::new(Brotli::new(0)),
+            #[allow(unreachable_patterns)]
+            codec => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    format!("chunk compressed with {:?}, not built in", codec),
+                ))
+            }
+        })
+    }
+}
+
+/// Compression of repositories tagging every data chunk with its codec
+///
+/// Chunks that compression doesn't make any smaller are stored as they
+/// are, and reading doesn't depend on the codec configured for the
+/// repository, which may have changed since.
+pub(crate) struct Tagged {
+    codec: Codec,
+    engine: ArcCompression,
+    /// Dictionaries of zstd compressed chunks
+    dicts: Dictionaries,
+}
+
+impl Tagged {
+    pub(crate) fn new(
+        codec: Codec,
+        engine: ArcCompression,
+        dicts: Dictionaries,
+    ) -> Self {
+        Tagged {
+            codec,
+            engine,
+            dicts,
+        }
+    }
+}
+
+fn with_tag(codec: Codec, mut buf: SGData) -> SGData {
+    buf.as_vec_mut()
+        .insert(0, ArcRef::new(Arc::new(vec![codec.tag()])).map(|v| &v[..]));
+    buf
+}
+
+impl Tagged {
+    /// Tag `compressed`, or `buf` if compressing didn't make it any smaller
+    fn tag_compressed(&self, buf: SGData, compressed: SGData) -> SGData {
+        if compressed.len() < buf.len() {
+            with_tag(self.codec, compressed)
+        } else {
+            with_tag(Codec::Stored, buf)
+        }
+    }
+
+    /// Engine decompressing the chunks of `codec`
+    fn decompressor(&self, codec: Codec) -> io::Result<ArcCompression> {
+        if codec == self.codec {
+            Ok(self.engine.clone())
+        } else {
+            codec.decompressor(&self.dicts)
+        }
+    }
+}
+
+/// Codec of a tagged chunk, along with the chunk without the tag
+fn split_tag(buf: SGData) -> io::Result<(Codec, SGData)> {
+    let buf = buf.to_linear();
+    let tag = *buf.first().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            "chunk too short to even contain a codec",
+        )
+    })?;
+    let codec = Codec::from_tag(tag)?;
+    Ok((codec, SGData::from_vec(vec![buf.map(|b| &b[1..])])))
+}
+
+impl Compression for Tagged {
+    fn compress(&self, buf: SGData) -> io::Result<SGData> {
+        if self.codec == Codec::Stored {
+            return Ok(with_tag(Codec::Stored, buf));
+        }
+        let compressed = self.engine.compress(buf.clone())?;
+        Ok(self.tag_compressed(buf, compressed))
+    }
+
+    fn compress_at(&self, buf: SGData, level: i32) -> io::Result<SGData> {
+        if self.codec == Codec::Stored {
+            return Ok(with_tag(Codec::Stored, buf));
+        }
+        let compressed = self.engine.compress_at(buf.clone(), level)?;
+        Ok(self.tag_compressed(buf, compressed))
+    }
+
+    fn decompress_into(
+        &self,
+        buf: SGData,
+        writer: &mut dyn Write,
+    ) -> io::Result<()> {
+        let (codec, data) = split_tag(buf)?;
+        self.decompressor(codec)?.decompress_into(data, writer)
+    }
+
+    fn decompress(&self, buf: SGData) -> io::Result<SGData> {
+        let (codec, data) = split_tag(buf)?;
+        self.decompressor(codec)?.decompress(data)
+    }
 }
@@ -1,3 +1,4 @@
+use std::io;
 use std::sync::Arc;
 
 use serde::{Deserialize, Serialize};
@@ -19,6 +20,15 @@ pub enum Compression {
     #[cfg(feature = "with-zstd")]
     #[serde(rename = "zstd")]
     Zstd(Zstd),
+    #[cfg(feature = "with-zstd")]
+    #[serde(rename = "auto")]
+    Auto(Auto),
+    #[cfg(feature = "with-lz4")]
+    #[serde(rename = "lz4")]
+    Lz4(Lz4),
+    #[cfg(feature = "with-brotli")]
+    #[serde(rename = "brotli")]
+    Brotli(Brotli),
     #[serde(rename = "none")]
     None,
 }
@@ -34,7 +44,11 @@ impl Default for Compression {
 
 impl Compression {
     #[allow(clippy::wrong_self_convention)]
-    pub(crate) fn to_engine(&self) -> compression::ArcCompression {
+    #[cfg_attr(not(feature = "with-zstd"), allow(unused_variables))]
+    pub(crate) fn to_engine(
+        &self,
+        dicts: &compression::Dictionaries,
+    ) -> compression::ArcCompression {
         match *self {
             Compression::None => Arc::new(compression::NoCompression),
             #[cfg(feature = "with-deflate")]
@@ -46,7 +60,141 @@ impl Compression {
             #[cfg(feature = "with-bzip2")]
             Compression::Bzip2(d) => Arc::new(compression::Bzip2::new(d.level)),
             #[cfg(feature = "with-zstd")]
-            Compression::Zstd(d) => Arc::new(compression::Zstd::new(d.level)),
+            Compression::Zstd(d) => {
+                Arc::new(compression::Zstd::with_dictionary(
+                    d.level,
+                    d.dict,
+                    dicts.clone(),
+                ))
+            }
+            #[cfg(feature = "with-zstd")]
+            Compression::Auto(d) => Arc::new(compression::AutoZstd::new(
+                d.min_level,
+                d.max_level,
+                d.dict,
+                dicts.clone(),
+            )),
+            #[cfg(feature = "with-lz4")]
+            Compression::Lz4(d) => Arc::new(compression::Lz4::new(d.level)),
+            #[cfg(feature = "with-brotli")]
+            Compression::Brotli(d) => {
+                Arc::new(compression::Brotli::new(d.level))
+            }
+        }
+    }
+
+    /// Codec the data chunks compressed this way are tagged with
+    pub(crate) fn codec(&self) -> compression::Codec {
+        match *self {
+            Compression::None => compression::Codec::Stored,
+            #[cfg(feature = "with-deflate")]
+            Compression::Deflate(_) => compression::Codec::Deflate,
+            #[cfg(feature = "with-xz2")]
+            Compression::Xz2(_) => compression::Codec::Xz2,
+            #[cfg(feature = "with-bzip2")]
+            Compression::Bzip2(_) => compression::Codec::Bzip2,
+            #[cfg(feature = "with-zstd")]
+            Compression::Zstd(_) | Compression::Auto(_) => {
+                compression::Codec::Zstd
+            }
+            #[cfg(feature = "with-lz4")]
+            Compression::Lz4(_) => compression::Codec::Lz4,
+            #[cfg(feature = "with-brotli")]
+            Compression::Brotli(_) => compression::Codec::Brotli,
+        }
+    }
+
+    /// Check the settings are usable
+    ///
+    /// Only the level bounds of `auto` can be invalid: checking the levels
+    /// of the other engines is left to them.
+    pub(crate) fn validate(&self) -> io::Result<()> {
+        #[cfg(feature = "with-zstd")]
+        if let Compression::Auto(d) = *self {
+            let range = zstd::compression_level_range();
+            if d.min_level > d.max_level
+                || !range.contains(&d.min_level)
+                || !range.contains(&d.max_level)
+            {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "invalid auto compression levels: {} to {}",
+                        d.min_level, d.max_level
+                    ),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Bounds of the level picked for every chunk, with `auto` compression
+    pub(crate) fn auto_levels(&self) -> Option<(i32, i32)> {
+        match *self {
+            #[cfg(feature = "with-zstd")]
+            Compression::Auto(d) => Some((d.min_level, d.max_level)),
+            _ => None,
+        }
+    }
+
+    /// Id of the zstd dictionary new chunks are compressed against
+    pub(crate) fn dict(&self) -> Option<u32> {
+        match *self {
+            #[cfg(feature = "with-zstd")]
+            Compression::Zstd(d) => d.dict,
+            #[cfg(feature = "with-zstd")]
+            Compression::Auto(d) => d.dict,
+            _ => None,
+        }
+    }
+
+    /// The same zstd or `auto` compression, against the dictionary `id`
+    #[cfg(feature = "with-zstd")]
+    pub(crate) fn with_dict(self, id: u32) -> Self {
+        match self {
+            Compression::Zstd(d) => Compression::Zstd(Zstd {
+                dict: Some(id),
+                ..d
+            }),
+            Compression::Auto(d) => Compression::Auto(Auto {
+                dict: Some(id),
+                ..d
+            }),
+            other => other,
+        }
+    }
+
+    /// The same compression, with another level
+    ///
+    /// `auto` compression has no single level, and is left as it is.
+    #[cfg_attr(
+        not(any(
+            feature = "with-deflate",
+            feature = "with-xz2",
+            feature = "with-bzip2",
+            feature = "with-zstd",
+            feature = "with-lz4",
+            feature = "with-brotli"
+        )),
+        allow(unused_variables)
+    )]
+    pub(crate) fn with_level(self, level: i32) -> Self {
+        match self {
+            Compression::None => Compression::None,
+            #[cfg(feature = "with-deflate")]
+            Compression::Deflate(_) => Compression::Deflate(Deflate { level }),
+            #[cfg(feature = "with-xz2")]
+            Compression::Xz2(_) => Compression::Xz2(Xz2 { level }),
+            #[cfg(feature = "with-bzip2")]
+            Compression::Bzip2(_) => Compression::Bzip2(Bzip2 { level }),
+            #[cfg(feature = "with-zstd")]
+            Compression::Zstd(d) => Compression::Zstd(Zstd { level, ..d }),
+            #[cfg(feature = "with-zstd")]
+            Compression::Auto(d) => Compression::Auto(d),
+            #[cfg(feature = "with-lz4")]
+            Compression::Lz4(_) => Compression::Lz4(Lz4 { level }),
+            #[cfg(feature = "with-brotli")]
+            Compression::Brotli(_) => Compression::Brotli(Brotli { level }),
         }
     }
 }
@@ -80,11 +228,38 @@ impl Bzip2 {
 pub struct Zstd {
     #[serde(rename = "level")]
     level: i32,
+    /// Id of the dictionary new chunks are compressed against, see
+    /// `Repo::train_dictionary`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) dict: Option<u32>,
 }
 #[cfg(feature = "with-zstd")]
 impl Zstd {
     pub fn new(level: i32) -> Self {
-        Zstd { level }
+        Zstd { level, dict: None }
+    }
+}
+
+/// Zstd, at a level picked for every chunk as the data is written, see
+/// `AdaptiveLevel`
+#[cfg(feature = "with-zstd")]
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Auto {
+    min_level: i32,
+    max_level: i32,
+    /// Id of the dictionary new chunks are compressed against, see
+    /// `Repo::train_dictionary`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) dict: Option<u32>,
+}
+#[cfg(feature = "with-zstd")]
+impl Auto {
+    pub fn new(min_level: i32, max_level: i32) -> Self {
+        Auto {
+            min_level,
+            max_level,
+            dict: None,
+        }
     }
 }
 
@@ -100,3 +275,29 @@ impl Xz2 {
         Xz2 { level }
     }
 }
+
+#[cfg(feature = "with-lz4")]
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Lz4 {
+    #[serde(rename = "level")]
+    level: i32,
+}
+#[cfg(feature = "with-lz4")]
+impl Lz4 {
+    pub fn new(level: i32) -> Self {
+        Lz4 { level }
+    }
+}
+
+#[cfg(feature = "with-brotli")]
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Brotli {
+    #[serde(rename = "level")]
+    level: i32,
+}
+#[cfg(feature = "with-brotli")]
+impl Brotli {
+    pub fn new(level: i32) -> Self {
+        Brotli { level }
+    }
+}
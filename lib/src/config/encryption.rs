@@ -16,19 +16,162 @@ pub(crate) enum Encryption {
     /// `Curve25519Blake2BSalsa20Poly1305`
     #[serde(rename = "curve25519_blake2b_salsa20_poly1305")]
     Curve25519(encryption::Curve25519),
+    /// `XChaCha20Poly1305` with keyed BLAKE2b chunk hashing
+    #[serde(rename = "xchacha20_poly1305_blake2b_keyed")]
+    XChaCha20Poly1305(encryption::XChaCha20Poly1305),
+}
+
+impl Encryption {
+    /// Whether chunks encrypted with `self` can be decrypted with `other`
+    pub(crate) fn same_keys(&self, other: &Encryption) -> bool {
+        match (self, other) {
+            (Encryption::None, Encryption::None) => true,
+            (Encryption::Curve25519(a), Encryption::Curve25519(b)) => {
+                a.pub_key == b.pub_key
+            }
+            (
+                Encryption::XChaCha20Poly1305(a),
+                Encryption::XChaCha20Poly1305(b),
+            ) => a.key_id == b.key_id,
+            _ => false,
+        }
+    }
+
+    /// Whether the chunk digests are keyed with the secret key
+    pub(crate) fn keyed_hashing(&self) -> bool {
+        matches!(*self, Encryption::XChaCha20Poly1305(_))
+    }
+
+    fn slots(&self) -> Option<&encryption::KeySlots> {
+        match *self {
+            Encryption::None => None,
+            Encryption::Curve25519(ref c) => Some(&c.slots),
+            Encryption::XChaCha20Poly1305(ref c) => Some(&c.slots),
+        }
+    }
+
+    fn slots_mut(&mut self) -> io::Result<&mut encryption::KeySlots> {
+        match *self {
+            Encryption::None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "repository is not encrypted",
+            )),
+            Encryption::Curve25519(ref mut c) => Ok(&mut c.slots),
+            Encryption::XChaCha20Poly1305(ref mut c) => Ok(&mut c.slots),
+        }
+    }
+
+    /// Error for the operations on key files, holding a `Curve25519` secret
+    /// key only
+    fn no_key_files(&self) -> io::Error {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            match *self {
+                Encryption::None => "repository is not encrypted",
+                _ => "key files are supported with curve25519 encryption only",
+            },
+        )
+    }
+
+    fn curve25519(&self) -> io::Result<&encryption::Curve25519> {
+        match *self {
+            Encryption::Curve25519(ref c) => Ok(c),
+            ref other => Err(other.no_key_files()),
+        }
+    }
+
+    fn curve25519_mut(&mut self) -> io::Result<&mut encryption::Curve25519> {
+        match *self {
+            Encryption::Curve25519(ref mut c) => Ok(c),
+            ref other => Err(other.no_key_files()),
+        }
+    }
+
+    /// Names of the key slots (none if not encrypted)
+    pub(crate) fn key_slots(&self) -> Vec<String> {
+        self.slots().map(|slots| slots.names()).unwrap_or_default()
+    }
+
+    pub(crate) fn is_encrypt_only(&self) -> bool {
+        match *self {
+            Encryption::Curve25519(ref c) => c.is_encrypt_only(),
+            _ => false,
+        }
+    }
+
+    pub(crate) fn make_encrypt_only(
+        &mut self,
+        keyfile: &encryption::KeyFile,
+        keyfile_p: PassphraseFn<'_>,
+    ) -> io::Result<()> {
+        self.curve25519_mut()?.make_encrypt_only(keyfile, keyfile_p)
+    }
+
+    pub(crate) fn add_key_slot(
+        &mut self,
+        name: &str,
+        p: PassphraseFn<'_>,
+        new_p: PassphraseFn<'_>,
+        pwhash: &config::PWHash,
+    ) -> io::Result<()> {
+        self.slots_mut()?.add(name, p, new_p, pwhash)
+    }
+
+    pub(crate) fn remove_key_slot(
+        &mut self,
+        name: &str,
+        p: PassphraseFn<'_>,
+        pwhash: &config::PWHash,
+    ) -> io::Result<()> {
+        self.slots_mut()?.remove(name, p, pwhash)
+    }
+
+    pub(crate) fn export_key(
+        &self,
+        p: PassphraseFn<'_>,
+        seal_p: Option<PassphraseFn<'_>>,
+        pwhash: &config::PWHash,
+    ) -> io::Result<encryption::KeyFile> {
+        self.curve25519()?.export_key(p, seal_p, pwhash)
+    }
+
+    pub(crate) fn keyfile_decrypter(
+        &self,
+        keyfile: &encryption::KeyFile,
+        keyfile_p: PassphraseFn<'_>,
+    ) -> io::Result<encryption::ArcDecrypter> {
+        self.curve25519()?.keyfile_decrypter(keyfile, keyfile_p)
+    }
+
+    pub(crate) fn import_key_slot(
+        &mut self,
+        name: &str,
+        keyfile: &encryption::KeyFile,
+        keyfile_p: PassphraseFn<'_>,
+        new_p: PassphraseFn<'_>,
+        pwhash: &config::PWHash,
+    ) -> io::Result<()> {
+        self.curve25519_mut()?
+            .import_slot(name, keyfile, keyfile_p, new_p, pwhash)
+    }
 }
 
 impl encryption::EncryptionEngine for Encryption {
     fn change_passphrase(
         &mut self,
+        slot: Option<&str>,
         old_p: PassphraseFn<'_>,
         new_p: PassphraseFn<'_>,
         pwhash: &config::PWHash,
+        new_pwhash: Option<&config::PWHash>,
     ) -> io::Result<()> {
         match *self {
             Encryption::None => Ok(()),
             Encryption::Curve25519(ref mut c) => {
-                c.change_passphrase(old_p, new_p, pwhash)
+                c.change_passphrase(slot, old_p, new_p, pwhash, new_pwhash)
+            }
+            Encryption::XChaCha20Poly1305(ref mut c) => {
+                c.change_passphrase(slot, old_p, new_p, pwhash, new_pwhash)
             }
         }
     }
@@ -41,6 +184,7 @@ impl encryption::EncryptionEngine for Encryption {
         match *self {
             Encryption::None => Ok(Arc::new(encryption::NopEncrypter)),
             Encryption::Curve25519(ref c) => c.encrypter(pass, pwhash),
+            Encryption::XChaCha20Poly1305(ref c) => c.encrypter(pass, pwhash),
         }
     }
     fn decrypter(
@@ -51,6 +195,7 @@ impl encryption::EncryptionEngine for Encryption {
         match *self {
             Encryption::None => Ok(Arc::new(encryption::NopDecrypter)),
             Encryption::Curve25519(ref c) => c.decrypter(pass, pwhash),
+            Encryption::XChaCha20Poly1305(ref c) => c.decrypter(pass, pwhash),
         }
     }
 }
@@ -12,7 +12,7 @@ use crate::aio;
 use crate::hashing;
 use crate::pwhash;
 use crate::settings;
-use crate::{PassphraseFn, SGData};
+use crate::{IndexFormat, PassphraseFn, SGData};
 
 mod chunking;
 mod compression;
@@ -24,7 +24,11 @@ pub(crate) use self::encryption::*;
 // }}}
 
 pub const REPO_VERSION_LOWEST: u32 = 3;
-pub const REPO_VERSION_CURRENT: u32 = 3;
+pub const REPO_VERSION_CURRENT: u32 = 5;
+/// First version tagging the data chunks with their codec
+pub const REPO_VERSION_CHUNK_CODEC: u32 = 4;
+/// First version recording the size of the data in the index entries
+pub const REPO_VERSION_SIZED_INDEX: u32 = 5;
 
 pub const DATA_SUBDIR: &str = "chunk";
 pub const LOCK_FILE: &str = ".lock";
@@ -38,6 +42,8 @@ pub const CONFIG_YML_FILE: &str = "config.yml";
 pub(crate) enum PWHash {
     #[serde(rename = "scryptsalsa208sha256")]
     SodiumOxide(pwhash::SodiumOxide),
+    #[serde(rename = "argon2id")]
+    Argon2id(pwhash::Argon2id),
 }
 
 impl Default for PWHash {
@@ -47,17 +53,33 @@ impl Default for PWHash {
 }
 
 impl PWHash {
-    fn from_settings(pwhash: settings::PWHash) -> Self {
-        match pwhash {
-            settings::PWHash::Weak => {
-                PWHash::SodiumOxide(pwhash::SodiumOxide::new_weak())
-            }
-            settings::PWHash::Interactive => {
-                PWHash::SodiumOxide(pwhash::SodiumOxide::new_interactive())
-            }
-            settings::PWHash::Strong => {
-                PWHash::SodiumOxide(pwhash::SodiumOxide::new_sensitive())
-            }
+    pub(crate) fn from_settings(pwhash: &settings::PWHashSettings) -> Self {
+        let (mem_limit, ops_limit) = (pwhash.mem_limit, pwhash.ops_limit);
+        match pwhash.alg {
+            settings::PWHashAlg::Scrypt => PWHash::SodiumOxide(
+                match pwhash.strength {
+                    settings::PWHash::Weak => pwhash::SodiumOxide::new_weak(),
+                    settings::PWHash::Interactive => {
+                        pwhash::SodiumOxide::new_interactive()
+                    }
+                    settings::PWHash::Strong => {
+                        pwhash::SodiumOxide::new_sensitive()
+                    }
+                }
+                .with_limits(mem_limit, ops_limit),
+            ),
+            settings::PWHashAlg::Argon2id => PWHash::Argon2id(
+                match pwhash.strength {
+                    settings::PWHash::Weak => pwhash::Argon2id::new_weak(),
+                    settings::PWHash::Interactive => {
+                        pwhash::Argon2id::new_interactive()
+                    }
+                    settings::PWHash::Strong => {
+                        pwhash::Argon2id::new_sensitive()
+                    }
+                }
+                .with_limits(mem_limit, ops_limit),
+            ),
         }
     }
 }
@@ -66,6 +88,7 @@ impl pwhash::PWHash for PWHash {
     fn derive_key(&self, passphrase: &str) -> io::Result<Vec<u8>> {
         match *self {
             PWHash::SodiumOxide(ref so) => so.derive_key(passphrase),
+            PWHash::Argon2id(ref a) => a.derive_key(passphrase),
         }
     }
 }
@@ -145,6 +168,22 @@ pub(crate) struct Repo {
     pub encryption: Encryption,
     #[serde(default)]
     pub nesting: Nesting,
+    /// Whether index chunks and name files are encrypted too
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub encrypt_index: bool,
+    /// Migration to other settings in progress, see `Repo::migrate`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub migration: Option<Migration>,
+}
+
+/// Settings the chunks are being migrated to
+#[derive(Serialize, Deserialize, Clone)]
+pub(crate) struct Migration {
+    /// Generation the migrated chunks and names are moved to
+    pub generation: String,
+    pub compression: Compression,
+    pub encryption: Encryption,
+    pub nesting: Nesting,
 }
 
 impl Repo {
@@ -152,27 +191,80 @@ impl Repo {
         pass: PassphraseFn<'_>,
         settings: settings::Repo,
     ) -> io::Result<Self> {
-        let pwhash = PWHash::from_settings(settings.pwhash);
+        let compression =
+            settings.compression.to_config(settings.compression_level);
+        compression.validate()?;
+        let pwhash = PWHash::from_settings(&settings.pwhash);
         let encryption = match settings.encryption {
             settings::Encryption::Curve25519 => Encryption::Curve25519(
                 crate::encryption::Curve25519::new(pass, &pwhash)?,
             ),
+            settings::Encryption::XChaCha20Poly1305 => {
+                if !matches!(settings.hashing, settings::Hashing::Blake2b) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "xchacha20poly1305 encryption requires blake2b hashing",
+                    ));
+                }
+                Encryption::XChaCha20Poly1305(
+                    crate::encryption::XChaCha20Poly1305::new(pass, &pwhash)?,
+                )
+            }
             settings::Encryption::None => Encryption::None,
         };
+        if settings.encrypt_index && matches!(encryption, Encryption::None) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "index encryption requires encryption",
+            ));
+        }
 
         Ok(Repo {
             version: REPO_VERSION_CURRENT,
             pwhash,
             chunking: settings.chunking.0,
             encryption,
-            compression: settings
-                .compression
-                .to_config(settings.compression_level),
+            compression,
             nesting: settings.nesting.to_config(),
             hashing: settings.hashing.to_config(),
+            encrypt_index: settings.encrypt_index,
+            migration: None,
         })
     }
 
+    /// Whether the data chunks are tagged with their codec
+    pub fn tags_chunk_codec(&self) -> bool {
+        self.version >= REPO_VERSION_CHUNK_CODEC
+    }
+
+    /// Format of the index entries written to the repository
+    ///
+    /// Older versions of rdedup would misread entries with sizes.
+    pub(crate) fn index_format(&self) -> IndexFormat {
+        if self.version >= REPO_VERSION_SIZED_INDEX {
+            IndexFormat::DigestSize
+        } else {
+            IndexFormat::Digest
+        }
+    }
+
+    /// Engine (de)compressing the data chunks, with the zstd dictionaries
+    /// `dicts`
+    pub fn compression_engine(
+        &self,
+        dicts: &crate::compression::Dictionaries,
+    ) -> crate::compression::ArcCompression {
+        let engine = self.compression.to_engine(dicts);
+        if !self.tags_chunk_codec() {
+            return engine;
+        }
+        Arc::new(crate::compression::Tagged::new(
+            self.compression.codec(),
+            engine,
+            dicts.clone(),
+        ))
+    }
+
     pub fn write(&self, aio: &aio::AsyncIO) -> super::Result<()> {
         let config_str =
             serde_yaml::to_string(self).expect("yaml serialization failed");
@@ -199,6 +291,7 @@ impl Repo {
             })?;
 
         check_version(config.version)?;
+        config.compression.validate()?;
 
         Ok(config)
     }
@@ -0,0 +1,179 @@
+//! Zstd dictionaries
+//!
+//! With small chunks, zstd compresses much better against a dictionary
+//! trained from similar data. `Repo::train_dictionary` trains one from a
+//! sample of the data chunks, and stores it in `dict/`, next to
+//! `config.yml`, named after its id. Dictionaries are made of pieces of the
+//! data, so they're encrypted just like the data chunks.
+//!
+//! Zstd records the id of the dictionary in every frame compressed against
+//! one, so each chunk is decompressed with the dictionary it was compressed
+//! against, whatever the current one is. Dictionaries are never removed.
+
+// {{{ use and mod
+#[cfg(feature = "with-zstd")]
+use std::collections::HashSet;
+use std::io;
+use std::path::PathBuf;
+
+use slog::info;
+
+use crate::hashing::{self, Hasher};
+#[cfg(feature = "with-zstd")]
+use crate::name::Name;
+#[cfg(feature = "with-zstd")]
+use crate::reading::ChunkAccessor;
+use crate::util::substitute_err_not_found;
+use crate::{ArcDecrypter, Repo, SGData};
+#[cfg(feature = "with-zstd")]
+use crate::{ArcEncrypter, DataType, DigestRef};
+// }}}
+
+pub(crate) const DICT_SUBDIR: &str = "dict";
+
+fn rel_path(id: u32) -> String {
+    format!("{}/{:08x}", DICT_SUBDIR, id)
+}
+
+/// Digest the content of an encrypted dictionary is bound to, so it can't be
+/// passed for another one
+fn file_digest(id: u32) -> Vec<u8> {
+    hashing::Blake2b
+        .calculate_digest(&SGData::from_single(rel_path(id).into_bytes()))
+}
+
+/// Ids of the dictionaries stored in the repository
+pub(crate) fn list(repo: &Repo) -> io::Result<Vec<u32>> {
+    let list = substitute_err_not_found(
+        repo.aio.list(PathBuf::from(DICT_SUBDIR)).wait(),
+        Vec::new,
+    )?;
+
+    list.iter()
+        .map(|path| {
+            path.file_name()
+                .and_then(|file| file.to_str())
+                .and_then(|file| u32::from_str_radix(file, 16).ok())
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("malformed dictionary: {}", path.display()),
+                    )
+                })
+        })
+        .collect()
+}
+
+/// Load the dictionaries of `repo` not loaded yet
+///
+/// Without `decrypter`, nothing is loaded.
+pub(crate) fn load(
+    repo: &Repo,
+    decrypter: Option<&ArcDecrypter>,
+) -> io::Result<()> {
+    let decrypter = match decrypter {
+        Some(decrypter) => decrypter,
+        None => return Ok(()),
+    };
+    for id in list(repo)? {
+        if repo.dictionaries.contains(id) {
+            continue;
+        }
+        let data = repo.aio.read(rel_path(id).into()).wait()?;
+        let data = decrypter.decrypt(data, &file_digest(id))?;
+        repo.dictionaries.insert(id, data.into_linear_vec());
+    }
+    Ok(())
+}
+
+/// Store the dictionary `data`, unless stored already
+#[cfg(feature = "with-zstd")]
+pub(crate) fn write(
+    repo: &Repo,
+    encrypter: &ArcEncrypter,
+    id: u32,
+    data: &[u8],
+) -> io::Result<()> {
+    let data = encrypter
+        .encrypt(SGData::from_single(data.to_vec()), &file_digest(id))?;
+    // The id is derived from the content
+    repo.aio.write_idempotent(rel_path(id).into(), data).wait()
+}
+
+/// Copy the dictionaries of `src` missing in `dst` as they are
+pub(crate) fn copy(src: &Repo, dst: &Repo) -> io::Result<()> {
+    let dst_ids = list(dst)?;
+    for id in list(src)? {
+        if dst_ids.contains(&id) {
+            continue;
+        }
+        info!(src.log, "copying dictionary"; "id" => format!("{:08x}", id));
+        let data = src.aio.read(rel_path(id).into()).wait()?;
+        dst.aio.write_idempotent(rel_path(id).into(), data).wait()?;
+    }
+    Ok(())
+}
+
+/// Train a dictionary of at most `dict_size` bytes from the data chunks of
+/// `repo`, returning it along with its id
+///
+/// Chunks are sampled in no particular order, up to a hundred times
+/// `dict_size` in total, as zstd recommends.
+#[cfg(feature = "with-zstd")]
+pub(crate) fn train(
+    repo: &Repo,
+    decrypter: &ArcDecrypter,
+    dict_size: usize,
+) -> io::Result<(u32, Vec<u8>)> {
+    let generations = repo.read_generations()?;
+    let index_decrypter = repo.index_decrypter(Some(decrypter))?;
+    let accessor = repo.get_chunk_accessor(
+        Some(decrypter.clone()),
+        repo.compression.clone(),
+        generations.clone(),
+    );
+
+    let mut samples = vec![];
+    let mut samples_size = 0;
+    let mut sampled = HashSet::new();
+    'names: for name_str in Name::list_all(&generations, &repo.aio)? {
+        let name = Name::load_from_any(
+            &name_str,
+            &generations,
+            &repo.aio,
+            index_decrypter.as_ref(),
+        )?;
+        let chunks = crate::sync::reachable_chunks(
+            repo,
+            &name,
+            &generations,
+            index_decrypter.as_ref(),
+        )?;
+        for (digest, data_type) in chunks {
+            if data_type != DataType::Data || !sampled.insert(digest.clone()) {
+                continue;
+            }
+            let mut sample = vec![];
+            accessor.read_chunk_into(
+                DigestRef(&digest),
+                DataType::Data,
+                &mut sample,
+            )?;
+            samples_size += sample.len();
+            samples.push(sample);
+            if samples_size >= dict_size * 100 {
+                break 'names;
+            }
+        }
+    }
+    info!(repo.log, "Training dictionary";
+          "samples" => samples.len(), "bytes" => samples_size);
+
+    let data = zstd::dict::from_samples(&samples, dict_size).map_err(|e| {
+        io::Error::new(e.kind(), format!("couldn't train a dictionary: {}", e))
+    })?;
+    let id = zstd::zstd_safe::get_dict_id_from_dict(&data)
+        .expect("trained dictionary has an id")
+        .get();
+    Ok((id, data))
+}
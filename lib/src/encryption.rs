@@ -5,8 +5,12 @@ use serde::{Deserialize, Serialize};
 
 use sgdata::SGData;
 
+use sodiumoxide::crypto::aead::xchacha20poly1305_ietf as aead;
+use sodiumoxide::crypto::kdf::blake2b as kdf;
+use sodiumoxide::randombytes;
+
 use crate::config;
-use crate::pwhash::PWHash;
+use crate::hashing::{self, ArcHasher};
 use crate::util::{as_base64, from_base64};
 use crate::PassphraseFn;
 use crate::{box_, pwhash, secretbox};
@@ -15,11 +19,18 @@ pub type ArcEncrypter = Arc<dyn Encrypter + Send + Sync>;
 pub type ArcDecrypter = Arc<dyn Decrypter + Send + Sync>;
 
 pub(crate) trait EncryptionEngine {
+    /// Seal the key again with `new_p`
+    ///
+    /// Changes the key slot named `slot`, or the one `old_p` unlocks. With
+    /// `new_pwhash`, the slot is sealed using it, as it's going to replace
+    /// `pwhash` in the config.
     fn change_passphrase(
         &mut self,
+        slot: Option<&str>,
         old_p: PassphraseFn<'_>,
         new_p: PassphraseFn<'_>,
         pwhash: &config::PWHash,
+        new_pwhash: Option<&config::PWHash>,
     ) -> io::Result<()>;
 
     fn encrypter(
@@ -37,10 +48,30 @@ pub(crate) trait EncryptionEngine {
 
 pub trait Encrypter {
     fn encrypt(&self, buf: SGData, digest: &[u8]) -> super::Result<SGData>;
+
+    /// Hasher of the chunk digests, if keyed with the secret key
+    fn keyed_hasher(&self) -> Option<ArcHasher> {
+        None
+    }
+
+    /// Decrypter of what's encrypted, if it holds the secret key
+    fn decrypter(&self) -> Option<ArcDecrypter> {
+        None
+    }
 }
 
 pub trait Decrypter {
     fn decrypt(&self, buf: SGData, digest: &[u8]) -> io::Result<SGData>;
+
+    /// Hasher of the chunk digests, if keyed with the secret key
+    fn keyed_hasher(&self) -> Option<ArcHasher> {
+        None
+    }
+
+    /// Encrypter using the same key, if the secret key encrypts too
+    fn encrypter(&self) -> Option<ArcEncrypter> {
+        None
+    }
 }
 
 pub struct NopEncrypter;
@@ -49,6 +80,10 @@ impl Encrypter for NopEncrypter {
     fn encrypt(&self, buf: SGData, _digest: &[u8]) -> io::Result<SGData> {
         Ok(buf)
     }
+
+    fn decrypter(&self) -> Option<ArcDecrypter> {
+        Some(Arc::new(NopDecrypter))
+    }
 }
 
 pub struct NopDecrypter;
@@ -59,40 +94,386 @@ impl Decrypter for NopDecrypter {
     }
 }
 
-/// Configuration of repository encryption
+/// Name of the key slot created along with the keys
+pub const DEFAULT_KEY_SLOT: &str = "default";
+
+/// Secret key sealed with a key derived from a passphrase
 #[derive(Serialize, Deserialize, Clone)]
-pub struct Curve25519 {
+pub struct SealedKey {
     #[serde(serialize_with = "as_base64", deserialize_with = "from_base64")]
     pub sealed_sec_key: Vec<u8>,
     #[serde(serialize_with = "as_base64", deserialize_with = "from_base64")]
-    pub pub_key: box_::PublicKey,
-    #[serde(serialize_with = "as_base64", deserialize_with = "from_base64")]
     pub nonce: secretbox::Nonce,
 }
 
-impl Curve25519 {
-    pub(crate) fn new(
+impl SealedKey {
+    fn seal(sec_key: &[u8], derived_key: &secretbox::Key) -> Self {
+        let nonce = secretbox::gen_nonce();
+        SealedKey {
+            sealed_sec_key: secretbox::seal(sec_key, &nonce, derived_key),
+            nonce,
+        }
+    }
+
+    fn open(&self, derived_key: &secretbox::Key) -> Option<Vec<u8>> {
+        secretbox::open(&self.sealed_sec_key, &self.nonce, derived_key).ok()
+    }
+}
+
+/// Named key slot: a copy of the secret key sealed with its own passphrase
+#[derive(Serialize, Deserialize, Clone)]
+pub struct KeySlot {
+    pub name: String,
+    #[serde(flatten)]
+    pub key: SealedKey,
+    /// Passphrase hashing of this slot, if other than the one of the config
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) pwhash: Option<config::PWHash>,
+}
+
+impl KeySlot {
+    fn pwhash<'a>(&'a self, default: &'a config::PWHash) -> &'a config::PWHash {
+        self.pwhash.as_ref().unwrap_or(default)
+    }
+}
+
+/// Copies of a secret key, any of which unlocks the repository
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(transparent)]
+pub struct KeySlots(pub Vec<KeySlot>);
+
+impl KeySlots {
+    /// Seal `sec_key` in the default slot
+    fn new(
+        sec_key: &[u8],
         passphrase_f: PassphraseFn<'_>,
-        pwhash: &dyn pwhash::PWHash,
-    ) -> super::Result<Self> {
-        let (pk, sk) = box_::gen_keypair();
+        pwhash: &config::PWHash,
+    ) -> io::Result<Self> {
+        let mut slots = KeySlots::default();
+        slots.push(DEFAULT_KEY_SLOT, sec_key, passphrase_f, pwhash)?;
+        Ok(slots)
+    }
+
+    /// Unseal the secret key, returning it along with the index of the slot
+    /// the passphrase opened
+    fn unseal(
+        &self,
+        passphrase_f: PassphraseFn<'_>,
+        pwhash: &config::PWHash,
+    ) -> io::Result<(Vec<u8>, usize)> {
+        if self.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "repository is encrypt-only: its config holds no secret key, \
+                 use a key file to decrypt",
+            ));
+        }
         let passphrase = passphrase_f()?;
 
-        let nonce = secretbox::gen_nonce();
+        // Derived once for all the slots using the config's hashing
+        let mut default_key = None;
+        for (i, slot) in self.0.iter().enumerate() {
+            let own_key;
+            let derived_key = match slot.pwhash {
+                Some(ref own) => {
+                    own_key = derive_key(&passphrase, own)?;
+                    &own_key
+                }
+                None => match default_key {
+                    Some(ref key) => key,
+                    None => {
+                        default_key.insert(derive_key(&passphrase, pwhash)?)
+                    }
+                },
+            };
+            if let Some(sec_key) = slot.key.open(derived_key) {
+                return Ok((sec_key, i));
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "can't decrypt key using given passphrase",
+        ))
+    }
+
+    fn index(&self, name: &str) -> io::Result<usize> {
+        self.0
+            .iter()
+            .position(|slot| slot.name == name)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("no key slot named {}", name),
+                )
+            })
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Names of the key slots
+    pub(crate) fn names(&self) -> Vec<String> {
+        self.0.iter().map(|slot| slot.name.clone()).collect()
+    }
+
+    fn ensure_absent(&self, name: &str) -> io::Result<()> {
+        if self.index(name).is_ok() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("key slot {} already exists", name),
+            ));
+        }
+        Ok(())
+    }
+
+    fn push(
+        &mut self,
+        name: &str,
+        sec_key: &[u8],
+        new_p: PassphraseFn<'_>,
+        pwhash: &config::PWHash,
+    ) -> io::Result<()> {
+        let derived_key = derive_key(&new_p()?, pwhash)?;
+        self.0.push(KeySlot {
+            name: name.into(),
+            key: SealedKey::seal(sec_key, &derived_key),
+            pwhash: None,
+        });
+        Ok(())
+    }
 
-        let sealed_sk = {
-            let derived_key = secretbox::Key::from_slice(
-                &pwhash.derive_key(&passphrase)?[..32],
+    /// Add a slot named `name`, sealed with `new_p`
+    ///
+    /// `p` must unlock any of the existing slots.
+    pub(crate) fn add(
+        &mut self,
+        name: &str,
+        p: PassphraseFn<'_>,
+        new_p: PassphraseFn<'_>,
+        pwhash: &config::PWHash,
+    ) -> io::Result<()> {
+        self.ensure_absent(name)?;
+        let (sec_key, _) = self.unseal(p, pwhash)?;
+        self.push(name, &sec_key, new_p, pwhash)
+    }
+
+    /// Remove the slot named `name`
+    ///
+    /// `p` must unlock any of the remaining slots, so the key can't be lost
+    /// this way.
+    pub(crate) fn remove(
+        &mut self,
+        name: &str,
+        p: PassphraseFn<'_>,
+        pwhash: &config::PWHash,
+    ) -> io::Result<()> {
+        let i = self.index(name)?;
+        let removed = self.0.remove(i);
+        if let Err(e) = self.unseal(p, pwhash) {
+            self.0.insert(i, removed);
+            return Err(if e.kind() == io::ErrorKind::InvalidData {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "passphrase must unlock one of the remaining key slots",
+                )
+            } else {
+                e
+            });
+        }
+        Ok(())
+    }
+
+    /// See `EncryptionEngine::change_passphrase`
+    fn change_passphrase(
+        &mut self,
+        slot: Option<&str>,
+        old_p: PassphraseFn<'_>,
+        new_p: PassphraseFn<'_>,
+        pwhash: &config::PWHash,
+        new_pwhash: Option<&config::PWHash>,
+    ) -> io::Result<()> {
+        let (sec_key, unlocked) = self.unseal(old_p, pwhash)?;
+        let i = match slot {
+            Some(name) => self.index(name)?,
+            None => unlocked,
+        };
+
+        let derived_key = match new_pwhash {
+            Some(new_pwhash) => {
+                // The other slots keep the hashing they were sealed with
+                for (j, slot) in self.0.iter_mut().enumerate() {
+                    if j != i && slot.pwhash.is_none() {
+                        slot.pwhash = Some(pwhash.clone());
+                    }
+                }
+                self.0[i].pwhash = None;
+                derive_key(&new_p()?, new_pwhash)?
+            }
+            None => derive_key(&new_p()?, self.0[i].pwhash(pwhash))?,
+        };
+        self.0[i].key = SealedKey::seal(&sec_key, &derived_key);
+
+        Ok(())
+    }
+}
+
+/// `Curve25519` as stored in the config
+///
+/// The default slot is stored the way it was before key slots were added
+/// (unless it has its own passphrase hashing), so the configs with only that
+/// slot stay readable by older versions.
+#[derive(Serialize, Deserialize)]
+struct Curve25519Config {
+    #[serde(flatten)]
+    default_slot: Option<SealedKey>,
+    #[serde(serialize_with = "as_base64", deserialize_with = "from_base64")]
+    pub_key: box_::PublicKey,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    slots: Vec<KeySlot>,
+}
+
+/// Secret key exported out of the config
+///
+/// Self-contained, so it unlocks the repository even if the config is lost
+/// or doesn't contain the secret key at all.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub(crate) enum KeyFile {
+    /// Unsealed, readable without any passphrase
+    #[serde(rename = "plain")]
+    Plain {
+        #[serde(
+            serialize_with = "as_base64",
+            deserialize_with = "from_base64"
+        )]
+        pub_key: box_::PublicKey,
+        #[serde(
+            serialize_with = "as_base64",
+            deserialize_with = "from_base64"
+        )]
+        sec_key: box_::SecretKey,
+    },
+    /// Sealed with a passphrase of its own
+    #[serde(rename = "sealed")]
+    Sealed {
+        #[serde(
+            serialize_with = "as_base64",
+            deserialize_with = "from_base64"
+        )]
+        pub_key: box_::PublicKey,
+        pwhash: config::PWHash,
+        #[serde(flatten)]
+        key: SealedKey,
+    },
+}
+
+impl KeyFile {
+    const HEADER: &'static str = "# rdedup secret key: anyone holding it (and \
+                                  its passphrase, if sealed) can read the \
+                                  repository\n";
+
+    pub(crate) fn parse(s: &str) -> io::Result<Self> {
+        serde_yaml::from_str(s).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("couldn't parse the key file: {}", e),
             )
-            .unwrap();
+        })
+    }
+
+    pub(crate) fn to_text(&self) -> io::Result<String> {
+        let yaml = serde_yaml::to_string(self)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok(format!("{}{}", Self::HEADER, yaml))
+    }
 
-            secretbox::seal(&sk.0, &nonce, &derived_key)
+    /// Unseal the secret key (asking for the passphrase, only if sealed)
+    fn sec_key(
+        &self,
+        passphrase_f: PassphraseFn<'_>,
+    ) -> io::Result<box_::SecretKey> {
+        let sec_key = match *self {
+            KeyFile::Plain { ref sec_key, .. } => sec_key.clone(),
+            KeyFile::Sealed {
+                ref pwhash,
+                ref key,
+                ..
+            } => key
+                .open(&derive_key(&passphrase_f()?, pwhash)?)
+                .and_then(|plain| box_::SecretKey::from_slice(&plain))
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "can't decrypt key file using given passphrase",
+                    )
+                })?,
         };
+        Ok(sec_key)
+    }
+}
+
+/// Configuration of repository encryption
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(from = "Curve25519Config", into = "Curve25519Config")]
+pub struct Curve25519 {
+    pub pub_key: box_::PublicKey,
+    /// Copies of the secret key, any of which unlocks the repository
+    pub slots: KeySlots,
+}
+
+impl From<Curve25519Config> for Curve25519 {
+    fn from(config: Curve25519Config) -> Self {
+        let mut slots = vec![];
+        if let Some(key) = config.default_slot {
+            slots.push(KeySlot {
+                name: DEFAULT_KEY_SLOT.into(),
+                key,
+                pwhash: None,
+            });
+        }
+        slots.extend(config.slots);
+        Curve25519 {
+            pub_key: config.pub_key,
+            slots: KeySlots(slots),
+        }
+    }
+}
+
+impl From<Curve25519> for Curve25519Config {
+    fn from(c: Curve25519) -> Self {
+        let (default_slot, slots): (Vec<_>, Vec<_>) =
+            c.slots.0.into_iter().partition(|slot| {
+                slot.name == DEFAULT_KEY_SLOT && slot.pwhash.is_none()
+            });
+        Curve25519Config {
+            default_slot: default_slot.into_iter().next().map(|slot| slot.key),
+            pub_key: c.pub_key,
+            slots,
+        }
+    }
+}
+
+fn derive_key(
+    passphrase: &str,
+    pwhash: &dyn pwhash::PWHash,
+) -> io::Result<secretbox::Key> {
+    Ok(
+        secretbox::Key::from_slice(&pwhash.derive_key(passphrase)?[..32])
+            .unwrap(),
+    )
+}
+
+impl Curve25519 {
+    pub(crate) fn new(
+        passphrase_f: PassphraseFn<'_>,
+        pwhash: &config::PWHash,
+    ) -> super::Result<Self> {
+        let (pk, sk) = box_::gen_keypair();
 
         Ok(Curve25519 {
-            sealed_sec_key: sealed_sk,
             pub_key: pk,
-            nonce,
+            slots: KeySlots::new(&sk.0, passphrase_f, pwhash)?,
         })
     }
 
@@ -101,21 +482,8 @@ impl Curve25519 {
         passphrase_f: &dyn Fn() -> io::Result<String>,
         pwhash: &config::PWHash,
     ) -> io::Result<box_::SecretKey> {
-        let passphrase = passphrase_f()?;
-
-        let derived_key =
-            secretbox::Key::from_slice(&pwhash.derive_key(&passphrase)?[..32])
-                .unwrap();
-        let plain_seckey =
-            secretbox::open(&self.sealed_sec_key, &self.nonce, &derived_key)
-                .map_err(|_| {
-                    io::Error::new(
-                        io::ErrorKind::InvalidData,
-                        "can't decrypt key using given passphrase",
-                    )
-                })?;
-
-        box_::SecretKey::from_slice(&plain_seckey).ok_or_else(|| {
+        let (sec_key, _) = self.slots.unseal(passphrase_f, pwhash)?;
+        box_::SecretKey::from_slice(&sec_key).ok_or_else(|| {
             io::Error::new(
                 io::ErrorKind::InvalidData,
                 "plain secret key in a wrong format",
@@ -126,30 +494,102 @@ impl Curve25519 {
     fn unseal_encrypt(&self) -> super::Result<box_::PublicKey> {
         Ok(self.pub_key)
     }
+
+    /// Whether there are no key slots, so the config can be used only to
+    /// encrypt
+    pub(crate) fn is_encrypt_only(&self) -> bool {
+        self.slots.is_empty()
+    }
+
+    /// Export the secret key, sealed with `seal_p` if given
+    ///
+    /// `p` must unlock any of the key slots.
+    pub(crate) fn export_key(
+        &self,
+        p: PassphraseFn<'_>,
+        seal_p: Option<PassphraseFn<'_>>,
+        pwhash: &config::PWHash,
+    ) -> io::Result<KeyFile> {
+        let sec_key = self.unseal_decrypt(p, pwhash)?;
+        Ok(match seal_p {
+            None => KeyFile::Plain {
+                pub_key: self.pub_key,
+                sec_key,
+            },
+            Some(seal_p) => KeyFile::Sealed {
+                pub_key: self.pub_key,
+                pwhash: pwhash.clone(),
+                key: SealedKey::seal(
+                    &sec_key.0,
+                    &derive_key(&seal_p()?, pwhash)?,
+                ),
+            },
+        })
+    }
+
+    /// Unseal the secret key of `keyfile`, checking it's the key of `self`
+    fn keyfile_sec_key(
+        &self,
+        keyfile: &KeyFile,
+        keyfile_p: PassphraseFn<'_>,
+    ) -> io::Result<box_::SecretKey> {
+        let sec_key = keyfile.sec_key(keyfile_p)?;
+        if sec_key.public_key() != self.pub_key {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "key file doesn't hold the key of this repository",
+            ));
+        }
+        Ok(sec_key)
+    }
+
+    /// Remove all the key slots, once `keyfile` is checked to hold the key
+    pub(crate) fn make_encrypt_only(
+        &mut self,
+        keyfile: &KeyFile,
+        keyfile_p: PassphraseFn<'_>,
+    ) -> io::Result<()> {
+        let _sec_key = self.keyfile_sec_key(keyfile, keyfile_p)?;
+        self.slots.0.clear();
+        Ok(())
+    }
+
+    pub(crate) fn keyfile_decrypter(
+        &self,
+        keyfile: &KeyFile,
+        keyfile_p: PassphraseFn<'_>,
+    ) -> io::Result<ArcDecrypter> {
+        let sec_key = self.keyfile_sec_key(keyfile, keyfile_p)?;
+        Ok(Arc::new(Curve25519Decrypter { sec_key }))
+    }
+
+    /// Add a slot named `name` holding the key of `keyfile`, sealed with
+    /// `new_p`
+    pub(crate) fn import_slot(
+        &mut self,
+        name: &str,
+        keyfile: &KeyFile,
+        keyfile_p: PassphraseFn<'_>,
+        new_p: PassphraseFn<'_>,
+        pwhash: &config::PWHash,
+    ) -> io::Result<()> {
+        self.slots.ensure_absent(name)?;
+        let sec_key = self.keyfile_sec_key(keyfile, keyfile_p)?;
+        self.slots.push(name, &sec_key.0, new_p, pwhash)
+    }
 }
 
 impl EncryptionEngine for Curve25519 {
     fn change_passphrase(
         &mut self,
+        slot: Option<&str>,
         old_p: PassphraseFn<'_>,
         new_p: PassphraseFn<'_>,
         pwhash: &config::PWHash,
+        new_pwhash: Option<&config::PWHash>,
     ) -> io::Result<()> {
-        let sec_key = self.unseal_decrypt(old_p, pwhash)?;
-
-        let new_passphrase = new_p()?;
-
-        let sealed_sk = {
-            let derived_key = secretbox::Key::from_slice(
-                &pwhash.derive_key(&new_passphrase)?[..32],
-            )
-            .unwrap();
-            secretbox::seal(&sec_key.0, &self.nonce, &derived_key)
-        };
-
-        self.sealed_sec_key = sealed_sk;
-
-        Ok(())
+        self.slots
+            .change_passphrase(slot, old_p, new_p, pwhash, new_pwhash)
     }
     fn encrypter(
         &self,
@@ -234,3 +674,173 @@ impl Decrypter for Curve25519Decrypter {
         ))
     }
 }
+
+/// Context of the subkeys derived from the master key of `XChaCha20Poly1305`
+const SUBKEY_CONTEXT: [u8; kdf::CONTEXTBYTES] = *b"rdedup__";
+const SUBKEY_ID_ENCRYPTION: u64 = 1;
+const SUBKEY_ID_HASHING: u64 = 2;
+const SUBKEY_ID_KEY_ID: u64 = 3;
+const KEY_ID_BYTES: usize = 16;
+
+fn derive_subkey(master_key: &kdf::Key, id: u64, len: usize) -> Vec<u8> {
+    let mut subkey = vec![0u8; len];
+    kdf::derive_from_key(&mut subkey, id, SUBKEY_CONTEXT, master_key)
+        .expect("subkey length out of range");
+    subkey
+}
+
+/// Configuration of the symmetric encryption
+///
+/// A single secret key, sealed in the key slots, both encrypts the chunks
+/// (XChaCha20-Poly1305) and keys the hashing of their digests (BLAKE2b), so
+/// nothing stored tells whether some known data is in the repository.
+/// Unlike with `Curve25519`, writing needs the passphrase too.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct XChaCha20Poly1305 {
+    /// Derived from the secret key, to tell whether two configs share it
+    #[serde(serialize_with = "as_base64", deserialize_with = "from_base64")]
+    pub key_id: Vec<u8>,
+    /// Copies of the secret key, any of which unlocks the repository
+    pub slots: KeySlots,
+}
+
+impl XChaCha20Poly1305 {
+    pub(crate) fn new(
+        passphrase_f: PassphraseFn<'_>,
+        pwhash: &config::PWHash,
+    ) -> io::Result<Self> {
+        let master_key = kdf::gen_key();
+
+        Ok(XChaCha20Poly1305 {
+            key_id: derive_subkey(&master_key, SUBKEY_ID_KEY_ID, KEY_ID_BYTES),
+            slots: KeySlots::new(&master_key.0, passphrase_f, pwhash)?,
+        })
+    }
+
+    fn unseal(
+        &self,
+        passphrase_f: PassphraseFn<'_>,
+        pwhash: &config::PWHash,
+    ) -> io::Result<XChaCha20Poly1305Cipher> {
+        let (master_key, _) = self.slots.unseal(passphrase_f, pwhash)?;
+        let master_key =
+            kdf::Key::from_slice(&master_key).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "plain secret key in a wrong format",
+                )
+            })?;
+
+        let key = aead::Key::from_slice(&derive_subkey(
+            &master_key,
+            SUBKEY_ID_ENCRYPTION,
+            aead::KEYBYTES,
+        ))
+        .expect("subkey of the key size");
+        let hash_key = derive_subkey(
+            &master_key,
+            SUBKEY_ID_HASHING,
+            hashing::Blake2bKeyed::KEY_BYTES,
+        );
+        Ok(XChaCha20Poly1305Cipher {
+            key,
+            hasher: Arc::new(hashing::Blake2bKeyed::new(&hash_key)),
+        })
+    }
+}
+
+impl EncryptionEngine for XChaCha20Poly1305 {
+    fn change_passphrase(
+        &mut self,
+        slot: Option<&str>,
+        old_p: PassphraseFn<'_>,
+        new_p: PassphraseFn<'_>,
+        pwhash: &config::PWHash,
+        new_pwhash: Option<&config::PWHash>,
+    ) -> io::Result<()> {
+        self.slots
+            .change_passphrase(slot, old_p, new_p, pwhash, new_pwhash)
+    }
+    fn encrypter(
+        &self,
+        pass: PassphraseFn<'_>,
+        pwhash: &config::PWHash,
+    ) -> io::Result<ArcEncrypter> {
+        Ok(Arc::new(self.unseal(pass, pwhash)?))
+    }
+    fn decrypter(
+        &self,
+        pass: PassphraseFn<'_>,
+        pwhash: &config::PWHash,
+    ) -> io::Result<ArcDecrypter> {
+        Ok(Arc::new(self.unseal(pass, pwhash)?))
+    }
+}
+
+/// Chunks are stored as a random nonce followed by the ciphertext, with the
+/// digest authenticated along, so a chunk can't be passed for another one
+#[derive(Clone)]
+struct XChaCha20Poly1305Cipher {
+    key: aead::Key,
+    hasher: ArcHasher,
+}
+
+impl Encrypter for XChaCha20Poly1305Cipher {
+    fn encrypt(&self, buf: SGData, digest: &[u8]) -> super::Result<SGData> {
+        let nonce = aead::Nonce::from_slice(&randombytes::randombytes(
+            aead::NONCEBYTES,
+        ))
+        .expect("Nonce::from_slice failed");
+        let cipher =
+            aead::seal(&buf.to_linear(), Some(digest), &nonce, &self.key);
+        Ok(SGData::from_many(vec![nonce.0.to_vec(), cipher]))
+    }
+
+    fn keyed_hasher(&self) -> Option<ArcHasher> {
+        Some(Arc::clone(&self.hasher))
+    }
+
+    fn decrypter(&self) -> Option<ArcDecrypter> {
+        Some(Arc::new(self.clone()))
+    }
+}
+
+impl Decrypter for XChaCha20Poly1305Cipher {
+    fn decrypt(&self, buf: SGData, digest: &[u8]) -> io::Result<SGData> {
+        let buf = buf.to_linear();
+
+        if buf.len() < aead::NONCEBYTES {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "chunk {} too short to even contain a nonce",
+                    hex::encode(digest)
+                ),
+            ));
+        }
+        let nonce = aead::Nonce::from_slice(&buf[..aead::NONCEBYTES]).unwrap();
+
+        Ok(SGData::from_single(
+            aead::open(
+                &buf[aead::NONCEBYTES..],
+                Some(digest),
+                &nonce,
+                &self.key,
+            )
+            .map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("can't decrypt chunk: {}", hex::encode(digest)),
+                )
+            })?,
+        ))
+    }
+
+    fn keyed_hasher(&self) -> Option<ArcHasher> {
+        Some(Arc::clone(&self.hasher))
+    }
+
+    fn encrypter(&self) -> Option<ArcEncrypter> {
+        Some(Arc::new(self.clone()))
+    }
+}
@@ -1,7 +1,7 @@
 use std::sync::Arc;
 
 use digest::consts::U32;
-use digest::Digest;
+use digest::{Digest, KeyInit, Mac};
 
 use crate::SGData;
 use crate::DIGEST_SIZE;
@@ -45,3 +45,33 @@ impl Hasher for Blake2b {
         vec_result
     }
 }
+
+/// BLAKE2b keyed with a secret, so the digests can't be computed without it
+pub struct Blake2bKeyed {
+    key: Vec<u8>,
+}
+
+impl Blake2bKeyed {
+    pub const KEY_BYTES: usize = 32;
+
+    pub fn new(key: &[u8]) -> Self {
+        Blake2bKeyed { key: key.to_vec() }
+    }
+}
+
+impl Hasher for Blake2bKeyed {
+    fn calculate_digest(&self, sg: &SGData) -> Vec<u8> {
+        let mut blake2: blake2::Blake2bMac<U32> =
+            KeyInit::new_from_slice(&self.key).expect("valid key length");
+
+        for sg_part in sg.as_parts() {
+            Mac::update(&mut blake2, sg_part);
+        }
+
+        let mut vec_result = vec![0u8; DIGEST_SIZE];
+        vec_result
+            .copy_from_slice(&blake2.finalize().into_bytes()[..DIGEST_SIZE]);
+
+        vec_result
+    }
+}
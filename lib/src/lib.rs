@@ -1,10 +1,10 @@
 // {{{ use and mod
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashSet};
 use std::io;
-use std::io::{Error, Read, Result, Write};
+use std::io::{Error, Read, Result, Seek, Write};
 use std::iter::Iterator;
 use std::path::{Path, PathBuf};
-use std::sync::{mpsc, Arc};
+use std::sync::{mpsc, Arc, Mutex};
 
 use sgdata::SGData;
 use slog::{info, o, warn, FnValue, Level, Logger};
@@ -54,6 +54,28 @@ use self::name::*;
 
 mod misc;
 use self::misc::*;
+
+mod prune;
+pub use self::prune::{PruneGroupBy, PrunePolicy, PruneResults};
+
+mod check;
+pub use self::check::{CheckIssue, CheckResults, RepairResults};
+
+mod sync;
+pub use self::sync::SyncResults;
+
+mod migrate;
+
+mod dict;
+
+#[cfg(unix)]
+mod snapshot;
+
+#[cfg(all(unix, feature = "fuse"))]
+mod mount;
+
+#[cfg(feature = "server")]
+pub mod server;
 // }}}
 
 // Fancy reexport of backends API and particular backends structs
@@ -72,18 +94,29 @@ pub mod backends {
         pub use crate::aio::local::{Local, LocalThread};
     }
 
+    pub mod lease {
+        pub use crate::aio::lease::{
+            LeaseLock, LeasePoison, DEFAULT_LEASE_DURATION,
+        };
+    }
+
     pub mod local_cache {
         pub use crate::aio::local_cache::{LocalCache, LocalCacheThread};
     }
 
     #[cfg(feature = "backend-http")]
     pub mod http {
-        pub use crate::aio::http::{HttpReadOnly, HttpReadOnlyThread};
+        pub use crate::aio::http::{Http, HttpThread};
+    }
+
+    #[cfg(feature = "backend-s3")]
+    pub mod s3 {
+        pub use crate::aio::s3::{S3Thread, S3};
     }
 
     #[cfg(feature = "backend-b2")]
     pub mod b2 {
-        pub use crate::aio::b2::{Auth, B2Thread, Lock, B2};
+        pub use crate::aio::b2::{Auth, B2Thread, B2};
     }
 }
 
@@ -92,6 +125,8 @@ type ArcEncrypter = Arc<dyn encryption::Encrypter + Send + Sync + 'static>;
 
 const INGRESS_BUFFER_SIZE: usize = 128 * 1024;
 const DIGEST_SIZE: usize = 32;
+/// Size of the chunk cache of a reader returned by `Repo::open_reader`
+const OPEN_READER_CACHE_SIZE: usize = 16 * 1024 * 1024;
 
 /// Type of user provided closure that will ask user for a passphrase if needed
 pub type PassphraseFn<'a> = &'a dyn Fn() -> io::Result<String>;
@@ -112,10 +147,6 @@ impl DataType {
     fn should_compress(&self) -> bool {
         *self == DataType::Data
     }
-
-    fn should_encrypt(&self) -> bool {
-        *self == DataType::Data
-    }
 }
 
 pub struct VerifyResults {
@@ -128,6 +159,53 @@ pub struct DuResults {
     pub bytes: u64,
 }
 
+/// User-provided metadata stored along with a name
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct NameMetadata {
+    /// Key/value tags (eg. host, job, source path, retention class)
+    pub tags: BTreeMap<String, String>,
+    /// Free-form description
+    pub description: Option<String>,
+}
+
+impl NameMetadata {
+    /// Whether all the `tags` are present; a tag without a value matches
+    /// any value
+    pub fn has_tags(&self, tags: &[(String, Option<String>)]) -> bool {
+        tags.iter()
+            .all(|(key, value)| match (self.tags.get(key), value) {
+                (Some(v), Some(value)) => v == value,
+                (found, None) => found.is_some(),
+                (None, Some(_)) => false,
+            })
+    }
+}
+
+/// Information about a stored name
+#[derive(Clone, Debug)]
+pub struct NameInfo {
+    pub name: String,
+    /// The UTC timestamp when the name was created
+    pub created: chrono::DateTime<chrono::Utc>,
+    /// Total size of the data (not recorded by older versions)
+    pub size: Option<u64>,
+    pub metadata: NameMetadata,
+}
+
+impl NameInfo {
+    fn new(name: String, stored: Name) -> Self {
+        NameInfo {
+            name,
+            created: stored.created,
+            size: stored.size,
+            metadata: NameMetadata {
+                tags: stored.tags,
+                description: stored.description,
+            },
+        }
+    }
+}
+
 /// A decryption handle
 ///
 /// Used as an argument to operations that decrypt data.
@@ -150,7 +228,12 @@ pub struct Repo {
     config: config::Repo,
 
     compression: compression::ArcCompression,
+    /// Zstd dictionaries loaded so far, see `dict`
+    dictionaries: compression::Dictionaries,
     hasher: hashing::ArcHasher,
+    /// Decrypter of the encrypted index chunks and name files, set by
+    /// `unlock_index`
+    index_decrypter: Option<ArcDecrypter>,
 
     /// Logger
     log: slog::Logger,
@@ -168,10 +251,35 @@ impl Repo {
             .config
             .encryption
             .decrypter(pass, &self.config.pwhash)?;
+        dict::load(self, Some(&decrypter))?;
+
+        Ok(DecryptHandle { decrypter })
+    }
+
+    /// Like `unlock_decrypt`, but using the secret key exported by
+    /// `export_key`, instead of the one stored in the config
+    ///
+    /// `keyfile` is the content of the key file; `pass` is asked for only if
+    /// it's sealed.
+    pub fn unlock_decrypt_with_keyfile(
+        &self,
+        keyfile: &str,
+        pass: PassphraseFn<'_>,
+    ) -> io::Result<DecryptHandle> {
+        info!(self.log, "Opening read handle with a key file");
+        let keyfile = encryption::KeyFile::parse(keyfile)?;
+        let decrypter =
+            self.config.encryption.keyfile_decrypter(&keyfile, pass)?;
+        dict::load(self, Some(&decrypter))?;
 
         Ok(DecryptHandle { decrypter })
     }
 
+    /// Open a handle for writing
+    ///
+    /// With a compression dictionary in use, which is encrypted like the
+    /// data, this needs the secret key too, so `pass` is asked for even
+    /// when the public key alone would do.
     pub fn unlock_encrypt(
         &self,
         pass: PassphraseFn<'_>,
@@ -181,6 +289,35 @@ impl Repo {
             .config
             .encryption
             .encrypter(pass, &self.config.pwhash)?;
+        dict::load(self, encrypter.decrypter().as_ref())?;
+        if let Some(id) = self.config.compression.dict() {
+            // Dictionaries are encrypted like the data, so with only the
+            // public key, the secret one has to be unlocked to compress
+            if !self.dictionaries.contains(id) {
+                info!(self.log, "Unlocking compression dictionary");
+                let decrypter = self
+                    .config
+                    .encryption
+                    .decrypter(pass, &self.config.pwhash)
+                    .map_err(|e| {
+                        io::Error::new(
+                            e.kind(),
+                            format!(
+                                "compression dictionary {:08x} can't be \
+                                 read without the secret key: {}",
+                                id, e
+                            ),
+                        )
+                    })?;
+                dict::load(self, Some(&decrypter))?;
+            }
+            if !self.dictionaries.contains(id) {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("compression dictionary {:08x} not found", id),
+                ));
+            }
+        }
 
         Ok(EncryptHandle { encrypter })
     }
@@ -244,14 +381,17 @@ impl Repo {
         let config = config::Repo::new_from_settings(passphrase, settings)?;
         config.write(&aio)?;
 
-        let compression = config.compression.to_engine();
+        let dictionaries = compression::Dictionaries::default();
+        let compression = config.compression_engine(&dictionaries);
         let hasher = config.hashing.to_hasher();
 
         Ok(Repo {
             backend_select,
             config,
             compression,
+            dictionaries,
             hasher,
+            index_decrypter: None,
             log,
             aio,
         })
@@ -259,6 +399,24 @@ impl Repo {
 
     /// Open an existing repository
     pub fn open<L>(backend_select: Arc<BackendSelectFn>, log: L) -> Result<Repo>
+    where
+        L: Into<Option<Logger>>,
+    {
+        let repo = Repo::open_unchecked(backend_select, log)?;
+        if repo.config.migration.is_some() {
+            return Err(Error::new(
+                io::ErrorKind::InvalidData,
+                "repository migration not finished; run it again to resume",
+            ));
+        }
+        Ok(repo)
+    }
+
+    /// Open a repository, even if it's being migrated
+    fn open_unchecked<L>(
+        backend_select: Arc<BackendSelectFn>,
+        log: L,
+    ) -> Result<Repo>
     where
         L: Into<Option<Logger>>,
     {
@@ -271,23 +429,190 @@ impl Repo {
 
         let config = config::Repo::read(&aio)?;
 
-        let compression = config.compression.to_engine();
+        let dictionaries = compression::Dictionaries::default();
+        let compression = config.compression_engine(&dictionaries);
         let hasher = config.hashing.to_hasher();
         Ok(Repo {
             backend_select,
             config,
             compression,
+            dictionaries,
             hasher,
+            index_decrypter: None,
             log,
             aio,
         })
     }
 
-    /// Change the passphrase
-    pub fn change_passphrase(
+    /// Migrate the repository to other compression, encryption or nesting
+    ///
+    /// Every chunk is rewritten in place with the new settings. New keys,
+    /// protected by `new_passphrase`, are generated only when enabling
+    /// encryption; `passphrase` unlocks the current ones. The repository
+    /// is locked exclusively for the whole time. If a previous migration
+    /// was interrupted, it's resumed instead, ignoring `migration`; until
+    /// then the repository can't be opened. Repositories of older versions
+    /// are upgraded to the current chunk format, even with no new settings.
+    pub fn migrate<L>(
+        backend_select: Arc<BackendSelectFn>,
+        passphrase: PassphraseFn<'_>,
+        new_passphrase: PassphraseFn<'_>,
+        migration: &settings::Migration,
+        log: L,
+    ) -> Result<Repo>
+    where
+        L: Into<Option<Logger>>,
+    {
+        let mut repo = Repo::open_unchecked(backend_select, log)?;
+        let _lock = repo.aio.lock_exclusive()?;
+        // Could have been started in the meantime
+        repo.config = config::Repo::read(&repo.aio)?;
+
+        let state = match repo.config.migration.clone() {
+            Some(state) => {
+                info!(repo.log, "Resuming interrupted migration");
+                state
+            }
+            None => match migrate::start(&repo, migration, new_passphrase)? {
+                Some(state) => state,
+                None => {
+                    info!(repo.log, "Nothing to migrate");
+                    return Ok(repo);
+                }
+            },
+        };
+        let decrypter = repo
+            .config
+            .encryption
+            .decrypter(passphrase, &repo.config.pwhash)?;
+        dict::load(&repo, Some(&decrypter))?;
+        migrate::run(&repo, &decrypter, &state)
+    }
+
+    /// Train a zstd dictionary from the stored data, and compress the new
+    /// data chunks against it
+    ///
+    /// Improves the compression of small chunks a lot. The dictionary, of at
+    /// most `dict_size` bytes, is stored encrypted along the config; chunks
+    /// compressed against previous ones stay readable. Requires zstd or
+    /// `auto` compression. Returns the id of the dictionary.
+    #[cfg(feature = "with-zstd")]
+    pub fn train_dictionary(
         &mut self,
-        old_p: PassphraseFn<'_>,
-        new_p: PassphraseFn<'_>,
+        dec: &DecryptHandle,
+        dict_size: usize,
+    ) -> Result<u32> {
+        if !matches!(
+            self.config.compression,
+            config::Compression::Zstd(_) | config::Compression::Auto(_)
+        ) {
+            return Err(Error::new(
+                io::ErrorKind::InvalidInput,
+                "dictionaries require zstd or auto compression",
+            ));
+        }
+        let (id, data) = {
+            let _lock = self.aio.lock_shared();
+            dict::train(self, &dec.decrypter, dict_size)?
+        };
+        let encrypter = self.encrypter_from(Some(&dec.decrypter))?;
+        dict::write(self, &encrypter, id, &data)?;
+        info!(self.log, "Stored dictionary"; "id" => format!("{:08x}", id));
+        self.dictionaries.insert(id, data);
+
+        self.update_config(|config| {
+            config.compression = config.compression.with_dict(id);
+            Ok(())
+        })?;
+        self.compression = self.config.compression_engine(&self.dictionaries);
+        Ok(id)
+    }
+
+    /// A handle to the same repository, using `config` instead
+    fn with_config(&self, config: config::Repo) -> Repo {
+        Repo {
+            compression: config.compression_engine(&self.dictionaries),
+            hasher: config.hashing.to_hasher(),
+            config,
+            ..self.clone()
+        }
+    }
+
+    /// Whether the index chunks and name files are encrypted
+    pub fn is_index_encrypted(&self) -> bool {
+        self.config.encrypt_index
+    }
+
+    /// A handle to the same repository, reading its encrypted index chunks
+    /// and name files with `dec`
+    ///
+    /// Needed by the operations not taking a `DecryptHandle` (eg. `gc`,
+    /// `name_info`, `prune`, `sync_to`), if the index is encrypted.
+    pub fn unlock_index(&self, dec: &DecryptHandle) -> Repo {
+        Repo {
+            index_decrypter: Some(Arc::clone(&dec.decrypter)),
+            ..self.clone()
+        }
+    }
+
+    /// Decrypter of the index chunks and name files, if they're encrypted
+    ///
+    /// `dec` is used if given, else the one set by `unlock_index`.
+    pub(crate) fn index_decrypter(
+        &self,
+        dec: Option<&ArcDecrypter>,
+    ) -> io::Result<Option<ArcDecrypter>> {
+        if !self.config.encrypt_index {
+            return Ok(None);
+        }
+        match dec.or(self.index_decrypter.as_ref()) {
+            Some(decrypter) => Ok(Some(Arc::clone(decrypter))),
+            None => Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "index is encrypted, the repository must be unlocked",
+            )),
+        }
+    }
+
+    /// Encrypter of this repository, got without asking for the passphrase
+    ///
+    /// The secret key of symmetric encryption is taken from `decrypter`,
+    /// which must hold the one of this repository.
+    pub(crate) fn encrypter_from(
+        &self,
+        decrypter: Option<&ArcDecrypter>,
+    ) -> io::Result<ArcEncrypter> {
+        match decrypter.and_then(|decrypter| decrypter.encrypter()) {
+            Some(encrypter) => Ok(encrypter),
+            None => self.config.encryption.encrypter(
+                &|| Err(io::Error::other("passphrase not expected")),
+                &self.config.pwhash,
+            ),
+        }
+    }
+
+    fn should_encrypt(&self, data_type: DataType) -> bool {
+        data_type == DataType::Data || self.config.encrypt_index
+    }
+
+    /// Hasher of the chunk digests
+    ///
+    /// `None` if they are keyed, and `decrypter` (holding the key) is missing.
+    pub(crate) fn chunk_hasher(
+        &self,
+        decrypter: Option<&ArcDecrypter>,
+    ) -> Option<hashing::ArcHasher> {
+        match decrypter.and_then(|decrypter| decrypter.keyed_hasher()) {
+            Some(hasher) => Some(hasher),
+            None if self.config.encryption.keyed_hashing() => None,
+            None => Some(Arc::clone(&self.hasher)),
+        }
+    }
+
+    /// Change the config under an exclusive lock, and write it
+    fn update_config(
+        &mut self,
+        f: impl FnOnce(&mut config::Repo) -> io::Result<()>,
     ) -> Result<()> {
         let _lock = self.aio.lock_exclusive();
 
@@ -297,24 +622,192 @@ impl Repo {
                 "rdedup v0 config format not supported",
             ))
         } else {
-            self.config.encryption.change_passphrase(
+            f(&mut self.config)?;
+            self.config.write(&self.aio)?;
+            Ok(())
+        }
+    }
+
+    /// Change the passphrase
+    ///
+    /// The key slot unlocked by `old_p` is changed.
+    pub fn change_passphrase(
+        &mut self,
+        old_p: PassphraseFn<'_>,
+        new_p: PassphraseFn<'_>,
+    ) -> Result<()> {
+        self.update_config(|config| {
+            config.encryption.change_passphrase(
+                None,
                 old_p,
                 new_p,
-                &self.config.pwhash,
+                &config.pwhash,
+                None,
+            )
+        })
+    }
+
+    /// Change the passphrase, and the way it's hashed into a key
+    ///
+    /// Upgrades the passphrase hashing (eg. to `argon2id`) without touching
+    /// any data. The key slot named `slot`, or the one unlocked by `old_p`,
+    /// is changed, and the new hashing becomes the default for the slots
+    /// added later. The other slots keep their hashing, until their
+    /// passphrases get changed this way too.
+    pub fn change_passphrase_with_pwhash(
+        &mut self,
+        slot: Option<&str>,
+        old_p: PassphraseFn<'_>,
+        new_p: PassphraseFn<'_>,
+        pwhash: &settings::PWHashSettings,
+    ) -> Result<()> {
+        let new_pwhash = config::PWHash::from_settings(pwhash);
+        self.update_config(|config| {
+            config.encryption.change_passphrase(
+                slot,
+                old_p,
+                new_p,
+                &config.pwhash,
+                Some(&new_pwhash),
             )?;
-            self.config.write(&self.aio)?;
+            config.pwhash = new_pwhash;
             Ok(())
-        }
+        })
+    }
+
+    /// Change the passphrase of the key slot named `slot`
+    ///
+    /// `old_p` can unlock any of the key slots.
+    pub fn change_key_slot_passphrase(
+        &mut self,
+        slot: &str,
+        old_p: PassphraseFn<'_>,
+        new_p: PassphraseFn<'_>,
+    ) -> Result<()> {
+        self.update_config(|config| {
+            config.encryption.change_passphrase(
+                Some(slot),
+                old_p,
+                new_p,
+                &config.pwhash,
+                None,
+            )
+        })
+    }
+
+    /// Names of the key slots, each holding a copy of the secret key sealed
+    /// with its own passphrase
+    ///
+    /// The slot created by `init` is named `default`. Empty if the
+    /// repository is not encrypted.
+    pub fn list_key_slots(&self) -> Vec<String> {
+        self.config.encryption.key_slots()
+    }
+
+    /// Add a key slot named `slot`, unlocked by `new_p`
+    ///
+    /// `p` must unlock any of the existing key slots.
+    pub fn add_key_slot(
+        &mut self,
+        slot: &str,
+        p: PassphraseFn<'_>,
+        new_p: PassphraseFn<'_>,
+    ) -> Result<()> {
+        self.update_config(|config| {
+            config
+                .encryption
+                .add_key_slot(slot, p, new_p, &config.pwhash)
+        })
+    }
+
+    /// Remove the key slot named `slot`
+    ///
+    /// `p` must unlock any of the remaining key slots.
+    pub fn remove_key_slot(
+        &mut self,
+        slot: &str,
+        p: PassphraseFn<'_>,
+    ) -> Result<()> {
+        self.update_config(|config| {
+            config.encryption.remove_key_slot(slot, p, &config.pwhash)
+        })
+    }
+
+    /// Whether the config holds no secret key, so the repository can only be
+    /// written to, and read with a key file
+    pub fn is_encrypt_only(&self) -> bool {
+        self.config.encryption.is_encrypt_only()
+    }
+
+    /// Remove all the key slots from the config, making the repository
+    /// encrypt-only
+    ///
+    /// Backup clients can then write to the repository, without having
+    /// anything to unlock it with. `keyfile`, exported by `export_key`, must
+    /// hold the secret key; it's the only one left. Use it to read the data,
+    /// or to `import_key` into the config again. `keyfile_p` is asked for
+    /// only if the key file is sealed.
+    pub fn make_encrypt_only(
+        &mut self,
+        keyfile: &str,
+        keyfile_p: PassphraseFn<'_>,
+    ) -> Result<()> {
+        let keyfile = encryption::KeyFile::parse(keyfile)?;
+        self.update_config(|config| {
+            config.encryption.make_encrypt_only(&keyfile, keyfile_p)
+        })
+    }
+
+    /// Export the secret key as a key file, to be kept apart from the
+    /// repository
+    ///
+    /// `p` must unlock any of the key slots. The key is sealed with `seal_p`,
+    /// if given, or written out unprotected otherwise.
+    pub fn export_key(
+        &self,
+        p: PassphraseFn<'_>,
+        seal_p: Option<PassphraseFn<'_>>,
+    ) -> Result<String> {
+        self.config
+            .encryption
+            .export_key(p, seal_p, &self.config.pwhash)?
+            .to_text()
+    }
+
+    /// Add a key slot named `slot`, unlocked by `new_p`, from a key file
+    /// exported by `export_key`
+    ///
+    /// Restores access when all the passphrases are lost. `keyfile_p` is
+    /// asked for only if the key file is sealed.
+    pub fn import_key(
+        &mut self,
+        slot: &str,
+        keyfile: &str,
+        keyfile_p: PassphraseFn<'_>,
+        new_p: PassphraseFn<'_>,
+    ) -> Result<()> {
+        let keyfile = encryption::KeyFile::parse(keyfile)?;
+        self.update_config(|config| {
+            config.encryption.import_key_slot(
+                slot,
+                &keyfile,
+                keyfile_p,
+                new_p,
+                &config.pwhash,
+            )
+        })
     }
 
     /// Write a chunk of data to the repo.
+    ///
+    /// Returns the address of the data and its total size.
     fn chunk_and_write_data_thread<'a>(
         &'a self,
         input_data_iter: Box<dyn Iterator<Item = Vec<u8>> + Send + 'a>,
         process_tx: crossbeam_channel::Sender<chunk_processor::Message>,
         aio: aio::AsyncIO,
         data_type: DataType,
-    ) -> io::Result<DataAddress> {
+    ) -> io::Result<(DataAddress, u64)> {
         // Note: This channel is intentionally unbounded
         // The processing loop runs in sort of a loop (actually more of a
         // recursive spiral). Unless this channel is unbounded it's possible
@@ -344,10 +837,24 @@ impl Repo {
                         Level::Debug,
                     );
 
-                    let chunker = chunking::Chunker::new(
-                        input_data_iter,
-                        self.config.chunking.to_engine(),
-                    );
+                    let engine = self.config.chunking.to_engine();
+                    let chunker: Box<dyn Iterator<Item = (SGData, u64)>> =
+                        match data_type {
+                            DataType::Data => Box::new(
+                                chunking::Chunker::new(input_data_iter, engine)
+                                    .map(|sg| {
+                                        let len = sg.len() as u64;
+                                        (sg, len)
+                                    }),
+                            ),
+                            DataType::Index => {
+                                Box::new(chunking::IndexChunker::new(
+                                    input_data_iter,
+                                    engine,
+                                    self.config.index_format(),
+                                ))
+                            }
+                        };
 
                     let mut data = EnumerateU64::new(chunker);
 
@@ -355,10 +862,11 @@ impl Repo {
                         timer.start_with("rx-and-chunking", || data.next())
                     {
                         timer.start("tx");
-                        let (i, sg) = i_sg;
+                        let (i, (sg, data_size)) = i_sg;
                         process_tx
                             .send(Message {
                                 data: (i, sg),
+                                data_size,
                                 response_tx: digests_tx.clone(),
                                 data_type,
                             })
@@ -379,25 +887,30 @@ impl Repo {
                 timer.start_with("digest-rx", || digests_rx.next())
             {
                 let mut two_first = vec![first_digest, second_digest];
-                let mut address = self.chunk_and_write_data_thread(
-                    Box::new(
-                        two_first
-                            .drain(..)
-                            .chain(digests_rx)
-                            .map(|digest| digest.0),
-                    ),
+                let (mut address, size) = self.chunk_and_write_data_thread(
+                    Box::new(two_first.drain(..).chain(digests_rx).map(
+                        |(digest, size)| {
+                            IndexFormat::sized_entry(&digest, size)
+                        },
+                    )),
                     process_tx,
                     aio.clone(),
                     DataType::Index,
                 )?;
 
                 address.index_level += 1;
-                Ok(address)
+                address.index_format = self.config.index_format();
+                Ok((address, size))
             } else {
-                Ok(DataAddress {
-                    index_level: 0,
-                    digest: first_digest,
-                })
+                let (digest, size) = first_digest;
+                Ok((
+                    DataAddress {
+                        index_level: 0,
+                        digest,
+                        index_format: self.config.index_format(),
+                    },
+                    size,
+                ))
             }
         })
         .expect("chunker thread failed")
@@ -409,11 +922,20 @@ impl Repo {
         num_cpus::get()
     }
 
+    /// Number of threads fetching and decoding chunks in parallel on read
+    ///
+    /// Most of the time they wait for the backend, so there are as many of
+    /// them as `AsyncIO` threads, to keep all of those busy.
+    fn read_thread_num(&self) -> usize {
+        4 * num_cpus::get()
+    }
+
     fn input_reader_thread<R>(
         &self,
         reader: R,
         chunker_tx: mpsc::SyncSender<Vec<u8>>,
-    ) where
+    ) -> io::Result<()>
+    where
         R: Read + Send,
     {
         let mut time = TimeReporter::new_with_level(
@@ -430,8 +952,9 @@ impl Repo {
             chunker_tx.send(buf).expect("chunker tx channel closed")
         }
 
-        if let Some(e) = while_ok.finish() {
-            panic!("Input thread error: {}", e)
+        match while_ok.finish() {
+            Some(e) => Err(e),
+            None => Ok(()),
         }
     }
 
@@ -495,7 +1018,11 @@ impl Repo {
             "Reclaiming old generation finished. Deleting...";
             "gen" => FnValue(|_| gen.to_string()),
         );
+        self.wipe_generation(gen)
+    }
 
+    /// Remove generation `gen` with all its content
+    fn wipe_generation(&self, gen: Generation) -> io::Result<()> {
         // Make sure chunks are successfully removed before
         // attempting to delete the generation dir itself
         // so that we don't leave garbage with no Generation
@@ -530,6 +1057,7 @@ impl Repo {
         name_str: &str,
         cur_gen: Generation,
         generations: &[Generation],
+        index_decrypter: Option<&ArcDecrypter>,
     ) -> io::Result<()> {
         // traverse all the chunks (both index and data)
         // and move all the chunks to the newest gen
@@ -539,11 +1067,17 @@ impl Repo {
             "name" => name_str,
             "gen" => FnValue(|_| cur_gen.to_string())
         );
-        let name = Name::load_from_any(name_str, generations, &self.aio)?;
+        let name = Name::load_from_any(
+            name_str,
+            generations,
+            &self.aio,
+            index_decrypter,
+        )?;
         let data_address: DataAddress = name.into();
 
         let accessor = GenerationUpdateChunkAccessor::new(
             self,
+            index_decrypter.cloned(),
             Arc::clone(&self.compression),
             generations.to_vec(),
         );
@@ -572,7 +1106,7 @@ impl Repo {
 
         let accessor = self.get_recording_chunk_accessor(
             reachable_digests,
-            None,
+            self.index_decrypter(None)?,
             Arc::clone(&self.compression),
             generations,
         );
@@ -591,8 +1125,14 @@ impl Repo {
         let generations = self.read_generations()?;
         let mut reachable_digests = HashSet::new();
         let all_names = Name::list_all(&generations, &self.aio)?;
+        let index_decrypter = self.index_decrypter(None)?;
         for name_str in &all_names {
-            match Name::load_from_any(name_str, &generations, &self.aio) {
+            match Name::load_from_any(
+                name_str,
+                &generations,
+                &self.aio,
+                index_decrypter.as_ref(),
+            ) {
                 Ok(name) => {
                     let data_address: DataAddress = name.into();
                     info!(self.log, "processing"; "name" => name_str);
@@ -632,15 +1172,89 @@ impl Repo {
         Name::list_all(&self.read_generations()?, &self.aio)
     }
 
+    /// Get information about a stored name, including its metadata
+    pub fn name_info(&self, name_str: &str) -> Result<NameInfo> {
+        let _lock = self.aio.lock_shared();
+        let generations = self.read_generations()?;
+        let name = Name::load_from_any(
+            name_str,
+            &generations,
+            &self.aio,
+            self.index_decrypter(None)?.as_ref(),
+        )?;
+        Ok(NameInfo::new(name_str.to_owned(), name))
+    }
+
+    /// Get information about the stored names having all the `tags` (see
+    /// `NameMetadata::has_tags`), sorted by name
+    pub fn list_names_info(
+        &self,
+        tags: &[(String, Option<String>)],
+    ) -> Result<Vec<NameInfo>> {
+        let _lock = self.aio.lock_shared();
+        let mut infos = self.names_info(&self.read_generations()?)?;
+        infos.retain(|info| info.metadata.has_tags(tags));
+        Ok(infos)
+    }
+
+    fn names_info(&self, generations: &[Generation]) -> Result<Vec<NameInfo>> {
+        let mut names = Name::list_all(generations, &self.aio)?;
+        names.sort();
+        names.dedup();
+
+        let index_decrypter = self.index_decrypter(None)?;
+        let mut infos = Vec::with_capacity(names.len());
+        for name_str in names {
+            match Name::load_from_any(
+                &name_str,
+                generations,
+                &self.aio,
+                index_decrypter.as_ref(),
+            ) {
+                Ok(name) => infos.push(NameInfo::new(name_str, name)),
+                // removed in the meantime
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(infos)
+    }
+
+    /// Select the names that `prune` would remove, without removing them
+    pub fn plan_prune(&self, policy: &PrunePolicy) -> Result<PruneResults> {
+        let _lock = self.aio.lock_shared();
+        prune::select(self.names_info(&self.read_generations()?)?, policy)
+    }
+
+    /// Remove the names not kept by the retention `policy`
+    ///
+    /// The data of the removed names stays in the repository until `gc`.
+    pub fn prune(&self, policy: &PrunePolicy) -> Result<PruneResults> {
+        let _lock = self.aio.lock_exclusive();
+        let generations = self.read_generations()?;
+        let results = prune::select(self.names_info(&generations)?, policy)?;
+        for name_str in &results.remove {
+            info!(self.log, "Removing name"; "name" => name_str);
+            Name::remove_any(name_str, &generations, &self.aio)?;
+        }
+        Ok(results)
+    }
+
     /// Remove a stored name from repo
     pub fn rm(&self, name: &str) -> Result<()> {
         let _lock = self.aio.lock_exclusive();
         Name::remove_any(name, &self.read_generations()?, &self.aio)
     }
 
+    /// Remove the chunks no name reaches, that were not accessed in
+    /// `min_age_secs` seconds
+    ///
+    /// With encrypted index, the repository must be unlocked (see
+    /// `unlock_index`).
     pub fn gc(&self, min_age_secs: u64) -> Result<()> {
         let _lock = self.aio.lock_exclusive();
 
+        let index_decrypter = self.index_decrypter(None)?;
         let generations = self.read_generations()?;
 
         if generations.is_empty() {
@@ -686,10 +1300,20 @@ impl Repo {
                 self.wipe_generation_maybe(gen_oldest, min_age_secs)?;
                 return Ok(());
             }
-            self.update_name_to(&names[0], *gen_cur, &generations)?;
+            self.update_name_to(
+                &names[0],
+                *gen_cur,
+                &generations,
+                index_decrypter.as_ref(),
+            )?;
         }
     }
 
+    /// Read the data stored under `name_str` into `writer`
+    ///
+    /// Every chunk is verified before it's written out, so on a corrupted
+    /// or missing chunk, the read fails with `writer` holding only the data
+    /// preceding it.
     pub fn read<W: Write>(
         &self,
         name_str: &str,
@@ -700,7 +1324,12 @@ impl Repo {
 
         let generations = self.read_generations()?;
 
-        let name = Name::load_from_any(name_str, &generations, &self.aio)?;
+        let name = Name::load_from_any(
+            name_str,
+            &generations,
+            &self.aio,
+            self.index_decrypter(Some(&dec.decrypter))?.as_ref(),
+        )?;
         let data_address: DataAddress = name.into();
 
         let accessor = self.get_chunk_accessor(
@@ -708,20 +1337,80 @@ impl Repo {
             Arc::clone(&self.compression),
             generations,
         );
-        let traverser = ReadContext::new(&accessor);
-        traverser.read_recursively(ReadRequest::new(
-            DataType::Data,
+        let workers = self.read_thread_num();
+        read_pipelined(
+            &accessor,
             data_address.as_ref(),
-            Some(writer),
+            writer,
+            workers,
+            2 * workers,
             self.log.clone(),
-        ))
+        )
+    }
+
+    /// Open `name_str` for random-access reading
+    ///
+    /// Only the chunks holding the data being read are fetched and
+    /// decrypted. Names stored without chunk sizes in the index, by older
+    /// versions of rdedup or in repositories older than version 5, can't be
+    /// opened this way.
+    pub fn open_reader(
+        &self,
+        name_str: &str,
+        dec: &DecryptHandle,
+    ) -> Result<impl Read + Seek + '_> {
+        let lock = self.aio.lock_shared().ok();
+
+        let generations = self.read_generations()?;
+
+        let name = Name::load_from_any(
+            name_str,
+            &generations,
+            &self.aio,
+            self.index_decrypter(Some(&dec.decrypter))?.as_ref(),
+        )?;
+        let size = name.size;
+        let data_address: DataAddress = name.into();
+
+        let accessor = CachingChunkAccessor::new(
+            self.clone(),
+            Some(Arc::clone(&dec.decrypter)),
+            Arc::clone(&self.compression),
+            generations,
+            Arc::new(ChunkCache::new(OPEN_READER_CACHE_SIZE)),
+        );
+        SeekableReader::new(accessor, data_address, size, lock)
+    }
+
+    /// Mount the repository as a read-only filesystem at `mountpoint`
+    ///
+    /// Every name is presented as a file. Up to `cache_size` bytes of
+    /// decrypted chunks are kept in memory. Blocks until unmounted.
+    #[cfg(all(unix, feature = "fuse"))]
+    pub fn mount(
+        &self,
+        mountpoint: &Path,
+        dec: &DecryptHandle,
+        cache_size: usize,
+    ) -> Result<()> {
+        let fs = mount::RepoFs::new(
+            self.clone(),
+            Arc::clone(&dec.decrypter),
+            cache_size,
+        )?;
+        fuser::mount2(fs, mountpoint, &mount::mount_options())
     }
 
     pub fn du(&self, name_str: &str, dec: &DecryptHandle) -> Result<DuResults> {
         let _lock = self.aio.lock_shared();
 
         let generations = self.read_generations()?;
-        let name = Name::load_from_any(name_str, &generations, &self.aio)?;
+        let name = Name::load_from_any(
+            name_str,
+            &generations,
+            &self.aio,
+            self.index_decrypter(Some(&dec.decrypter))?.as_ref(),
+        )?;
         let data_address: DataAddress = name.into();
 
         let mut counter = CounterWriter::new();
@@ -755,7 +1444,12 @@ impl Repo {
 
         let generations = self.read_generations()?;
 
-        let name = Name::load_from_any(name_str, &generations, &self.aio)?;
+        let name = Name::load_from_any(
+            name_str,
+            &generations,
+            &self.aio,
+            self.index_decrypter(Some(&dec.decrypter))?.as_ref(),
+        )?;
         let data_address: DataAddress = name.into();
 
         let mut counter = CounterWriter::new();
@@ -777,46 +1471,163 @@ impl Repo {
         Ok(accessor.get_results())
     }
 
+    /// Check the integrity of the whole repository
+    ///
+    /// Every stored chunk is read and verified against its digest, and all
+    /// the names are checked to reference only stored chunks. Unlike
+    /// `verify`, this also finds damage not reachable from any name, and
+    /// names that can't be read at all.
+    pub fn check(&self, dec: &DecryptHandle) -> Result<CheckResults> {
+        let _lock = self.aio.lock_shared();
+        check::check(self, &dec.decrypter, self.read_thread_num())
+    }
+
+    /// Replace damaged or missing chunks with copies from `source`
+    ///
+    /// `source` must have the same configuration and keys as this
+    /// repository, eg. be a copy of it. Every chunk is verified before being
+    /// written to the current generation. The damage is found by `check`, or
+    /// by `verify` of `names` if any are given.
+    ///
+    /// A damaged index chunk hides the damage of the chunks below it, so
+    /// this repeats as long as new damaged chunks are found. Chunks that
+    /// couldn't be replaced are not retried.
+    pub fn repair_from(
+        &self,
+        source: &Repo,
+        names: &[String],
+        dec: &DecryptHandle,
+    ) -> Result<RepairResults> {
+        let mut attempted = HashSet::new();
+        let mut results = RepairResults::default();
+        loop {
+            let mut damaged = vec![];
+            if names.is_empty() {
+                damaged = self.check(dec)?.damaged_chunks();
+            } else {
+                for name in names {
+                    damaged.extend(
+                        self.verify(name, dec)?
+                            .errors
+                            .into_iter()
+                            .map(|(digest, _)| digest),
+                    );
+                }
+            }
+            damaged.retain(|digest| attempted.insert(digest.clone()));
+            if damaged.is_empty() {
+                return Ok(results);
+            }
+
+            let _lock = self.aio.lock_shared();
+            let _source_lock = source.aio.lock_shared();
+            let pass = check::repair(self, source, &dec.decrypter, &damaged)?;
+            results.repaired.extend(pass.repaired);
+            results.errors.extend(pass.errors);
+        }
+    }
+
+    /// Copy `names` (all of them, if empty) to the repository `other`
+    ///
+    /// Only the chunks reachable from the names and not yet stored in
+    /// `other` are copied. Chunks are copied verbatim, so `other` must use
+    /// the same keys, hashing and chunk format (and compression, with
+    /// repositories older than version 4), eg. be initialized from a copy of
+    /// this repository's config (see `sync_to_reencoding` otherwise). Names
+    /// already existing in `other` are skipped. With encrypted index, this
+    /// repository must be unlocked (see `unlock_index`).
+    pub fn sync_to(
+        &self,
+        other: &Repo,
+        names: &[String],
+    ) -> Result<SyncResults> {
+        let _lock = self.aio.lock_shared();
+        let _other_lock = other.aio.lock_shared();
+        sync::sync(self, other, names, None)
+    }
+
+    /// Like `sync_to`, but `other` can use different keys and compression
+    ///
+    /// Chunks `other` can't store as they are, are decrypted with `dec` and
+    /// decompressed, then compressed and encrypted again with `other_enc`.
+    /// The digests are kept, so the repositories must use the same hashing
+    /// (and the same key, if it's keyed); chunking settings don't matter, as
+    /// the data is not rechunked.
+    pub fn sync_to_reencoding(
+        &self,
+        other: &Repo,
+        names: &[String],
+        dec: &DecryptHandle,
+        other_enc: &EncryptHandle,
+    ) -> Result<SyncResults> {
+        let _lock = self.aio.lock_shared();
+        let _other_lock = other.aio.lock_shared();
+        sync::sync(
+            self,
+            other,
+            names,
+            Some((&dec.decrypter, &other_enc.encrypter)),
+        )
+    }
+
     fn read_generations(&self) -> io::Result<Vec<Generation>> {
-        let mut list: Vec<_> = self
-            .aio
-            .list(PathBuf::new())
-            .wait()?
-            .iter()
-            .filter_map(|path| path.file_name().and_then(|file| file.to_str()))
-            .filter(|&item| {
-                item != config::CONFIG_YML_FILE
-                    && item != config::LOCK_FILE
-                    && !item.ends_with(".yml")
-            })
-            .filter_map(|item| match Generation::try_from(item) {
+        let (list, dead, unknown) = self.scan_generations()?;
+        for gen in dead {
+            warn!(
+                self.log,
+                "skipping dead generation: `{}` (config missing)", gen,
+            );
+        }
+        for (item, e) in unknown {
+            warn!(
+                self.log,
+                "skipping unknown generation: `{}` due to: `{}`", item, e
+            );
+        }
+        Ok(list)
+    }
+
+    /// List generations, sorted
+    ///
+    /// Returns also the dead generations (missing their config), and the
+    /// unknown entries of the repository root along with the reason they
+    /// are not generations.
+    #[allow(clippy::type_complexity)]
+    fn scan_generations(
+        &self,
+    ) -> io::Result<(Vec<Generation>, Vec<Generation>, Vec<(String, io::Error)>)>
+    {
+        let mut list = vec![];
+        let mut dead = vec![];
+        let mut unknown = vec![];
+        for path in self.aio.list(PathBuf::new()).wait()? {
+            let item = match path.file_name().and_then(|file| file.to_str()) {
+                Some(item) => item,
+                None => continue,
+            };
+            if item == config::CONFIG_YML_FILE
+                || item == config::LOCK_FILE
+                || item == dict::DICT_SUBDIR
+                || item.ends_with(".yml")
+            {
+                continue;
+            }
+            match Generation::try_from(item) {
                 Ok(gen) => {
                     if self.aio.read_metadata(gen.config_path()).wait().is_ok()
                     {
-                        Some(gen)
+                        list.push(gen);
                     } else {
-                        warn!(
-                            self.log,
-                            "skipping dead generation: `{}` (config missing)",
-                            item,
-                        );
-                        None
+                        dead.push(gen);
                     }
                 }
-                Err(e) => {
-                    warn!(
-                        self.log,
-                        "skipping unknown generation: `{}` due to: `{}`",
-                        item,
-                        e
-                    );
-                    None
-                }
-            })
-            .collect();
+                Err(e) => unknown.push((item.to_owned(), e)),
+            }
+        }
 
         list.sort();
-        Ok(list)
+        dead.sort();
+        Ok((list, dead, unknown))
     }
 
     pub fn write<R>(
@@ -825,10 +1636,183 @@ impl Repo {
         reader: R,
         enc: &EncryptHandle,
     ) -> Result<WriteStats>
+    where
+        R: Read + Send,
+    {
+        self.write_with_metadata(name_str, reader, enc, NameMetadata::default())
+    }
+
+    /// Like `write`, attaching `metadata` to the name
+    pub fn write_with_metadata<R>(
+        &self,
+        name_str: &str,
+        reader: R,
+        enc: &EncryptHandle,
+        metadata: NameMetadata,
+    ) -> Result<WriteStats>
     where
         R: Read + Send,
     {
         info!(self.log, "Writing data"; "name" => name_str);
+        self.write_from_input(name_str, enc, metadata, move |writer| {
+            let (data_address, size) =
+                writer.write_stream(move |chunker_tx| {
+                    self.input_reader_thread(reader, chunker_tx)
+                })?;
+            let mut name: Name = data_address.into();
+            name.size = Some(size);
+            Ok(name)
+        })
+    }
+
+    /// Store a snapshot of the directory `dir` under `name_str`
+    ///
+    /// Unlike `write`, this walks the directory by itself, and keeps
+    /// metadata of every entry (modes, ownership, mtimes, symlinks,
+    /// xattrs) along with the content of every file.
+    #[cfg(unix)]
+    pub fn write_snapshot(
+        &self,
+        name_str: &str,
+        dir: &Path,
+        enc: &EncryptHandle,
+    ) -> Result<WriteStats> {
+        self.write_snapshot_with_metadata(
+            name_str,
+            dir,
+            enc,
+            NameMetadata::default(),
+        )
+    }
+
+    /// Like `write_snapshot`, attaching `metadata` to the name
+    #[cfg(unix)]
+    pub fn write_snapshot_with_metadata(
+        &self,
+        name_str: &str,
+        dir: &Path,
+        enc: &EncryptHandle,
+        metadata: NameMetadata,
+    ) -> Result<WriteStats> {
+        info!(self.log, "Writing snapshot"; "name" => name_str, "dir" => %dir.display());
+        let tree = snapshot::Tree::from_dir(dir, &self.log)?;
+        self.write_from_input(name_str, enc, metadata, move |writer| {
+            let (data_address, size, tree_address) =
+                snapshot::write(dir, tree, writer, &self.log)?;
+            let mut name: Name = data_address.into();
+            name.size = Some(size);
+            name.snapshot_tree = Some(tree_address.into());
+            Ok(name)
+        })
+    }
+
+    /// Restore a snapshot stored under `name_str` into `dir`
+    ///
+    /// `dir` must not exist or be empty. It's only created after the tree
+    /// of the snapshot was read.
+    #[cfg(unix)]
+    pub fn read_snapshot(
+        &self,
+        name_str: &str,
+        dir: &Path,
+        dec: &DecryptHandle,
+    ) -> Result<()> {
+        self.with_snapshot_tree(name_str, dec, |tree, read| {
+            snapshot::restore(dir, &tree, read, &self.log)
+        })
+    }
+
+    /// Read the content of the file at `path` of a snapshot stored under
+    /// `name_str` into `writer`
+    ///
+    /// Only the tree of the snapshot and the content of this one file are
+    /// read.
+    #[cfg(unix)]
+    pub fn read_snapshot_file<W: Write>(
+        &self,
+        name_str: &str,
+        path: &Path,
+        writer: &mut W,
+        dec: &DecryptHandle,
+    ) -> Result<()> {
+        self.with_snapshot_tree(name_str, dec, |tree, read| {
+            match tree.file_content(path)? {
+                Some(content) => read(content.clone().into(), writer),
+                None => Ok(()),
+            }
+        })
+    }
+
+    /// Call `f` with the tree of the snapshot stored under `name_str`, and
+    /// a function reading data by its address
+    #[cfg(unix)]
+    fn with_snapshot_tree<T, F>(
+        &self,
+        name_str: &str,
+        dec: &DecryptHandle,
+        f: F,
+    ) -> Result<T>
+    where
+        F: FnOnce(
+            snapshot::Tree,
+            &dyn Fn(DataAddress, &mut dyn Write) -> io::Result<()>,
+        ) -> io::Result<T>,
+    {
+        let _lock = self.aio.lock_shared();
+
+        let generations = self.read_generations()?;
+
+        let name = Name::load_from_any(
+            name_str,
+            &generations,
+            &self.aio,
+            self.index_decrypter(Some(&dec.decrypter))?.as_ref(),
+        )?;
+        let tree_address = name.snapshot_tree.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "name does not contain a snapshot",
+            )
+        })?;
+
+        let accessor = self.get_chunk_accessor(
+            Some(Arc::clone(&dec.decrypter)),
+            Arc::clone(&self.compression),
+            generations,
+        );
+        let workers = self.read_thread_num();
+        let read = |data_address: DataAddress, writer: &mut dyn Write| {
+            read_pipelined(
+                &accessor,
+                data_address.as_ref(),
+                writer,
+                workers,
+                2 * workers,
+                self.log.clone(),
+            )
+        };
+
+        let mut tree_data = vec![];
+        read(tree_address.into(), &mut tree_data)?;
+        let tree = snapshot::Tree::from_data(&tree_data)?;
+        f(tree, &read)
+    }
+
+    /// Store data with `write`, and save the `Name` it returns as
+    /// `name_str`
+    ///
+    /// `write` can store any number of streams with the `StreamWriter`
+    /// it's given, which share the chunk processing threads.
+    fn write_from_input<F>(
+        &self,
+        name_str: &str,
+        enc: &EncryptHandle,
+        metadata: NameMetadata,
+        write: F,
+    ) -> Result<WriteStats>
+    where
+        F: FnOnce(&StreamWriter<'_>) -> io::Result<Name>,
+    {
         let _lock = self.aio.lock_shared();
 
         let mut generations = self.read_generations()?;
@@ -846,8 +1830,6 @@ impl Repo {
         );
         timer.start("write");
         let num_threads = num_cpus::get();
-        let (chunker_tx, chunker_rx) =
-            mpsc::sync_channel(self.write_cpu_thread_num());
 
         let backend = (self.backend_select)()?;
         let aio = aio::AsyncIO::new(backend, self.log.clone())?;
@@ -857,55 +1839,154 @@ impl Repo {
         // mpmc queue used  as spmc fan-out
         let (process_tx, process_rx) = crossbeam_channel::bounded(num_threads);
 
-        let data_address = crossbeam::scope(|scope| {
-            scope.spawn(move |_| self.input_reader_thread(reader, chunker_tx));
+        let adaptive_level = self.config.compression.auto_levels().map(
+            |(min_level, max_level)| {
+                Arc::new(AdaptiveLevel::new(
+                    min_level,
+                    max_level,
+                    num_threads,
+                    self.log.clone(),
+                ))
+            },
+        );
+        let process_error = Arc::new(Mutex::new(None));
 
+        let name = crossbeam::scope(|scope| {
             for _ in 0..num_threads {
                 let process_rx = process_rx.clone();
                 let aio = aio.clone();
                 let encrypter = Arc::clone(&enc.encrypter);
-                let compression = Arc::clone(&self.compression);
-                let hasher = Arc::clone(&self.hasher);
+                let adaptive_level = adaptive_level.clone();
                 let generations = generations.clone();
+                let process_error = Arc::clone(&process_error);
                 scope.spawn(move |_| {
                     let processor = ChunkProcessor::new(
                         self.clone(),
                         process_rx,
                         aio,
                         encrypter,
-                        compression,
-                        hasher,
+                        adaptive_level,
                         generations,
+                        process_error,
                     );
                     processor.run();
                 });
             }
             drop(process_rx);
 
-            let chunk_and_write = scope.spawn(move |_| {
-                self.chunk_and_write_data_thread(
-                    Box::new(chunker_rx.into_iter()),
-                    process_tx,
-                    aio,
-                    DataType::Data,
-                )
-            });
+            // Dropping the `StreamWriter` lets the chunk processors finish
+            write(&StreamWriter {
+                repo: self,
+                process_tx,
+                aio,
+            })
+        })
+        .expect("chunk processor panicked");
+
+        let mut name = name?;
+        if let Some(e) = process_error.lock().unwrap().take() {
+            return Err(e);
+        }
+
+        name.tags = metadata.tags;
+        name.description = metadata.description;
+        name.write_as(
+            name_str,
+            *generations.last().unwrap(),
+            &self.aio,
+            self.config.encrypt_index.then_some(&enc.encrypter),
+        )?;
 
-            chunk_and_write.join()
+        let mut stats = stats.get_stats();
+        if let Some(adaptive_level) = adaptive_level {
+            stats.compression_levels = adaptive_level.chunks_per_level();
+            info!(self.log, "Compressed chunks";
+                  "levels" => ?stats.compression_levels);
+        }
+        Ok(stats)
+    }
+}
+// }}}
+
+// {{{ StreamWriter
+/// Stores streams of data for `Repo::write_from_input`
+pub(crate) struct StreamWriter<'a> {
+    repo: &'a Repo,
+    process_tx: crossbeam_channel::Sender<chunk_processor::Message>,
+    aio: aio::AsyncIO,
+}
+
+impl StreamWriter<'_> {
+    /// Store the data sent to the chunker by the `input`
+    ///
+    /// `input` is running in its own thread. Returns the address of the
+    /// data and its total size.
+    pub(crate) fn write_stream<F>(
+        &self,
+        input: F,
+    ) -> io::Result<(DataAddress, u64)>
+    where
+        F: FnOnce(mpsc::SyncSender<Vec<u8>>) -> io::Result<()> + Send,
+    {
+        let (chunker_tx, chunker_rx) =
+            mpsc::sync_channel(self.repo.write_cpu_thread_num());
+
+        crossbeam::scope(|scope| {
+            let input = scope.spawn(move |_| input(chunker_tx));
+            let res = self.repo.chunk_and_write_data_thread(
+                Box::new(chunker_rx.into_iter()),
+                self.process_tx.clone(),
+                self.aio.clone(),
+                DataType::Data,
+            );
+            input.join().expect("input thread panicked")?;
+            res
         })
-        .expect("non-joined thread panicked (chunk processor?)");
+        .expect("chunker thread panicked")
+    }
 
-        let data_address = data_address.map_err(|e| {
-            if let Some(io_e) = e.downcast_ref::<io::Error>() {
-                io::Error::new(io_e.kind(), format!("{}", io_e))
-            } else {
-                io::Error::new(io::ErrorKind::Other, format!("{:?}", e))
+    /// Store an index joining the already stored `parts`, in order
+    ///
+    /// Reading the returned address gives all the parts one after another,
+    /// and everything reachable from any of them stays reachable from it.
+    /// All entries of an index have to be on the same index level, so parts
+    /// with fewer levels are first wrapped in single-entry index chunks.
+    pub(crate) fn write_joined(
+        &self,
+        parts: Vec<(DataAddress, u64)>,
+    ) -> io::Result<(DataAddress, u64)> {
+        let level = parts
+            .iter()
+            .map(|(address, _)| address.index_level)
+            .max()
+            .expect("at least one part");
+
+        let mut entries = Vec::with_capacity(parts.len());
+        for (mut address, size) in parts {
+            while address.index_level < level {
+                address = self.write_index(vec![(address, size)])?.0;
             }
-        })?;
+            entries.push((address, size));
+        }
+        self.write_index(entries)
+    }
 
-        let name: Name = data_address?.into();
-        name.write_as(name_str, *generations.last().unwrap(), &self.aio)?;
-        Ok(stats.get_stats())
+    /// Store an index of `entries`, which are all on the same index level
+    fn write_index(
+        &self,
+        entries: Vec<(DataAddress, u64)>,
+    ) -> io::Result<(DataAddress, u64)> {
+        let level = entries[0].0.index_level;
+        let (mut address, size) = self.repo.chunk_and_write_data_thread(
+            Box::new(entries.into_iter().map(|(address, size)| {
+                IndexFormat::sized_entry(&address.digest, size)
+            })),
+            self.process_tx.clone(),
+            self.aio.clone(),
+            DataType::Index,
+        )?;
+        address.index_level += level + 1;
+        Ok((address, size))
     }
 }
 // }}}
@@ -0,0 +1,319 @@
+//! Migrating a repository to other settings
+//!
+//! `Repo::migrate` rewrites every chunk with the new compression, encryption
+//! and nesting. Much like `gc`, it creates a new generation, and moves the
+//! names there one by one, along with all the chunks they reach, re-encoding
+//! them on the way. Once a generation has no names left, it's removed along
+//! with the chunks no name reaches.
+//!
+//! The new settings are recorded in `config.yml` before anything is moved,
+//! so an interrupted migration is resumed just by running it again. Until
+//! it's finished, the repository can't be opened, as the chunks are stored
+//! in two different ways.
+//!
+//! Repositories of older versions are upgraded to the current chunk format
+//! along the way, even if no setting changes.
+
+// {{{ use and mod
+use std::cell::Cell;
+use std::io::{self, Write};
+
+use slog::{info, FnValue};
+
+use crate::config;
+use crate::dict;
+use crate::encryption;
+use crate::name::Name;
+use crate::reading::{ChunkAccessor, ReadContext, ReadRequest};
+use crate::settings;
+use crate::sync::reencode;
+use crate::{
+    ArcDecrypter, ArcEncrypter, DataAddressRef, DataType, DigestRef,
+    Generation, PassphraseFn, Repo, SGData,
+};
+// }}}
+
+/// Start the migration, recording it in the config
+///
+/// Returns `None` if nothing would change.
+pub(crate) fn start(
+    repo: &Repo,
+    migration: &settings::Migration,
+    new_passphrase: PassphraseFn<'_>,
+) -> io::Result<Option<config::Migration>> {
+    let config = &repo.config;
+
+    let mut compression = match migration.compression {
+        Some(ref compression) => compression.to_config(0),
+        None => config.compression,
+    };
+    if let Some(level) = migration.compression_level {
+        compression = compression.with_level(level);
+    }
+    compression.validate()?;
+    let encryption = match (&migration.encryption, &config.encryption) {
+        (None, current)
+        | (
+            Some(settings::Encryption::Curve25519),
+            current @ config::Encryption::Curve25519(_),
+        )
+        | (
+            Some(settings::Encryption::XChaCha20Poly1305),
+            current @ config::Encryption::XChaCha20Poly1305(_),
+        ) => current.clone(),
+        (Some(settings::Encryption::XChaCha20Poly1305), _)
+        | (Some(_), config::Encryption::XChaCha20Poly1305(_)) => {
+            // The data would have to be rechunked, not just re-encoded
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "can't migrate to or from keyed chunk hashing, \
+                 as the digests would change",
+            ));
+        }
+        (Some(settings::Encryption::Curve25519), config::Encryption::None) => {
+            config::Encryption::Curve25519(encryption::Curve25519::new(
+                new_passphrase,
+                &config.pwhash,
+            )?)
+        }
+        (Some(settings::Encryption::None), _) => config::Encryption::None,
+    };
+    if config.encrypt_index && matches!(encryption, config::Encryption::None) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "index encryption requires encryption",
+        ));
+    }
+    if !encryption.same_keys(&config.encryption)
+        && !dict::list(repo)?.is_empty()
+    {
+        // They'd have to be rewritten along with the config
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "can't change the encryption of a repository with \
+             compression dictionaries",
+        ));
+    }
+    let nesting = match migration.nesting {
+        Some(ref nesting) => nesting.to_config(),
+        None => config.nesting.clone(),
+    };
+
+    if compression == config.compression
+        && encryption.same_keys(&config.encryption)
+        && nesting == config.nesting
+        && config.version == config::REPO_VERSION_CURRENT
+    {
+        return Ok(None);
+    }
+
+    let generation = match repo.read_generations()?.last() {
+        Some(gen) => gen.gen_next(),
+        None => Generation::gen_first(),
+    };
+    info!(repo.log, "Starting migration";
+          "gen" => FnValue(|_| generation.to_string()));
+    generation.write(&repo.aio)?;
+
+    let state = config::Migration {
+        generation: generation.to_string(),
+        compression,
+        encryption,
+        nesting,
+    };
+    let mut config = config.clone();
+    config.migration = Some(state.clone());
+    config.write(&repo.aio)?;
+    Ok(Some(state))
+}
+
+/// `ChunkAccessor` moving every chunk accessed to the target generation
+///
+/// Only index chunks are read; the data chunks are just moved.
+struct MigratingChunkAccessor<'a> {
+    repo: &'a Repo,
+    /// `repo` with the settings migrated to
+    target: &'a Repo,
+    target_generation: Generation,
+    /// Generations being migrated
+    generations: &'a [Generation],
+    decrypter: &'a ArcDecrypter,
+    encrypter: &'a ArcEncrypter,
+    migrated: Cell<usize>,
+}
+
+impl MigratingChunkAccessor<'_> {
+    /// Move a chunk to the target generation, unless it's there already
+    ///
+    /// Returns the chunk as stored now, if it was moved.
+    fn migrate_chunk(
+        &self,
+        digest: DigestRef<'_>,
+        data_type: DataType,
+    ) -> io::Result<Option<SGData>> {
+        let target_path = self.target.chunk_rel_path_by_digest(
+            digest,
+            &self.target_generation.to_string(),
+        );
+        match self.target.aio.read_metadata(target_path.clone()).wait() {
+            Ok(_) => return Ok(None),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+
+        for gen in self.generations.iter().rev() {
+            let path =
+                self.repo.chunk_rel_path_by_digest(digest, &gen.to_string());
+            let data = match self.repo.aio.read(path.clone()).wait() {
+                Ok(data) => data,
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            let data = reencode(
+                self.repo,
+                self.target,
+                self.decrypter,
+                self.encrypter,
+                data,
+                digest.0,
+                data_type,
+            )
+            .map_err(|e| {
+                io::Error::new(e.kind(), format!("{}: {}", path.display(), e))
+            })?;
+            // The copy must be in place before the original is gone
+            self.target.aio.write(target_path, data.clone()).wait()?;
+            self.repo.aio.remove(path).wait()?;
+            self.migrated.set(self.migrated.get() + 1);
+            return Ok(Some(data));
+        }
+
+        Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("chunk not found: {}", hex::encode(digest.0)),
+        ))
+    }
+}
+
+impl ChunkAccessor for MigratingChunkAccessor<'_> {
+    fn read_chunk_into(
+        &self,
+        digest: DigestRef<'_>,
+        data_type: DataType,
+        writer: &mut dyn Write,
+    ) -> io::Result<()> {
+        debug_assert!(data_type == DataType::Index);
+        let data = match self.migrate_chunk(digest, data_type)? {
+            Some(data) => data,
+            None => {
+                let path = self.target.chunk_rel_path_by_digest(
+                    digest,
+                    &self.target_generation.to_string(),
+                );
+                self.target.aio.read(path).wait()?
+            }
+        };
+        // The keys are the same, see `start`
+        let data = if self.target.should_encrypt(data_type) {
+            self.decrypter.decrypt(data, digest.0)?
+        } else {
+            data
+        };
+        for part in data.as_parts() {
+            writer.write_all(part)?;
+        }
+        Ok(())
+    }
+
+    fn touch(&self, digest: DigestRef<'_>) -> io::Result<()> {
+        self.migrate_chunk(digest, DataType::Data).map(|_| ())
+    }
+}
+
+/// Finish the migration recorded as `state`, returning the migrated `repo`
+pub(crate) fn run(
+    repo: &Repo,
+    decrypter: &ArcDecrypter,
+    state: &config::Migration,
+) -> io::Result<Repo> {
+    let target_generation = Generation::try_from(&state.generation)?;
+    let mut target_config = repo.config.clone();
+    target_config.compression = state.compression;
+    target_config.encryption = state.encryption.clone();
+    target_config.nesting = state.nesting.clone();
+    target_config.migration = None;
+    // Every chunk is rewritten anyway
+    target_config.version = config::REPO_VERSION_CURRENT;
+    let target = repo.with_config(target_config);
+    // Symmetric keys are never changed, see `start`
+    let encrypter = target.encrypter_from(Some(decrypter))?;
+    let index_decrypter = repo.index_decrypter(Some(decrypter))?;
+
+    loop {
+        let generations: Vec<_> = repo
+            .read_generations()?
+            .into_iter()
+            .filter(|gen| *gen < target_generation)
+            .collect();
+        let gen = match generations.first() {
+            Some(gen) => *gen,
+            None => break,
+        };
+
+        let names = Name::list(gen, &repo.aio)?;
+        info!(repo.log, "Migrating generation";
+              "gen" => FnValue(|_| gen.to_string()), "names" => names.len());
+        for name_str in names {
+            let name = Name::load_from(
+                &name_str,
+                gen,
+                &repo.aio,
+                index_decrypter.as_ref(),
+            )?;
+            let accessor = MigratingChunkAccessor {
+                repo,
+                target: &target,
+                target_generation,
+                generations: &generations,
+                decrypter,
+                encrypter: &encrypter,
+                migrated: Cell::new(0),
+            };
+            ReadContext::new(&accessor)
+                .read_recursively(ReadRequest::new(
+                    DataType::Data,
+                    DataAddressRef {
+                        index_level: name.index_level,
+                        digest: DigestRef(&name.digest),
+                        index_format: name.index_format,
+                    },
+                    None,
+                    repo.log.clone(),
+                ))
+                .map_err(|e| {
+                    io::Error::new(
+                        e.kind(),
+                        format!("couldn't migrate {}: {}", name_str, e),
+                    )
+                })?;
+            info!(repo.log, "Migrated name";
+                  "name" => &name_str, "chunks" => accessor.migrated.get());
+            Name::update_generation_to(
+                &name_str,
+                target_generation,
+                &[gen],
+                &repo.aio,
+            )?;
+        }
+
+        info!(repo.log, "Removing migrated generation";
+              "gen" => FnValue(|_| gen.to_string()));
+        repo.wipe_generation(gen)?;
+    }
+
+    target.config.write(&target.aio)?;
+    info!(target.log, "Migration finished");
+    Ok(target)
+}
+
+// vim: foldmethod=marker foldmarker={{{,}}}
@@ -1,5 +1,66 @@
 // {{{ use
-use crate::Name;
+use std::convert::TryInto;
+
+use serde::{Deserialize, Serialize};
+
+use crate::util::{as_hex, from_hex};
+use crate::{Name, DIGEST_SIZE};
+// }}}
+
+// {{{ IndexFormat
+/// Layout of the entries of an index
+///
+/// Names written before chunk sizes were recorded don't have this field
+/// stored, and deserialize to `Digest`.
+#[derive(
+    Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize,
+)]
+pub(crate) enum IndexFormat {
+    /// Every entry is just a digest of a chunk
+    #[default]
+    #[serde(rename = "digest")]
+    Digest,
+    /// Every entry is a digest followed by a little-endian `u64` size of the
+    /// data it represents
+    ///
+    /// Index chunks never split entries, so each index chunk
+    /// can be interpreted on its own, which makes seeking possible.
+    #[serde(rename = "digest-size")]
+    DigestSize,
+}
+
+impl IndexFormat {
+    pub(crate) fn entry_size(self) -> usize {
+        match self {
+            IndexFormat::Digest => DIGEST_SIZE,
+            IndexFormat::DigestSize => DIGEST_SIZE + 8,
+        }
+    }
+
+    /// Split `entry` into the digest and the data size (if recorded)
+    pub(crate) fn parse_entry(
+        self,
+        entry: &[u8],
+    ) -> (DigestRef<'_>, Option<u64>) {
+        debug_assert_eq!(entry.len(), self.entry_size());
+        let (digest, size) = entry.split_at(DIGEST_SIZE);
+        let size = match self {
+            IndexFormat::Digest => None,
+            IndexFormat::DigestSize => Some(u64::from_le_bytes(
+                size.try_into().expect("wrong index entry size"),
+            )),
+        };
+        (DigestRef(digest), size)
+    }
+
+    /// Serialize an entry in `IndexFormat::DigestSize` format
+    pub(crate) fn sized_entry(digest: &Digest, size: u64) -> Vec<u8> {
+        let mut entry = Vec::with_capacity(DIGEST_SIZE + 8);
+        entry.extend_from_slice(&digest.0);
+        entry.extend_from_slice(&size.to_le_bytes());
+        entry
+    }
+}
 // }}}
 
 // {{{ DataAddress & DataAddressRef
@@ -13,6 +74,8 @@ pub(crate) struct DataAddressRef<'a> {
     pub(crate) index_level: u32,
     // final digest
     pub(crate) digest: DigestRef<'a>,
+    // format of the index entries (if `index_level > 0`)
+    pub(crate) index_format: IndexFormat,
 }
 
 #[derive(Clone)]
@@ -26,6 +89,8 @@ pub(crate) struct DataAddress {
     pub(crate) index_level: u32,
     // final digest
     pub(crate) digest: Digest,
+    // format of the index entries (if `index_level > 0`)
+    pub(crate) index_format: IndexFormat,
 }
 
 impl DataAddress {
@@ -33,6 +98,17 @@ impl DataAddress {
         DataAddressRef {
             index_level: self.index_level,
             digest: self.digest.as_digest_ref(),
+            index_format: self.index_format,
+        }
+    }
+}
+
+impl From<StoredAddress> for DataAddress {
+    fn from(address: StoredAddress) -> Self {
+        DataAddress {
+            index_level: address.index_level,
+            digest: Digest(address.digest),
+            index_format: address.index_format,
         }
     }
 }
@@ -42,6 +118,26 @@ impl From<Name> for DataAddress {
         DataAddress {
             index_level: name.index_level,
             digest: Digest(name.digest),
+            index_format: name.index_format,
+        }
+    }
+}
+
+/// A `DataAddress` as stored in the repo, alongside other data
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct StoredAddress {
+    #[serde(serialize_with = "as_hex", deserialize_with = "from_hex")]
+    pub(crate) digest: Vec<u8>,
+    pub(crate) index_level: u32,
+    pub(crate) index_format: IndexFormat,
+}
+
+impl From<DataAddress> for StoredAddress {
+    fn from(address: DataAddress) -> Self {
+        StoredAddress {
+            digest: address.digest.0,
+            index_level: address.index_level,
+            index_format: address.index_format,
         }
     }
 }
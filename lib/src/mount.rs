@@ -0,0 +1,439 @@
+//! Read-only FUSE filesystem exposing the names stored in a `Repo`
+//!
+//! Every name is presented as a regular file in the root directory.
+//! Reads are served by a `SeekableReader` kept for every open file, so only
+//! the chunks holding the requested range are fetched. Decrypted chunks are
+//! kept in a `ChunkCache` shared by all the files.
+//!
+//! The repository lock is taken for every operation separately, so the
+//! repository can still be modified (and garbage-collected) while mounted.
+
+// {{{ use and mod
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::OsStr;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use fuser::{
+    FileAttr, FileType, Filesystem, MountOption, ReplyAttr, ReplyData,
+    ReplyDirectory, ReplyEmpty, ReplyEntry, ReplyOpen, Request,
+};
+use slog::{debug, warn, Logger};
+
+use crate::reading::{CachingChunkAccessor, ChunkCache, SeekableReader};
+use crate::{ArcDecrypter, DataAddress, Generation, Name, Repo};
+// }}}
+
+const ROOT_INO: u64 = 1;
+
+/// How long can the kernel cache the attributes and entries
+const TTL: Duration = Duration::from_secs(1);
+
+pub(crate) fn mount_options() -> Vec<MountOption> {
+    vec![
+        MountOption::RO,
+        MountOption::FSName("rdedup".into()),
+        MountOption::Subtype("rdedup".into()),
+    ]
+}
+
+struct Entry {
+    name: String,
+    address: DataAddress,
+    size: u64,
+    created: SystemTime,
+}
+
+/// A file open by the kernel
+struct Handle {
+    ino: u64,
+    reader: SeekableReader<CachingChunkAccessor>,
+}
+
+pub(crate) struct RepoFs {
+    repo: Repo,
+    decrypter: ArcDecrypter,
+    cache: Arc<ChunkCache>,
+    generations: Vec<Generation>,
+    /// Entries of the names currently stored, by inode number
+    entries: BTreeMap<u64, Entry>,
+    inos: HashMap<String, u64>,
+    /// Last inode number given out; they are never reused
+    last_ino: u64,
+    /// Open files, by file handle
+    handles: HashMap<u64, Handle>,
+    last_fh: u64,
+    uid: u32,
+    gid: u32,
+    log: Logger,
+}
+
+impl RepoFs {
+    pub(crate) fn new(
+        repo: Repo,
+        decrypter: ArcDecrypter,
+        cache_size: usize,
+    ) -> io::Result<Self> {
+        let mut fs = RepoFs {
+            log: repo.log.clone(),
+            repo,
+            decrypter,
+            cache: Arc::new(ChunkCache::new(cache_size)),
+            generations: vec![],
+            entries: BTreeMap::new(),
+            inos: HashMap::new(),
+            last_ino: ROOT_INO,
+            handles: HashMap::new(),
+            last_fh: 0,
+            // Safety: these calls can't fail
+            uid: unsafe { libc::getuid() },
+            gid: unsafe { libc::getgid() },
+        };
+        fs.refresh()?;
+        Ok(fs)
+    }
+
+    fn open_reader(
+        &self,
+        entry: &Entry,
+    ) -> io::Result<SeekableReader<CachingChunkAccessor>> {
+        let accessor = CachingChunkAccessor::new(
+            self.repo.clone(),
+            Some(Arc::clone(&self.decrypter)),
+            Arc::clone(&self.repo.compression),
+            self.generations.clone(),
+            Arc::clone(&self.cache),
+        );
+        SeekableReader::new(
+            accessor,
+            entry.address.clone(),
+            Some(entry.size),
+            None,
+        )
+    }
+
+    /// Reload the list of names and generations
+    ///
+    /// Names still stored keep their inode numbers; the entries of the
+    /// removed ones are dropped.
+    pub(crate) fn refresh(&mut self) -> io::Result<()> {
+        let _lock = self.repo.aio.lock_shared();
+
+        self.generations = self.repo.read_generations()?;
+        let mut names = Name::list_all(&self.generations, &self.repo.aio)?;
+        names.sort();
+        names.dedup();
+
+        let index_decrypter =
+            self.repo.index_decrypter(Some(&self.decrypter))?;
+        let mut entries = BTreeMap::new();
+        for name_str in names {
+            let name = match Name::load_from_any(
+                &name_str,
+                &self.generations,
+                &self.repo.aio,
+                index_decrypter.as_ref(),
+            ) {
+                Ok(name) => name,
+                // removed in the meantime
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+
+            let ino = self.inos.get(&name_str).cloned();
+            if let Some(ino) = ino {
+                if self
+                    .entries
+                    .get(&ino)
+                    .is_some_and(|entry| entry.address.digest.0 == name.digest)
+                {
+                    let entry = self.entries.remove(&ino).unwrap();
+                    entries.insert(ino, entry);
+                    continue;
+                }
+            }
+
+            let size = name.size;
+            let created = name.created.into();
+            let mut entry = Entry {
+                name: name_str.clone(),
+                address: name.into(),
+                size: 0,
+                created,
+            };
+            entry.size = match size {
+                Some(size) => size,
+                None => match self.open_reader(&entry) {
+                    Ok(reader) => reader.size(),
+                    Err(e) => {
+                        warn!(self.log, "skipping name that can't be mounted";
+                              "name" => &name_str, "err" => %e);
+                        continue;
+                    }
+                },
+            };
+
+            let ino = ino.unwrap_or_else(|| {
+                self.last_ino += 1;
+                self.last_ino
+            });
+            entries.insert(ino, entry);
+        }
+
+        self.inos = entries
+            .iter()
+            .map(|(ino, entry)| (entry.name.clone(), *ino))
+            .collect();
+        self.entries = entries;
+        Ok(())
+    }
+
+    /// Inode number of a name
+    pub(crate) fn ino(&self, name: &str) -> Option<u64> {
+        self.inos.get(name).cloned()
+    }
+
+    fn entry(&self, ino: u64) -> Option<&Entry> {
+        self.entries.get(&ino)
+    }
+
+    fn attr(&self, ino: u64) -> Option<FileAttr> {
+        let (kind, perm, size, time) = if ino == ROOT_INO {
+            (FileType::Directory, 0o555, 0, UNIX_EPOCH)
+        } else {
+            let entry = self.entry(ino)?;
+            (FileType::RegularFile, 0o444, entry.size, entry.created)
+        };
+
+        Some(FileAttr {
+            ino,
+            size,
+            blocks: size.div_ceil(512),
+            atime: time,
+            mtime: time,
+            ctime: time,
+            crtime: time,
+            kind,
+            perm,
+            nlink: if kind == FileType::Directory { 2 } else { 1 },
+            uid: self.uid,
+            gid: self.gid,
+            rdev: 0,
+            blksize: 4096,
+            flags: 0,
+        })
+    }
+
+    /// Open the file `ino`, returning its file handle
+    pub(crate) fn open_file(&mut self, ino: u64) -> io::Result<u64> {
+        let reader = {
+            let _lock = self.repo.aio.lock_shared();
+            let entry = self
+                .entry(ino)
+                .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+            self.open_reader(entry)?
+        };
+
+        self.last_fh += 1;
+        self.handles.insert(self.last_fh, Handle { ino, reader });
+        Ok(self.last_fh)
+    }
+
+    /// Close the file handle `fh`
+    pub(crate) fn release_file(&mut self, fh: u64) {
+        self.handles.remove(&fh);
+    }
+
+    /// Open the file of `fh` again, to read it from the current generations
+    fn reopen_file(&mut self, fh: u64) -> io::Result<()> {
+        let ino = self.handles[&fh].ino;
+        let reader = {
+            let _lock = self.repo.aio.lock_shared();
+            let entry = self
+                .entry(ino)
+                .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+            self.open_reader(entry)?
+        };
+        self.handles.get_mut(&fh).unwrap().reader = reader;
+        Ok(())
+    }
+
+    /// Read up to `size` bytes at `offset` of the file open as `fh`
+    pub(crate) fn read_at(
+        &mut self,
+        fh: u64,
+        offset: u64,
+        size: u32,
+    ) -> io::Result<Vec<u8>> {
+        let _lock = self.repo.aio.lock_shared();
+
+        let reader = &mut self
+            .handles
+            .get_mut(&fh)
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::EBADF))?
+            .reader;
+        reader.seek(SeekFrom::Start(offset))?;
+
+        let mut buf = Vec::with_capacity(size as usize);
+        reader.take(u64::from(size)).read_to_end(&mut buf)?;
+        Ok(buf)
+    }
+}
+
+impl Filesystem for RepoFs {
+    fn lookup(
+        &mut self,
+        _req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        reply: ReplyEntry,
+    ) {
+        if parent != ROOT_INO {
+            return reply.error(libc::ENOENT);
+        }
+        let name = match name.to_str() {
+            Some(name) => name,
+            None => return reply.error(libc::ENOENT),
+        };
+
+        if self.ino(name).is_none() {
+            if let Err(e) = self.refresh() {
+                warn!(self.log, "couldn't refresh names"; "err" => %e);
+                return reply.error(libc::EIO);
+            }
+        }
+
+        match self.ino(name).and_then(|ino| self.attr(ino)) {
+            Some(attr) => reply.entry(&TTL, &attr, 0),
+            None => reply.error(libc::ENOENT),
+        }
+    }
+
+    fn getattr(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: Option<u64>,
+        reply: ReplyAttr,
+    ) {
+        match self.attr(ino) {
+            Some(attr) => reply.attr(&TTL, &attr),
+            None => reply.error(libc::ENOENT),
+        }
+    }
+
+    fn open(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _flags: i32,
+        reply: ReplyOpen,
+    ) {
+        match self.open_file(ino) {
+            Ok(fh) => reply.opened(fh, 0),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                reply.error(libc::ENOENT)
+            }
+            Err(e) => {
+                warn!(self.log, "open failed"; "ino" => ino, "err" => %e);
+                reply.error(libc::EIO)
+            }
+        }
+    }
+
+    fn release(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: u64,
+        fh: u64,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        _flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        self.release_file(fh);
+        reply.ok();
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        if offset < 0 {
+            return reply.error(libc::EINVAL);
+        }
+        if !self.handles.contains_key(&fh) {
+            return reply.error(libc::EBADF);
+        }
+
+        let mut res = self.read_at(fh, offset as u64, size);
+        if let Err(ref e) = res {
+            if e.kind() == io::ErrorKind::NotFound {
+                // Chunks might have been moved by GC to a newer generation
+                debug!(self.log, "chunk not found; refreshing"; "err" => %e);
+                res = self
+                    .refresh()
+                    .and_then(|_| self.reopen_file(fh))
+                    .and_then(|_| self.read_at(fh, offset as u64, size));
+            }
+        }
+
+        match res {
+            Ok(data) => reply.data(&data),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                reply.error(libc::ENOENT)
+            }
+            Err(e) => {
+                warn!(self.log, "read failed"; "ino" => ino, "err" => %e);
+                reply.error(libc::EIO)
+            }
+        }
+    }
+
+    fn readdir(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        if ino != ROOT_INO {
+            return reply.error(libc::ENOTDIR);
+        }
+
+        if offset == 0 {
+            if let Err(e) = self.refresh() {
+                warn!(self.log, "couldn't refresh names"; "err" => %e);
+                return reply.error(libc::EIO);
+            }
+        }
+
+        let entries = [
+            (ROOT_INO, FileType::Directory, "."),
+            (ROOT_INO, FileType::Directory, ".."),
+        ]
+        .into_iter()
+        .chain(self.entries.iter().map(|(&ino, entry)| {
+            (ino, FileType::RegularFile, entry.name.as_str())
+        }));
+
+        for (i, (ino, kind, name)) in entries.enumerate().skip(offset as usize)
+        {
+            if reply.add(ino, (i + 1) as i64, kind, name) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+}
+
+// vim: foldmethod=marker foldmarker={{{,}}}
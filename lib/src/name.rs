@@ -1,13 +1,18 @@
+use std::collections::BTreeMap;
 use std::io;
 use std::path::PathBuf;
 
 use serde::{Deserialize, Serialize};
 
 use crate::aio;
+use crate::hashing::{self, Hasher};
 use crate::util::*;
 use crate::SGData;
 use crate::DIGEST_SIZE;
-use crate::{DataAddress, DataAddressRef, Generation};
+use crate::{ArcDecrypter, ArcEncrypter};
+use crate::{
+    DataAddress, DataAddressRef, Generation, IndexFormat, StoredAddress,
+};
 
 pub(crate) const NAME_SUBDIR: &str = "name";
 
@@ -19,6 +24,21 @@ pub(crate) struct Name {
     #[serde(serialize_with = "as_rfc3339", deserialize_with = "from_rfc3339")]
     /// The UTC timestamp when this `Name` was created.
     pub(crate) created: chrono::DateTime<chrono::Utc>,
+    /// Format of the index entries
+    #[serde(default)]
+    pub(crate) index_format: IndexFormat,
+    /// Total size of the data (not recorded by older versions)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) size: Option<u64>,
+    /// User-provided key/value tags
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub(crate) tags: BTreeMap<String, String>,
+    /// User-provided free-form description
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) description: Option<String>,
+    /// Address of the tree, if this is a snapshot of a directory
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) snapshot_tree: Option<StoredAddress>,
 }
 
 // TODO: I am very displeased with myself how this
@@ -89,6 +109,13 @@ impl Name {
         ))
     }
 
+    /// Digest the content of an encrypted name file is bound to, so it can't
+    /// be passed for another name
+    fn file_digest(name: &str) -> Vec<u8> {
+        hashing::Blake2b
+            .calculate_digest(&SGData::from_single(name.as_bytes().to_vec()))
+    }
+
     pub(crate) fn path(name: &str, gen: Generation) -> PathBuf {
         let mut path: PathBuf = gen.to_string().into();
         path.push(NAME_SUBDIR);
@@ -131,14 +158,23 @@ impl Name {
         Ok(res)
     }
 
+    /// Write as `name`, encrypted with `encrypter`, if given
     pub fn write_as(
         &self,
         name: &str,
         gen: Generation,
         aio: &aio::AsyncIO,
+        encrypter: Option<&ArcEncrypter>,
     ) -> io::Result<()> {
         let serialized_str =
             serde_yaml::to_string(self).expect("yaml serialization failed");
+        let data = SGData::from_single(serialized_str.into_bytes());
+        let data = match encrypter {
+            Some(encrypter) => {
+                encrypter.encrypt(data, &Name::file_digest(name))?
+            }
+            None => data,
+        };
 
         let path = Name::path(name, gen);
 
@@ -149,22 +185,38 @@ impl Name {
             ));
         }
 
-        aio.write(path, SGData::from_single(serialized_str.into_bytes()))
-            .wait()?;
+        aio.write(path, data).wait()?;
         Ok(())
     }
 
     /// Attempts to deserialize `path` as a `Name`. For backwards compatibility,
     /// if the source `Name` does not have populated `created` information,
     /// populates from filesystem metadata.
+    ///
+    /// Encrypted name files are decrypted with `decrypter`, and never written
+    /// by the versions missing `created`.
     fn try_deserialize(
         name_str: &str,
         gen: Generation,
         aio: &aio::AsyncIO,
+        decrypter: Option<&ArcDecrypter>,
     ) -> Result<Name, io::Error> {
         let path = Name::path(name_str, gen);
 
         let config_data = aio.read(path.clone()).wait()?;
+        if let Some(decrypter) = decrypter {
+            let config_data =
+                decrypter.decrypt(config_data, &Name::file_digest(name_str))?;
+            return serde_yaml::from_reader(
+                config_data.into_linear_vec().as_slice(),
+            )
+            .map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("couldn't parse yaml: {}", e),
+                )
+            });
+        }
         let config_data = config_data.into_linear_vec();
 
         if let Ok(name) = serde_yaml::from_reader(config_data.as_slice()) {
@@ -195,6 +247,11 @@ impl Name {
             digest,
             index_level,
             created,
+            index_format: IndexFormat::Digest,
+            size: None,
+            tags: BTreeMap::new(),
+            description: None,
+            snapshot_tree: None,
         };
 
         let is_serde_err = serde_yaml::to_string(&name)
@@ -213,12 +270,14 @@ impl Name {
         Ok(name)
     }
 
+    /// Load `name` from `gen`, decrypting it with `decrypter`, if given
     pub fn load_from(
         name: &str,
         gen: Generation,
         aio: &aio::AsyncIO,
+        decrypter: Option<&ArcDecrypter>,
     ) -> io::Result<Self> {
-        let name = Name::try_deserialize(name, gen, aio)?;
+        let name = Name::try_deserialize(name, gen, aio, decrypter)?;
 
         if name.digest.len() != DIGEST_SIZE {
             return Err(io::Error::new(
@@ -234,9 +293,10 @@ impl Name {
         name: &str,
         gens: &[Generation],
         aio: &aio::AsyncIO,
+        decrypter: Option<&ArcDecrypter>,
     ) -> io::Result<Self> {
         for gen in gens.iter().rev() {
-            match Name::load_from(name, *gen, aio) {
+            match Name::load_from(name, *gen, aio, decrypter) {
                 Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
                 res => return res,
             }
@@ -255,6 +315,11 @@ impl From<DataAddressRef<'_>> for Name {
             digest: da.digest.0.into(),
             index_level: da.index_level,
             created: chrono::Utc::now(),
+            index_format: da.index_format,
+            size: None,
+            tags: BTreeMap::new(),
+            description: None,
+            snapshot_tree: None,
         }
     }
 }
@@ -265,6 +330,11 @@ impl From<DataAddress> for Name {
             digest: da.digest.0,
             index_level: da.index_level,
             created: chrono::Utc::now(),
+            index_format: da.index_format,
+            size: None,
+            tags: BTreeMap::new(),
+            description: None,
+            snapshot_tree: None,
         }
     }
 }
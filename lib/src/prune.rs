@@ -0,0 +1,164 @@
+//! Selecting names to remove according to a retention policy
+//!
+//! Names are first split into groups (see `PruneGroupBy`), and the policy is
+//! applied to every group separately, so eg. each host or job keeps its own
+//! daily backups. Within a group, a name is kept if any of the rules keeps
+//! it:
+//!
+//! * `keep_last` keeps the `n` most recently created names,
+//! * `keep_daily`, `keep_weekly` and `keep_monthly` keep the most recent name
+//!   of each of the `n` most recent days, ISO weeks and months (in UTC) that
+//!   have any names.
+
+use std::collections::{BTreeMap, HashSet};
+use std::io;
+
+use chrono::Datelike;
+
+use crate::NameInfo;
+
+/// How to split names into groups pruned independently
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum PruneGroupBy {
+    /// All the names form one group
+    #[default]
+    None,
+    /// Group by the part of the name before its first digit, without
+    /// trailing separators (`-`, `_`, `.`, `:` and `@`); eg. `home-2024-05-01`
+    /// and `home-2024-06-01` are both in the `home` group
+    Prefix,
+    /// Group by the value of a tag; names without it form their own group
+    Tag(String),
+}
+
+impl PruneGroupBy {
+    fn key(&self, info: &NameInfo) -> Option<String> {
+        match self {
+            PruneGroupBy::None => None,
+            PruneGroupBy::Prefix => {
+                let prefix = info
+                    .name
+                    .split(|c: char| c.is_ascii_digit())
+                    .next()
+                    .unwrap_or_default();
+                Some(prefix.trim_end_matches(['-', '_', '.', ':', '@']).into())
+            }
+            PruneGroupBy::Tag(key) => info.metadata.tags.get(key).cloned(),
+        }
+    }
+}
+
+/// Retention policy used by `Repo::prune`
+#[derive(Clone, Debug, Default)]
+pub struct PrunePolicy {
+    pub keep_last: usize,
+    pub keep_daily: usize,
+    pub keep_weekly: usize,
+    pub keep_monthly: usize,
+    pub group_by: PruneGroupBy,
+}
+
+impl PrunePolicy {
+    fn is_empty(&self) -> bool {
+        self.keep_last == 0
+            && self.keep_daily == 0
+            && self.keep_weekly == 0
+            && self.keep_monthly == 0
+    }
+}
+
+/// Names kept and removed by `Repo::prune`, sorted by name
+#[derive(Clone, Debug, Default)]
+pub struct PruneResults {
+    pub keep: Vec<String>,
+    pub remove: Vec<String>,
+}
+
+/// Keep the newest name for each of the `n` newest periods
+///
+/// `names` must be sorted from the newest.
+fn keep_periods<K: PartialEq>(
+    names: &[&NameInfo],
+    n: usize,
+    period: impl Fn(&NameInfo) -> K,
+    keep: &mut HashSet<String>,
+) {
+    let mut last_period = None;
+    let mut count = 0;
+    for info in names {
+        if count == n {
+            break;
+        }
+        let cur_period = period(info);
+        if last_period.as_ref() != Some(&cur_period) {
+            keep.insert(info.name.clone());
+            last_period = Some(cur_period);
+            count += 1;
+        }
+    }
+}
+
+/// Select the names to keep and remove according to `policy`
+pub(crate) fn select(
+    infos: Vec<NameInfo>,
+    policy: &PrunePolicy,
+) -> io::Result<PruneResults> {
+    if policy.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "empty retention policy would remove everything",
+        ));
+    }
+
+    let mut groups: BTreeMap<Option<String>, Vec<&NameInfo>> = BTreeMap::new();
+    for info in &infos {
+        groups
+            .entry(policy.group_by.key(info))
+            .or_default()
+            .push(info);
+    }
+
+    let mut keep = HashSet::new();
+    for names in groups.values_mut() {
+        names.sort_by(|a, b| {
+            b.created.cmp(&a.created).then_with(|| b.name.cmp(&a.name))
+        });
+
+        keep.extend(
+            names
+                .iter()
+                .take(policy.keep_last)
+                .map(|info| info.name.clone()),
+        );
+        keep_periods(
+            names,
+            policy.keep_daily,
+            |i| (i.created.year(), i.created.ordinal()),
+            &mut keep,
+        );
+        keep_periods(
+            names,
+            policy.keep_weekly,
+            |i| i.created.iso_week(),
+            &mut keep,
+        );
+        keep_periods(
+            names,
+            policy.keep_monthly,
+            |i| (i.created.year(), i.created.month()),
+            &mut keep,
+        );
+    }
+
+    let mut results = PruneResults::default();
+    for info in infos {
+        if keep.contains(&info.name) {
+            results.keep.push(info.name);
+        } else {
+            results.remove.push(info.name);
+        }
+    }
+    results.keep.sort();
+    results.remove.sort();
+    Ok(results)
+}
@@ -2,6 +2,7 @@ use std::io;
 
 use serde::{Deserialize, Serialize};
 use sodiumoxide::crypto::pwhash;
+use sodiumoxide::crypto::pwhash::argon2id13;
 
 use crate::util::{as_base64, from_base64};
 
@@ -45,6 +46,18 @@ impl SodiumOxide {
     }
 }
 
+impl SodiumOxide {
+    pub(crate) fn with_limits(
+        mut self,
+        mem_limit: Option<u64>,
+        ops_limit: Option<u64>,
+    ) -> Self {
+        self.mem_limit = mem_limit.unwrap_or(self.mem_limit);
+        self.ops_limit = ops_limit.unwrap_or(self.ops_limit);
+        self
+    }
+}
+
 impl Default for SodiumOxide {
     fn default() -> Self {
         SodiumOxide {
@@ -77,3 +90,71 @@ impl PWHash for SodiumOxide {
         Ok(key)
     }
 }
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct Argon2id {
+    #[serde(serialize_with = "as_base64", deserialize_with = "from_base64")]
+    salt: argon2id13::Salt,
+    mem_limit: u64,
+    ops_limit: u64,
+}
+
+impl Argon2id {
+    pub(crate) fn new_weak() -> Self {
+        // The lowest limits `argon2id` accepts
+        Self {
+            ops_limit: 1,
+            mem_limit: 8192,
+            salt: argon2id13::gen_salt(),
+        }
+    }
+
+    pub(crate) fn new_interactive() -> Self {
+        Self {
+            ops_limit: argon2id13::OPSLIMIT_INTERACTIVE.0 as u64,
+            mem_limit: argon2id13::MEMLIMIT_INTERACTIVE.0 as u64,
+            salt: argon2id13::gen_salt(),
+        }
+    }
+
+    pub(crate) fn new_sensitive() -> Self {
+        Self {
+            ops_limit: argon2id13::OPSLIMIT_SENSITIVE.0 as u64,
+            mem_limit: argon2id13::MEMLIMIT_SENSITIVE.0 as u64,
+            salt: argon2id13::gen_salt(),
+        }
+    }
+
+    pub(crate) fn with_limits(
+        mut self,
+        mem_limit: Option<u64>,
+        ops_limit: Option<u64>,
+    ) -> Self {
+        self.mem_limit = mem_limit.unwrap_or(self.mem_limit);
+        self.ops_limit = ops_limit.unwrap_or(self.ops_limit);
+        self
+    }
+}
+
+impl PWHash for Argon2id {
+    /// Derive secret key from passphrase and salt
+    fn derive_key(&self, passphrase: &str) -> io::Result<Vec<u8>> {
+        let mut key = vec![0; 32];
+
+        argon2id13::derive_key(
+            &mut key,
+            passphrase.as_bytes(),
+            &self.salt,
+            argon2id13::OpsLimit(self.ops_limit as usize),
+            argon2id13::MemLimit(self.mem_limit as usize),
+        )
+        .map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "can't derive encryption key from passphrase",
+            )
+        })?;
+
+        Ok(key)
+    }
+}
@@ -1,31 +1,35 @@
 //! Primitives used for reading the chunked data stored in the `Repo`
 // {{{ use and mod
-use std::cell::RefCell;
-use std::collections::HashSet;
+use std::cell::{Cell, RefCell};
+use std::cmp;
+use std::collections::{BTreeMap, HashSet};
 use std::io;
-use std::io::Write;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::sync::{mpsc, Arc, Mutex};
 
 use slog::{trace, warn, FnValue, Logger};
 
+use crate::aio::backend::Lock;
 use crate::Generation;
 use crate::VerifyResults;
 use crate::{ArcCompression, ArcDecrypter};
 use crate::{
-    DataAddressRef, DataType, Digest, DigestRef, Error, Repo, DIGEST_SIZE,
+    DataAddress, DataAddressRef, DataType, DigestRef, Error, IndexFormat, Repo,
 };
 // }}}
 
 /// Translates index stream into data stream
 ///
 /// This type implements `io::Write` and interprets what's written to it as a
-/// stream of digests.
+/// stream of index entries in `index_format`.
 ///
-/// For every digest written to it, it will access the corresponding chunk and
+/// For every entry written to it, it will access the corresponding chunk and
 /// write it into `writer` that it wraps.
 struct IndexTranslator<'a, 'b> {
     writer: Option<&'b mut dyn Write>,
-    digest_buf: Digest,
+    entry_buf: Vec<u8>,
     data_type: DataType,
+    index_format: IndexFormat,
     read_context: &'a ReadContext<'a>,
     log: Logger,
 }
@@ -34,12 +38,14 @@ impl<'a, 'b> IndexTranslator<'a, 'b> {
     pub(crate) fn new(
         writer: Option<&'b mut dyn Write>,
         data_type: DataType,
+        index_format: IndexFormat,
         read_context: &'a ReadContext<'a>,
         log: Logger,
     ) -> Self {
         IndexTranslator {
             data_type,
-            digest_buf: Digest(Vec::with_capacity(DIGEST_SIZE)),
+            index_format,
+            entry_buf: Vec::with_capacity(index_format.entry_size()),
             read_context,
             writer,
             log,
@@ -49,62 +55,57 @@ impl<'a, 'b> IndexTranslator<'a, 'b> {
 
 impl Write for IndexTranslator<'_, '_> {
     fn write(&mut self, mut bytes: &[u8]) -> io::Result<usize> {
-        assert!(!bytes.is_empty());
+        if bytes.is_empty() {
+            return Ok(0);
+        }
 
+        let entry_size = self.index_format.entry_size();
         let total_len = bytes.len();
         loop {
-            let has_already = self.digest_buf.0.len();
-            if (has_already + bytes.len()) < DIGEST_SIZE {
-                self.digest_buf.0.extend_from_slice(bytes);
+            let has_already = self.entry_buf.len();
+            if (has_already + bytes.len()) < entry_size {
+                self.entry_buf.extend_from_slice(bytes);
 
                 trace!(
                     self.log,
                     "left with a buffer";
-                    "digest" => FnValue(|_| hex::encode(&self.digest_buf.0)),
+                    "entry" => FnValue(|_| hex::encode(&self.entry_buf)),
                 );
                 return Ok(total_len);
             }
 
             let &mut IndexTranslator {
-                ref mut digest_buf,
+                ref mut entry_buf,
                 data_type,
+                index_format,
                 ref mut writer,
                 read_context,
                 ..
             } = self;
-            let needs = DIGEST_SIZE - has_already;
-
-            if digest_buf.0.is_empty() {
-                let digest = &bytes[..needs];
-                debug_assert_eq!(digest.len(), DIGEST_SIZE);
-                bytes = &bytes[needs..];
-
-                read_context.read_recursively(ReadRequest::new(
-                    data_type,
-                    DataAddressRef {
-                        digest: DigestRef(digest),
-                        index_level: 0,
-                    },
-                    writer.as_mut().map(|w| w as &mut dyn io::Write),
-                    self.log.clone(),
-                ))?;
+            let needs = entry_size - has_already;
+
+            let entry = if entry_buf.is_empty() {
+                &bytes[..needs]
             } else {
-                digest_buf.0.extend_from_slice(&bytes[..needs]);
-                debug_assert_eq!(digest_buf.0.len(), DIGEST_SIZE);
-                bytes = &bytes[needs..];
-
-                let res = read_context.read_recursively(ReadRequest::new(
-                    data_type,
-                    DataAddressRef {
-                        digest: digest_buf.as_digest_ref(),
-                        index_level: 0,
-                    },
-                    writer.as_mut().map(|w| w as &mut dyn io::Write),
-                    self.log.clone(),
-                ));
-                digest_buf.0.clear();
-                res?;
-            }
+                entry_buf.extend_from_slice(&bytes[..needs]);
+                entry_buf.as_slice()
+            };
+            debug_assert_eq!(entry.len(), entry_size);
+            bytes = &bytes[needs..];
+
+            let (digest, _size) = index_format.parse_entry(entry);
+            let res = read_context.read_recursively(ReadRequest::new(
+                data_type,
+                DataAddressRef {
+                    digest,
+                    index_level: 0,
+                    index_format,
+                },
+                writer.as_mut().map(|w| w as &mut dyn io::Write),
+                self.log.clone(),
+            ));
+            entry_buf.clear();
+            res?;
         }
     }
 
@@ -116,7 +117,7 @@ impl Write for IndexTranslator<'_, '_> {
 impl Drop for IndexTranslator<'_, '_> {
     fn drop(&mut self) {
         if !std::thread::panicking() {
-            debug_assert_eq!(self.digest_buf.0.len(), 0);
+            debug_assert_eq!(self.entry_buf.len(), 0);
         }
     }
 }
@@ -169,6 +170,7 @@ impl<'a> ReadContext<'a> {
         let mut translator = IndexTranslator::new(
             req.writer.take(),
             req.data_type,
+            req.data_address.index_format,
             self,
             req.log.clone(),
         );
@@ -176,6 +178,7 @@ impl<'a> ReadContext<'a> {
         let da = DataAddressRef {
             digest: req.data_address.digest,
             index_level: req.data_address.index_level - 1,
+            index_format: req.data_address.index_format,
         };
         let req = ReadRequest::new(
             DataType::Index,
@@ -221,6 +224,365 @@ impl<'a> ReadContext<'a> {
     }
 }
 
+/// `ChunkAccessor` walking the index tree for `read_pipelined`
+///
+/// Index chunks are read with the wrapped accessor as usual, while the data
+/// chunks are only numbered and handed over to the workers.
+struct DispatchingChunkAccessor<'a> {
+    raw: &'a dyn ChunkAccessor,
+    next_i: Cell<u64>,
+    /// Limits how far ahead of the writer the workers can get
+    window_tx: mpsc::SyncSender<()>,
+    digest_tx: crossbeam_channel::Sender<(u64, Vec<u8>)>,
+}
+
+fn read_cancelled() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "read cancelled")
+}
+
+impl ChunkAccessor for DispatchingChunkAccessor<'_> {
+    fn read_chunk_into(
+        &self,
+        digest: DigestRef<'_>,
+        data_type: DataType,
+        writer: &mut dyn Write,
+    ) -> io::Result<()> {
+        if data_type == DataType::Index {
+            return self.raw.read_chunk_into(digest, data_type, writer);
+        }
+
+        let i = self.next_i.get();
+        self.window_tx.send(()).map_err(|_| read_cancelled())?;
+        self.digest_tx
+            .send((i, digest.0.to_owned()))
+            .map_err(|_| read_cancelled())?;
+        self.next_i.set(i + 1);
+        Ok(())
+    }
+
+    fn touch(&self, digest: DigestRef<'_>) -> io::Result<()> {
+        self.raw.touch(digest)
+    }
+}
+
+/// Read the data at `data_address` into `writer`, fetching many data chunks
+/// at once
+///
+/// One thread walks the index tree and dispatches the digests of the data
+/// chunks, in order, to `workers` threads reading, decrypting and
+/// decompressing them using `accessor` concurrently. The chunks are put back
+/// in order before being written out (like `SortingIterator` does on the
+/// write path). At most `window` chunks are processed ahead of the one
+/// being written.
+///
+/// Every chunk is read whole and verified before any of it is written, so
+/// nothing past a chunk that fails to read reaches `writer`.
+pub(crate) fn read_pipelined(
+    accessor: &(dyn ChunkAccessor + Sync),
+    data_address: DataAddressRef<'_>,
+    writer: &mut dyn Write,
+    workers: usize,
+    window: usize,
+    log: Logger,
+) -> io::Result<()> {
+    let (window_tx, window_rx) = mpsc::sync_channel(window);
+    let (digest_tx, digest_rx) = crossbeam_channel::bounded(workers);
+    let (chunk_tx, chunk_rx) = crossbeam_channel::unbounded();
+
+    crossbeam::scope(|scope| {
+        let dispatcher = scope.spawn({
+            let log = log.clone();
+            move |_| {
+                let dispatching = DispatchingChunkAccessor {
+                    raw: accessor,
+                    next_i: Cell::new(0),
+                    window_tx,
+                    digest_tx,
+                };
+                ReadContext::new(&dispatching).read_recursively(
+                    ReadRequest::new(
+                        DataType::Data,
+                        data_address,
+                        Some(&mut io::sink()),
+                        log,
+                    ),
+                )
+            }
+        });
+
+        for _ in 0..workers {
+            let digest_rx = digest_rx.clone();
+            let chunk_tx = chunk_tx.clone();
+            scope.spawn(move |_| {
+                for (i, digest) in digest_rx {
+                    let mut data = vec![];
+                    let res = accessor
+                        .read_chunk_into(
+                            DigestRef(&digest),
+                            DataType::Data,
+                            &mut data,
+                        )
+                        .map(|_| data);
+                    if chunk_tx.send((i, res)).is_err() {
+                        return;
+                    }
+                }
+            });
+        }
+        drop(digest_rx);
+        drop(chunk_tx);
+
+        let mut write_res = Ok(());
+        let mut early_chunks = BTreeMap::new();
+        let mut next_i = 0;
+        'chunks: for (i, res) in chunk_rx.iter() {
+            early_chunks.insert(i, res);
+            while let Some(res) = early_chunks.remove(&next_i) {
+                write_res = res.and_then(|data| writer.write_all(&data));
+                if write_res.is_err() {
+                    break 'chunks;
+                }
+                next_i += 1;
+                let _ = window_rx.recv();
+            }
+        }
+        // Make the other threads notice if we're bailing out early
+        drop(chunk_rx);
+        drop(window_rx);
+
+        let dispatch_res = dispatcher.join().expect("dispatcher panicked");
+        write_res?;
+        trace!(log, "pipelined read finished"; "chunks" => next_i);
+        dispatch_res
+    })
+    .expect("read worker panicked")
+}
+
+/// Seekable reader of the data stored under a `DataAddress`
+///
+/// Instead of streaming all the data, it walks down the index tree using the
+/// sizes recorded in `IndexFormat::DigestSize` entries, and reads only the
+/// chunks containing the requested offset.
+pub(crate) struct SeekableReader<A> {
+    accessor: A,
+    address: DataAddress,
+    size: u64,
+    pos: u64,
+    /// Last data chunk read, along with its offset
+    chunk: Option<(u64, Vec<u8>)>,
+    /// Last index chunk read (digest and content) on every level of the
+    /// index tree, starting from the top
+    index_chunks: Vec<Option<(Vec<u8>, Vec<u8>)>>,
+    _lock: Option<Box<dyn Lock>>,
+}
+
+impl<A: ChunkAccessor> SeekableReader<A> {
+    /// Create a reader of data at `address`
+    ///
+    /// `size` is the total size of the data, if known.
+    pub(crate) fn new(
+        accessor: A,
+        address: DataAddress,
+        size: Option<u64>,
+        lock: Option<Box<dyn Lock>>,
+    ) -> io::Result<Self> {
+        if address.index_level > 0
+            && address.index_format != IndexFormat::DigestSize
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "data was stored without chunk sizes and can't be accessed \
+                 randomly; store it again (after upgrading the repository \
+                 with `migrate` if needed) to make it seekable",
+            ));
+        }
+
+        let mut reader = SeekableReader {
+            accessor,
+            index_chunks: vec![None; address.index_level as usize],
+            address,
+            size: 0,
+            pos: 0,
+            chunk: None,
+            _lock: lock,
+        };
+
+        reader.size = match size {
+            Some(size) => size,
+            None if reader.address.index_level == 0 => {
+                let digest = reader.address.digest.clone();
+                reader.read_chunk(digest.as_digest_ref(), DataType::Data, 0)?;
+                reader.chunk.as_ref().map_or(0, |c| c.1.len() as u64)
+            }
+            None => {
+                let entry_size = reader.address.index_format.entry_size();
+                let digest = reader.address.digest.0.clone();
+                reader
+                    .index_chunk(0, &digest)?
+                    .chunks(entry_size)
+                    .map(|entry| {
+                        IndexFormat::DigestSize.parse_entry(entry).1.unwrap()
+                    })
+                    .sum()
+            }
+        };
+
+        Ok(reader)
+    }
+
+    /// Total size of the data
+    #[cfg(all(unix, feature = "fuse"))]
+    pub(crate) fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn read_chunk(
+        &mut self,
+        digest: DigestRef<'_>,
+        data_type: DataType,
+        offset: u64,
+    ) -> io::Result<()> {
+        let mut data = vec![];
+        self.accessor
+            .read_chunk_into(digest, data_type, &mut data)?;
+        self.chunk = Some((offset, data));
+        Ok(())
+    }
+
+    /// Content of the index chunk `digest` on `level` of the index tree
+    fn index_chunk(
+        &mut self,
+        level: usize,
+        digest: &[u8],
+    ) -> io::Result<&[u8]> {
+        let cached = match self.index_chunks[level] {
+            Some((ref cached_digest, _)) => cached_digest == digest,
+            None => false,
+        };
+
+        if !cached {
+            let mut data = vec![];
+            self.accessor.read_chunk_into(
+                DigestRef(digest),
+                DataType::Index,
+                &mut data,
+            )?;
+            if data.len() % self.address.index_format.entry_size() != 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "index chunk {} has partial entries",
+                        hex::encode(digest)
+                    ),
+                ));
+            }
+            self.index_chunks[level] = Some((digest.to_owned(), data));
+        }
+
+        Ok(self.index_chunks[level].as_ref().unwrap().1.as_slice())
+    }
+
+    /// Load the data chunk containing `pos`
+    fn load_chunk_at(&mut self, pos: u64) -> io::Result<()> {
+        let entry_size = self.address.index_format.entry_size();
+        let mut digest = self.address.digest.0.clone();
+        let mut chunk_start = 0;
+        let mut chunk_size = None;
+
+        for level in 0..self.address.index_level as usize {
+            let mut found = None;
+            for entry in self.index_chunk(level, &digest)?.chunks(entry_size) {
+                let (entry_digest, size) =
+                    IndexFormat::DigestSize.parse_entry(entry);
+                let size = size.unwrap();
+                if pos < chunk_start + size {
+                    found = Some((entry_digest.0.to_owned(), size));
+                    break;
+                }
+                chunk_start += size;
+            }
+
+            let (entry_digest, size) = found.ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "index chunk {} is shorter than recorded",
+                        hex::encode(&digest)
+                    ),
+                )
+            })?;
+            digest = entry_digest;
+            chunk_size = Some(size);
+        }
+
+        self.read_chunk(DigestRef(&digest), DataType::Data, chunk_start)?;
+
+        let len = self.chunk.as_ref().map_or(0, |c| c.1.len() as u64);
+        if chunk_size.is_some_and(|size| size != len)
+            || pos >= chunk_start + len
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "data chunk {} has size different than recorded",
+                    hex::encode(&digest)
+                ),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl<A: ChunkAccessor> Read for SeekableReader<A> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() || self.pos >= self.size {
+            return Ok(0);
+        }
+
+        let pos = self.pos;
+        let loaded = match self.chunk {
+            Some((start, ref data)) => {
+                start <= pos && pos < start + data.len() as u64
+            }
+            None => false,
+        };
+        if !loaded {
+            self.load_chunk_at(pos)?;
+        }
+
+        let (start, data) = self.chunk.as_ref().unwrap();
+        let data = &data[(pos - start) as usize..];
+        let len = cmp::min(buf.len(), data.len());
+        buf[..len].copy_from_slice(&data[..len]);
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl<A> Seek for SeekableReader<A> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, offset) = match pos {
+            SeekFrom::Start(n) => {
+                self.pos = n;
+                return Ok(n);
+            }
+            SeekFrom::End(offset) => (self.size, offset),
+            SeekFrom::Current(offset) => (self.pos, offset),
+        };
+
+        match base.checked_add_signed(offset) {
+            Some(n) => {
+                self.pos = n;
+                Ok(n)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )),
+        }
+    }
+}
+
 /// Abstraction over accessing chunks stored in the repository
 pub(crate) trait ChunkAccessor {
     /// Read a chunk identified by `digest` into `writer`
@@ -318,10 +680,18 @@ impl ChunkAccessor for DefaultChunkAccessor<'_> {
         }
 
         let data = data.unwrap();
-        let data = if data_type.should_encrypt() {
+        let data = if self.repo.should_encrypt(data_type) {
             self.decrypter
                 .as_ref()
-                .expect("Decrypter expected")
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::PermissionDenied,
+                        format!(
+                            "can't decrypt chunk {} without the key",
+                            hex::encode(digest.0)
+                        ),
+                    )
+                })?
                 .decrypt(data, digest.0)?
         } else {
             data
@@ -333,7 +703,11 @@ impl ChunkAccessor for DefaultChunkAccessor<'_> {
             data
         };
 
-        let vec_result = self.repo.hasher.calculate_digest(&data);
+        // Keyed digests can't be checked without the key
+        let vec_result = match self.repo.chunk_hasher(self.decrypter.as_ref()) {
+            Some(hasher) => hasher.calculate_digest(&data),
+            None => digest.0.to_vec(),
+        };
 
         if vec_result != digest.0 {
             Err(io::Error::new(
@@ -357,6 +731,113 @@ impl ChunkAccessor for DefaultChunkAccessor<'_> {
     }
 }
 
+/// LRU cache of chunks, limited by the total size of the data held
+pub(crate) struct ChunkCache {
+    inner: Mutex<ChunkCacheInner>,
+}
+
+struct ChunkCacheInner {
+    chunks: lru::LruCache<Vec<u8>, Arc<Vec<u8>>>,
+    size: usize,
+    max_size: usize,
+}
+
+impl ChunkCache {
+    pub(crate) fn new(max_size: usize) -> Self {
+        ChunkCache {
+            inner: Mutex::new(ChunkCacheInner {
+                chunks: lru::LruCache::unbounded(),
+                size: 0,
+                max_size,
+            }),
+        }
+    }
+
+    pub(crate) fn get(&self, digest: &[u8]) -> Option<Arc<Vec<u8>>> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.chunks.get(digest).cloned()
+    }
+
+    pub(crate) fn insert(&self, digest: Vec<u8>, data: Arc<Vec<u8>>) {
+        let mut inner = self.inner.lock().unwrap();
+        if data.len() > inner.max_size {
+            return;
+        }
+
+        inner.size += data.len();
+        if let Some((_, old)) = inner.chunks.push(digest, data) {
+            inner.size -= old.len();
+        }
+        while inner.size > inner.max_size {
+            let (_, evicted) = inner.chunks.pop_lru().expect("cache not empty");
+            inner.size -= evicted.len();
+        }
+    }
+}
+
+/// `ChunkAccessor` that keeps recently read chunks in a `ChunkCache`
+///
+/// Reading them again doesn't touch the backend, nor decrypts or
+/// decompresses them again. It holds a handle of its own to the `Repo`, so
+/// it can be kept around, eg. for a file open in a mount.
+pub(crate) struct CachingChunkAccessor {
+    repo: Repo,
+    decrypter: Option<ArcDecrypter>,
+    compression: ArcCompression,
+    generations: Vec<Generation>,
+    cache: Arc<ChunkCache>,
+}
+
+impl CachingChunkAccessor {
+    pub(crate) fn new(
+        repo: Repo,
+        decrypter: Option<ArcDecrypter>,
+        compression: ArcCompression,
+        generations: Vec<Generation>,
+        cache: Arc<ChunkCache>,
+    ) -> Self {
+        CachingChunkAccessor {
+            repo,
+            decrypter,
+            compression,
+            generations,
+            cache,
+        }
+    }
+
+    fn raw(&self) -> DefaultChunkAccessor<'_> {
+        DefaultChunkAccessor::new(
+            &self.repo,
+            self.decrypter.clone(),
+            Arc::clone(&self.compression),
+            self.generations.clone(),
+        )
+    }
+}
+
+impl ChunkAccessor for CachingChunkAccessor {
+    fn read_chunk_into(
+        &self,
+        digest: DigestRef<'_>,
+        data_type: DataType,
+        writer: &mut dyn Write,
+    ) -> io::Result<()> {
+        if let Some(data) = self.cache.get(digest.0) {
+            return writer.write_all(&data);
+        }
+
+        let mut data = vec![];
+        self.raw().read_chunk_into(digest, data_type, &mut data)?;
+        writer.write_all(&data)?;
+        self.cache.insert(digest.0.into(), Arc::new(data));
+        Ok(())
+    }
+
+    fn touch(&self, digest: DigestRef<'_>) -> io::Result<()> {
+        self.raw().touch(digest)
+    }
+}
+
 /// `ChunkAccessor` that records which chunks
 /// were accessed
 ///
@@ -364,6 +845,8 @@ impl ChunkAccessor for DefaultChunkAccessor<'_> {
 pub(crate) struct RecordingChunkAccessor<'a> {
     raw: DefaultChunkAccessor<'a>,
     accessed: RefCell<&'a mut HashSet<Vec<u8>>>,
+    /// Digests of the index chunks among `accessed`
+    indexes: RefCell<HashSet<Vec<u8>>>,
 }
 
 impl<'a> RecordingChunkAccessor<'a> {
@@ -382,8 +865,14 @@ impl<'a> RecordingChunkAccessor<'a> {
                 generations,
             ),
             accessed: RefCell::new(accessed),
+            indexes: RefCell::new(HashSet::new()),
         }
     }
+
+    /// Digests of the index chunks accessed
+    pub(crate) fn into_indexes(self) -> HashSet<Vec<u8>> {
+        self.indexes.into_inner()
+    }
 }
 
 impl ChunkAccessor for RecordingChunkAccessor<'_> {
@@ -394,6 +883,9 @@ impl ChunkAccessor for RecordingChunkAccessor<'_> {
         writer: &mut dyn Write,
     ) -> io::Result<()> {
         self.touch(digest)?;
+        if data_type == DataType::Index {
+            self.indexes.borrow_mut().insert(digest.0.into());
+        }
         self.raw.read_chunk_into(digest, data_type, writer)
     }
 
@@ -471,6 +963,9 @@ impl ChunkAccessor for VerifyingChunkAccessor<'_> {
 
 /// `ChunkAccessor` that update accessed chunks
 /// to the latest generation
+///
+/// Only index chunks are read, so `decrypter` is needed only if they are
+/// encrypted.
 pub(crate) struct GenerationUpdateChunkAccessor<'a> {
     raw: DefaultChunkAccessor<'a>,
 }
@@ -478,13 +973,14 @@ pub(crate) struct GenerationUpdateChunkAccessor<'a> {
 impl<'a> GenerationUpdateChunkAccessor<'a> {
     pub(crate) fn new(
         repo: &'a Repo,
+        decrypter: Option<ArcDecrypter>,
         compression: ArcCompression,
         generations: Vec<Generation>,
     ) -> Self {
         GenerationUpdateChunkAccessor {
             raw: DefaultChunkAccessor::new(
                 repo,
-                None,
+                decrypter,
                 compression,
                 generations,
             ),
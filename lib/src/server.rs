@@ -0,0 +1,740 @@
+//! HTTP server exposing a local repository to the `http` backend
+//!
+//! Files are served with plain HTTP methods:
+//!
+//! * `GET <path>` - content of a file, or a JSON listing of a directory in the
+//!   format of `static-web-server` (so the repository can be also served
+//!   read-only by it),
+//! * `HEAD <path>` - size and modification time of a file, in the
+//!   `Content-Length` and `Last-Modified` headers,
+//! * `PUT <path>` - write a file atomically; with `If-None-Match: *` an
+//!   already existing file is left untouched,
+//! * `DELETE <path>` - remove a file; with a trailing `/`, remove a directory
+//!   with all its content,
+//! * `MOVE <path>` - rename a file to the path in the `Destination` header.
+//!
+//! Request bodies are held in memory, and limited to 64 MiB.
+//!
+//! Locks are taken on the underlying `Local` backend, so they exclude both
+//! the HTTP clients and any `rdedup` running directly on the server:
+//!
+//! * `POST /.lock/shared`, `POST /.lock/exclusive` - block until the lock is
+//!   acquired and return `{"id": ..., "ttl_secs": ...}`,
+//! * `PUT /.lock/<id>` - refresh a held lock; it is released if not
+//!   refreshed within `ttl_secs`,
+//! * `DELETE /.lock/<id>` - release a held lock.
+//!
+//! There's no authentication; the server is meant to be run in a trusted
+//! network, or behind a reverse proxy taking care of it. It must be served
+//! at the root of the url.
+
+// {{{ use and mod
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::Duration;
+use std::{fs, io, thread};
+
+use percent_encoding::percent_decode_str;
+use rand::distr::Alphanumeric;
+use rand::Rng;
+use serde::Serialize;
+use sgdata::SGData;
+use slog::{debug, info, o, warn, Logger};
+
+use crate::aio::backend::Backend;
+use crate::aio::Local;
+use crate::config;
+// }}}
+
+/// Time for which a lock is held without being refreshed
+const LOCK_TTL: Duration = Duration::from_secs(60);
+
+/// Number of threads handling requests
+///
+/// Requests waiting for a lock don't take any of them.
+const WORKER_THREADS: usize = 16;
+
+/// Time after which an idle kept-alive connection is closed
+///
+/// Longer than the 90s `reqwest` keeps its idle connections for, so the
+/// server never closes one a client is just about to reuse.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Limit of the request line and each of the headers
+const MAX_LINE_LEN: u64 = 8 * 1024;
+
+/// Limit of the number of headers of a request
+const MAX_HEADERS: usize = 100;
+
+/// Limit of a request body, which is held in memory
+///
+/// Way above the size of the chunks of a repository with sensible chunking
+/// settings (128 KiB on average by default), while keeping a single
+/// request from taking all the memory.
+const MAX_BODY_LEN: u64 = 64 * 1024 * 1024;
+
+/// Limit of the number of connections served at once
+///
+/// Further connections wait to be accepted until one of them is closed.
+const MAX_CONNECTIONS: usize = 256;
+
+#[derive(Serialize)]
+struct FileInfo {
+    name: String,
+    mtime: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    size: Option<u64>,
+    #[serde(rename = "type")]
+    file_type: &'static str,
+}
+
+#[derive(Serialize)]
+struct LockInfo {
+    id: String,
+    ttl_secs: u64,
+}
+
+/// Locks held on behalf of the clients, by id
+///
+/// Every lock is held by its own thread; the `Sender` is used to refresh
+/// it, and dropping it releases the lock.
+type Locks = Arc<Mutex<HashMap<String, mpsc::Sender<()>>>>;
+
+struct Request {
+    method: String,
+    url: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+    keep_alive: bool,
+}
+
+impl Request {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(field, _)| field.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+enum Body {
+    Empty,
+    Data(Vec<u8>),
+    File(fs::File, u64),
+}
+
+struct Response {
+    status: u16,
+    headers: Vec<(&'static str, String)>,
+    body: Body,
+}
+
+impl Response {
+    fn empty(status: u16) -> Self {
+        Response {
+            status,
+            headers: vec![],
+            body: Body::Empty,
+        }
+    }
+
+    fn data(status: u16, data: Vec<u8>) -> Self {
+        Response {
+            status,
+            headers: vec![],
+            body: Body::Data(data),
+        }
+    }
+
+    fn with_header(mut self, name: &'static str, value: String) -> Self {
+        self.headers.push((name, value));
+        self
+    }
+
+    fn len(&self) -> u64 {
+        match self.body {
+            Body::Empty => 0,
+            Body::Data(ref data) => data.len() as u64,
+            Body::File(_, len) => len,
+        }
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        412 => "Precondition Failed",
+        413 => "Content Too Large",
+        501 => "Not Implemented",
+        _ => "Internal Server Error",
+    }
+}
+
+/// A request handed to the workers, with the way to answer it
+struct Job {
+    request: Request,
+    responder: Responder,
+}
+
+/// Sends the response back to the connection the request came from
+struct Responder {
+    tx: mpsc::Sender<Response>,
+    log: Logger,
+}
+
+impl Responder {
+    fn send(self, response: io::Result<Response>) {
+        let response = match response {
+            Ok(response) => response,
+            Err(e) => {
+                if e.kind() != io::ErrorKind::NotFound {
+                    warn!(self.log, "request failed"; "err" => %e);
+                }
+                error_response(&e)
+            }
+        };
+        debug!(self.log, "responding"; "status" => response.status);
+        // The connection is gone if it fails, nothing else to do
+        let _ = self.tx.send(response);
+    }
+}
+
+/// Server of a local repository
+pub struct Server {
+    listener: TcpListener,
+    root: PathBuf,
+    backend: Arc<Local>,
+    locks: Locks,
+    log: Logger,
+}
+
+impl Server {
+    /// Bind to `addr`, serving the repository at `root`
+    pub fn bind(
+        root: PathBuf,
+        addr: impl ToSocketAddrs,
+        log: Logger,
+    ) -> io::Result<Self> {
+        Ok(Server {
+            listener: TcpListener::bind(addr)?,
+            backend: Arc::new(Local::new(root.clone())),
+            root,
+            locks: Arc::new(Mutex::new(HashMap::new())),
+            log,
+        })
+    }
+
+    /// Address the server is listening on
+    pub fn local_addr(&self) -> SocketAddr {
+        self.listener
+            .local_addr()
+            .expect("bound listener has an address")
+    }
+
+    /// Serve requests forever
+    ///
+    /// Every connection is read by its own thread, up to `MAX_CONNECTIONS`
+    /// of them, and the requests are handled by a fixed pool of
+    /// `WORKER_THREADS` workers.
+    pub fn run(&self) {
+        info!(self.log, "serving repository";
+              "dir" => self.root.display(), "addr" => %self.local_addr());
+        // Every connection waits for the response to its request before
+        // sending another one
+        let (job_tx, job_rx) =
+            crossbeam_channel::bounded::<Job>(MAX_CONNECTIONS);
+        // Holds an item for every connection being served
+        let (slot_tx, slot_rx) = crossbeam_channel::bounded(MAX_CONNECTIONS);
+        for _ in 0..WORKER_THREADS {
+            let job_rx = job_rx.clone();
+            let handler = Handler {
+                root: self.root.clone(),
+                backend: Arc::clone(&self.backend),
+                locks: Arc::clone(&self.locks),
+            };
+            thread::spawn(move || {
+                for job in job_rx {
+                    handler.handle(job.request, job.responder);
+                }
+            });
+        }
+
+        for stream in self.listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    warn!(self.log, "couldn't accept connection"; "err" => %e);
+                    continue;
+                }
+            };
+            slot_tx.send(()).expect("slots are never closed");
+            let job_tx = job_tx.clone();
+            let slot_rx = slot_rx.clone();
+            let log = self.log.clone();
+            thread::spawn(move || {
+                if let Err(e) = serve_connection(stream, &job_tx, &log) {
+                    debug!(log, "connection failed"; "err" => %e);
+                }
+                let _ = slot_rx.recv();
+            });
+        }
+    }
+}
+
+/// Read requests from a connection and write back their responses, until
+/// the client closes it or it stays idle for `IDLE_TIMEOUT`
+fn serve_connection(
+    stream: TcpStream,
+    job_tx: &crossbeam_channel::Sender<Job>,
+    log: &Logger,
+) -> io::Result<()> {
+    stream.set_read_timeout(Some(IDLE_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = io::BufWriter::new(stream);
+    loop {
+        let request = match read_request(&mut reader, &mut writer) {
+            Ok(Some(request)) => request,
+            Ok(None) => return Ok(()),
+            Err(ref e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                return Ok(());
+            }
+            Err(e) => {
+                let status = match e.kind() {
+                    io::ErrorKind::FileTooLarge => 413,
+                    _ => 400,
+                };
+                let response =
+                    Response::data(status, e.to_string().into_bytes());
+                write_response(&mut writer, response, false, false)?;
+                return Err(e);
+            }
+        };
+
+        let (tx, rx) = mpsc::channel();
+        let keep_alive = request.keep_alive;
+        let head = request.method == "HEAD";
+        let responder = Responder {
+            tx,
+            log: log.new(o!(
+                "method" => request.method.clone(),
+                "url" => request.url.clone(),
+            )),
+        };
+        job_tx
+            .send(Job { request, responder })
+            .expect("workers never stop");
+        let response = rx.recv().unwrap_or_else(|_| Response::empty(500));
+        write_response(&mut writer, response, head, keep_alive)?;
+        if !keep_alive {
+            return Ok(());
+        }
+    }
+}
+
+/// Read a line of the request head, without the line ending
+fn read_line(reader: &mut impl BufRead) -> io::Result<Option<String>> {
+    let mut line = String::new();
+    if (&mut *reader).take(MAX_LINE_LEN).read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    if !line.ends_with('\n') {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "request line too long or incomplete",
+        ));
+    }
+    Ok(Some(line.trim_end_matches(['\r', '\n']).to_owned()))
+}
+
+/// Read the next request of a connection
+///
+/// Returns `None` if the client closed the connection instead.
+fn read_request(
+    reader: &mut impl BufRead,
+    writer: &mut impl Write,
+) -> io::Result<Option<Request>> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg);
+
+    let request_line = loop {
+        match read_line(reader)? {
+            None => return Ok(None),
+            // Empty lines are allowed before a request
+            Some(line) if line.is_empty() => continue,
+            Some(line) => break line,
+        }
+    };
+    let mut parts = request_line.split(' ');
+    let (method, url, version) =
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some(method), Some(url), Some(version))
+                if parts.next().is_none() =>
+            {
+                (method.to_owned(), url.to_owned(), version)
+            }
+            _ => return Err(invalid("malformed request line")),
+        };
+    if !version.starts_with("HTTP/1.") {
+        return Err(invalid("unsupported HTTP version"));
+    }
+
+    let mut headers = vec![];
+    loop {
+        let line = read_line(reader)?
+            .ok_or_else(|| invalid("connection closed in headers"))?;
+        if line.is_empty() {
+            break;
+        }
+        if headers.len() == MAX_HEADERS {
+            return Err(invalid("too many headers"));
+        }
+        let (field, value) = line
+            .split_once(':')
+            .ok_or_else(|| invalid("malformed header"))?;
+        headers.push((field.trim().to_owned(), value.trim().to_owned()));
+    }
+
+    let mut request = Request {
+        method,
+        url,
+        headers,
+        body: vec![],
+        keep_alive: version != "HTTP/1.0",
+    };
+    if let Some(connection) = request.header("Connection") {
+        let connection = connection.to_ascii_lowercase();
+        if connection.contains("close") {
+            request.keep_alive = false;
+        } else if connection.contains("keep-alive") {
+            request.keep_alive = true;
+        }
+    }
+    if request.header("Transfer-Encoding").is_some() {
+        return Err(invalid("chunked requests are not supported"));
+    }
+    let len = match request.header("Content-Length") {
+        Some(len) => len
+            .parse::<u64>()
+            .map_err(|_| invalid("invalid Content-Length"))?,
+        None => 0,
+    };
+    if len > MAX_BODY_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::FileTooLarge,
+            format!("request body over {} bytes", MAX_BODY_LEN),
+        ));
+    }
+    if len > 0 {
+        if request
+            .header("Expect")
+            .is_some_and(|v| v.eq_ignore_ascii_case("100-continue"))
+        {
+            writer.write_all(b"HTTP/1.1 100 Continue\r\n\r\n")?;
+            writer.flush()?;
+        }
+        (&mut *reader).take(len).read_to_end(&mut request.body)?;
+        if request.body.len() as u64 != len {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+    }
+    Ok(Some(request))
+}
+
+/// Write a response; the body is left out for `HEAD` requests
+fn write_response(
+    writer: &mut impl Write,
+    response: Response,
+    head: bool,
+    keep_alive: bool,
+) -> io::Result<()> {
+    write!(
+        writer,
+        "HTTP/1.1 {} {}\r\nContent-Length: {}\r\n",
+        response.status,
+        reason(response.status),
+        response.len()
+    )?;
+    for (name, value) in &response.headers {
+        write!(writer, "{}: {}\r\n", name, value)?;
+    }
+    if !keep_alive {
+        writer.write_all(b"Connection: close\r\n")?;
+    }
+    writer.write_all(b"\r\n")?;
+    if !head {
+        match response.body {
+            Body::Empty => {}
+            Body::Data(data) => writer.write_all(&data)?,
+            Body::File(file, len) => {
+                // Must match the length announced in the head
+                if io::copy(&mut file.take(len), writer)? != len {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+            }
+        }
+    }
+    writer.flush()
+}
+
+struct Handler {
+    root: PathBuf,
+    backend: Arc<Local>,
+    locks: Locks,
+}
+
+fn error_response(e: &io::Error) -> Response {
+    let status = match e.kind() {
+        io::ErrorKind::NotFound => 404,
+        io::ErrorKind::InvalidInput => 400,
+        io::ErrorKind::PermissionDenied => 403,
+        _ => 500,
+    };
+    Response::data(status, e.to_string().into_bytes())
+}
+
+fn json_response<T: Serialize>(value: &T) -> io::Result<Response> {
+    Ok(Response::data(200, serde_json::to_vec(value)?)
+        .with_header("Content-Type", "application/json".into()))
+}
+
+/// Format a time as an HTTP date
+fn http_date(time: std::time::SystemTime) -> String {
+    let time: chrono::DateTime<chrono::Utc> = time.into();
+    time.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+/// Convert the path part of a request url to a path in the repository
+///
+/// Only plain relative paths are accepted, so nothing outside of the
+/// repository can be accessed.
+fn repo_path(url: &str) -> io::Result<PathBuf> {
+    let url_path = url.split(['?', '#']).next().unwrap_or_default();
+    let decoded = percent_decode_str(url_path).decode_utf8().map_err(|e| {
+        io::Error::new(io::ErrorKind::InvalidInput, e.to_string())
+    })?;
+    let path = Path::new(decoded.trim_start_matches('/'));
+    if !path.components().all(|c| matches!(c, Component::Normal(_))) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid path: {}", decoded),
+        ));
+    }
+    Ok(path.to_owned())
+}
+
+fn random_string() -> String {
+    rand::rng()
+        .sample_iter(&Alphanumeric)
+        .take(20)
+        .map(char::from)
+        .collect()
+}
+
+impl Handler {
+    fn handle(&self, request: Request, responder: Responder) {
+        // Waiting for a lock mustn't hold up a worker
+        match lock_mode(&request) {
+            Some(exclusive) => self.lock(exclusive, responder),
+            None => responder.send(self.respond(&request)),
+        }
+    }
+
+    fn respond(&self, request: &Request) -> io::Result<Response> {
+        let path = repo_path(&request.url)?;
+        if path.starts_with(config::LOCK_FILE) {
+            return self.respond_lock(request, &path);
+        }
+
+        let mut thread = self.backend.new_thread()?;
+
+        match request.method.as_str() {
+            // The body is left out when writing the response
+            "GET" | "HEAD" => self.get(&path),
+            "PUT" => {
+                let idempotent = request
+                    .header("If-None-Match")
+                    .is_some_and(|v| v.trim() == "*");
+                let data = request.body.clone();
+                thread.write(path, SGData::from_single(data), idempotent)?;
+                Ok(Response::empty(204))
+            }
+            "DELETE" => {
+                if request.url.ends_with('/') {
+                    thread.remove_dir_all(path)?;
+                } else {
+                    thread.remove(path)?;
+                }
+                Ok(Response::empty(204))
+            }
+            "MOVE" => {
+                let dst = request
+                    .header("Destination")
+                    .ok_or_else(|| {
+                        io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            "Destination header missing",
+                        )
+                    })
+                    .and_then(|dst| {
+                        url::Url::parse(dst).map_err(|e| {
+                            io::Error::new(
+                                io::ErrorKind::InvalidInput,
+                                format!("invalid Destination: {}", e),
+                            )
+                        })
+                    })?;
+                let dst = repo_path(dst.path())?;
+                if !self.root.join(&path).is_file() {
+                    return Err(io::ErrorKind::NotFound.into());
+                }
+                thread.rename(path, dst)?;
+                Ok(Response::empty(204))
+            }
+            _ => Ok(Response::empty(405)),
+        }
+    }
+
+    fn get(&self, path: &Path) -> io::Result<Response> {
+        let full_path = self.root.join(path);
+        let md = fs::metadata(&full_path)?;
+
+        if md.is_file() {
+            let content_type = if path.extension().is_some_and(|e| e == "yml") {
+                "text/x-yaml"
+            } else {
+                "application/octet-stream"
+            };
+            let file = fs::File::open(&full_path)?;
+            return Ok(Response {
+                status: 200,
+                headers: vec![
+                    ("Content-Type", content_type.into()),
+                    ("Last-Modified", http_date(md.modified()?)),
+                ],
+                body: Body::File(file, md.len()),
+            });
+        }
+
+        let mut list = vec![];
+        for entry in fs::read_dir(&full_path)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if path.as_os_str().is_empty() && name == config::LOCK_FILE {
+                continue;
+            }
+            let md = match entry.metadata() {
+                Ok(md) => md,
+                // removed in the meantime
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            let mtime: chrono::DateTime<chrono::Utc> = md.modified()?.into();
+            list.push(FileInfo {
+                name,
+                mtime: mtime.to_rfc3339(),
+                size: md.is_file().then_some(md.len()),
+                file_type: if md.is_file() { "file" } else { "directory" },
+            });
+        }
+        json_response(&list)
+    }
+
+    fn respond_lock(
+        &self,
+        request: &Request,
+        path: &Path,
+    ) -> io::Result<Response> {
+        let name = path
+            .strip_prefix(config::LOCK_FILE)
+            .expect("checked by the caller")
+            .to_string_lossy()
+            .into_owned();
+
+        match (request.method.as_str(), name.as_str()) {
+            ("PUT", id) => {
+                let refreshed = self
+                    .locks
+                    .lock()
+                    .unwrap()
+                    .get(id)
+                    .is_some_and(|tx| tx.send(()).is_ok());
+                if !refreshed {
+                    return Err(io::Error::new(
+                        io::ErrorKind::NotFound,
+                        "lock not held",
+                    ));
+                }
+                Ok(Response::empty(204))
+            }
+            ("DELETE", id) => {
+                if self.locks.lock().unwrap().remove(id).is_none() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::NotFound,
+                        "lock not held",
+                    ));
+                }
+                Ok(Response::empty(204))
+            }
+            _ => Ok(Response::empty(405)),
+        }
+    }
+
+    /// Acquire a lock in a thread of its own, responding once it's held
+    fn lock(&self, exclusive: bool, responder: Responder) {
+        let backend = Arc::clone(&self.backend);
+        let locks = Arc::clone(&self.locks);
+        thread::spawn(move || {
+            let lock = if exclusive {
+                backend.lock_exclusive()
+            } else {
+                backend.lock_shared()
+            };
+            let lock = match lock {
+                Ok(lock) => lock,
+                Err(e) => return responder.send(Err(e)),
+            };
+            let id = random_string();
+            let (refresh_tx, refresh_rx) = mpsc::channel();
+            // Register before replying, so the client can refresh
+            locks.lock().unwrap().insert(id.clone(), refresh_tx);
+            let log = responder.log.clone();
+            responder.send(json_response(&LockInfo {
+                id: id.clone(),
+                ttl_secs: LOCK_TTL.as_secs(),
+            }));
+
+            while let Ok(()) = refresh_rx.recv_timeout(LOCK_TTL) {}
+            if locks.lock().unwrap().remove(&id).is_some() {
+                warn!(log, "lock expired"; "id" => &id);
+            }
+            drop(lock);
+        });
+    }
+}
+
+/// Whether the request is to acquire an exclusive or a shared lock
+fn lock_mode(request: &Request) -> Option<bool> {
+    if request.method != "POST" {
+        return None;
+    }
+    let path = repo_path(&request.url).ok()?;
+    match path.strip_prefix(config::LOCK_FILE).ok()?.to_str()? {
+        "shared" => Some(false),
+        "exclusive" => Some(true),
+        _ => None,
+    }
+}
+
+// vim: foldmethod=marker foldmarker={{{,}}}
@@ -13,6 +13,18 @@ pub enum Compression {
     Bzip2,
     #[cfg(feature = "with-zstd")]
     Zstd,
+    /// Zstd, at a level between `min_level` and `max_level` adjusted while
+    /// writing, to the speed of the backend: the slower it is compared to
+    /// compression, the higher the level
+    #[cfg(feature = "with-zstd")]
+    Auto {
+        min_level: i32,
+        max_level: i32,
+    },
+    #[cfg(feature = "with-lz4")]
+    Lz4,
+    #[cfg(feature = "with-brotli")]
+    Brotli,
     None,
 }
 
@@ -26,6 +38,13 @@ impl Default for Compression {
 }
 
 impl Compression {
+    /// Default bounds of the level of `Auto`
+    #[cfg(feature = "with-zstd")]
+    pub const AUTO_MIN_LEVEL: i32 = 1;
+    #[cfg(feature = "with-zstd")]
+    pub const AUTO_MAX_LEVEL: i32 = 19;
+
+    /// The level is ignored by `Auto`
     pub fn to_config(&self, _level: i32) -> config::Compression {
         match *self {
             #[cfg(feature = "with-deflate")]
@@ -44,6 +63,21 @@ impl Compression {
             Compression::Zstd => {
                 config::Compression::Zstd(config::Zstd::new(_level))
             }
+            #[cfg(feature = "with-zstd")]
+            Compression::Auto {
+                min_level,
+                max_level,
+            } => config::Compression::Auto(config::Auto::new(
+                min_level, max_level,
+            )),
+            #[cfg(feature = "with-lz4")]
+            Compression::Lz4 => {
+                config::Compression::Lz4(config::Lz4::new(_level))
+            }
+            #[cfg(feature = "with-brotli")]
+            Compression::Brotli => {
+                config::Compression::Brotli(config::Brotli::new(_level))
+            }
             Compression::None => config::Compression::None,
         }
     }
@@ -52,6 +86,9 @@ impl Compression {
 #[derive(Clone, Default)]
 pub enum Encryption {
     Curve25519,
+    /// Symmetric, with the chunk digests keyed too; requires `Blake2b`
+    /// hashing
+    XChaCha20Poly1305,
     #[default]
     None,
 }
@@ -79,6 +116,58 @@ impl From<&'_ str> for PWHash {
     }
 }
 
+/// Algorithm deriving keys from passphrases
+#[derive(Clone, Copy, Default)]
+pub enum PWHashAlg {
+    /// `scryptsalsa208sha256`
+    #[default]
+    Scrypt,
+    Argon2id,
+}
+
+impl From<&'_ str> for PWHashAlg {
+    fn from(s: &str) -> Self {
+        match s {
+            "scrypt" => PWHashAlg::Scrypt,
+            "argon2id" => PWHashAlg::Argon2id,
+            _ => panic!("Wrong pwhash algorithm string"),
+        }
+    }
+}
+
+/// Passphrase hashing: the algorithm, with its limits set by the strength,
+/// unless given explicitly
+#[derive(Clone, Default)]
+pub struct PWHashSettings {
+    pub(crate) alg: PWHashAlg,
+    pub(crate) strength: PWHash,
+    pub(crate) mem_limit: Option<u64>,
+    pub(crate) ops_limit: Option<u64>,
+}
+
+impl PWHashSettings {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn set_alg(&mut self, alg: PWHashAlg) {
+        self.alg = alg;
+    }
+
+    pub fn set_strength(&mut self, strength: PWHash) {
+        self.strength = strength;
+    }
+
+    /// Set the memory limit, in bytes
+    pub fn set_mem_limit(&mut self, mem_limit: u64) {
+        self.mem_limit = Some(mem_limit);
+    }
+
+    pub fn set_ops_limit(&mut self, ops_limit: u64) {
+        self.ops_limit = Some(ops_limit);
+    }
+}
+
 #[derive(Clone)]
 pub struct Nesting(u8);
 impl Default for Nesting {
@@ -88,6 +177,16 @@ impl Default for Nesting {
 }
 
 impl Nesting {
+    fn new(level: u8) -> super::Result<Self> {
+        if level > 31 {
+            return Err(super::Error::new(
+                io::ErrorKind::InvalidInput,
+                "nesting can't be greater than or equal to 32",
+            ));
+        }
+        Ok(Nesting(level))
+    }
+
     pub fn to_config(&self) -> config::Nesting {
         config::Nesting(self.0)
     }
@@ -111,13 +210,14 @@ impl Hashing {
 
 #[derive(Clone, Default)]
 pub struct Repo {
-    pub(crate) pwhash: PWHash,
+    pub(crate) pwhash: PWHashSettings,
     pub(crate) encryption: Encryption,
     pub(crate) compression: Compression,
     pub(crate) compression_level: i32,
     pub(crate) chunking: Chunking,
     pub(crate) nesting: Nesting,
     pub(crate) hashing: Hashing,
+    pub(crate) encrypt_index: bool,
 }
 
 impl Repo {
@@ -138,8 +238,30 @@ impl Repo {
         Ok(())
     }
 
+    /// Encrypt index chunks and name files too, hiding the structure of the
+    /// stored data (sizes, shared chunks, history)
+    ///
+    /// Requires encryption. The passphrase is then needed by all the
+    /// operations reading them, including `gc`.
+    pub fn set_encrypt_index(&mut self, encrypt_index: bool) {
+        self.encrypt_index = encrypt_index;
+    }
+
     pub fn set_pwhash(&mut self, pwhash: PWHash) {
-        self.pwhash = pwhash;
+        self.pwhash.set_strength(pwhash);
+    }
+
+    pub fn set_pwhash_alg(&mut self, alg: PWHashAlg) {
+        self.pwhash.set_alg(alg);
+    }
+
+    /// Set the memory limit of pwhash, in bytes
+    pub fn set_pwhash_mem_limit(&mut self, mem_limit: u64) {
+        self.pwhash.set_mem_limit(mem_limit);
+    }
+
+    pub fn set_pwhash_ops_limit(&mut self, ops_limit: u64) {
+        self.pwhash.set_ops_limit(ops_limit);
     }
 
     pub fn set_compression_level(&mut self, level: i32) {
@@ -200,13 +322,44 @@ impl Repo {
     }
 
     pub fn set_nesting(&mut self, level: u8) -> super::Result<()> {
-        if level > 31 {
-            return Err(super::Error::new(
-                io::ErrorKind::InvalidInput,
-                "nesting can't be greater than or equal to 32",
-            ));
-        }
-        self.nesting = Nesting(level);
+        self.nesting = Nesting::new(level)?;
+        Ok(())
+    }
+}
+
+/// Settings changed by `Repo::migrate`
+///
+/// The ones not set are kept as they are.
+#[derive(Clone, Default)]
+pub struct Migration {
+    pub(crate) compression: Option<Compression>,
+    pub(crate) compression_level: Option<i32>,
+    pub(crate) encryption: Option<Encryption>,
+    pub(crate) nesting: Option<Nesting>,
+}
+
+impl Migration {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Change the compression, with level 0 unless set too
+    pub fn set_compression(&mut self, compression: Compression) {
+        self.compression = Some(compression);
+    }
+
+    pub fn set_compression_level(&mut self, level: i32) {
+        self.compression_level = Some(level);
+    }
+
+    /// Change the encryption; new keys are generated only if the encryption
+    /// is enabled by the change
+    pub fn set_encryption(&mut self, encryption: Encryption) {
+        self.encryption = Some(encryption);
+    }
+
+    pub fn set_nesting(&mut self, level: u8) -> super::Result<()> {
+        self.nesting = Some(Nesting::new(level)?);
         Ok(())
     }
 }
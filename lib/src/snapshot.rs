@@ -0,0 +1,450 @@
+//! Built-in directory snapshots
+//!
+//! Every regular file of a snapshot is stored as its own stream of data,
+//! with its own address, so files deduplicate on their own, no matter what
+//! else is in the snapshot, and each of them can be read without reading
+//! the rest. The tree describes every entry of the directory (paths, modes,
+//! ownership, mtimes, symlink targets, xattrs) along with the address of the
+//! content of every regular file. The tree is stored as data too, so it's
+//! encrypted like any other data, and the name of a snapshot records its
+//! address.
+//!
+//! GC and everything else traversing indexes can't decrypt the tree, so the
+//! index of the name itself joins the tree and the content of all files.
+//! This way `gc`, `verify`, `du` and others see everything a snapshot
+//! consists of, and reading a snapshot name as regular data gives:
+//!
+//! ```norust
+//! [magic][tree length: u64 LE][tree][file 1 content][file 2 content]...
+//! ```
+// {{{ use and mod
+use std::fs;
+use std::io::{self, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
+use std::sync::mpsc;
+
+use serde::{Deserialize, Serialize};
+use slog::{debug, warn, Logger};
+use walkdir::WalkDir;
+
+use crate::util::{as_base64, from_base64};
+use crate::{DataAddress, StoredAddress, StreamWriter, INGRESS_BUFFER_SIZE};
+// }}}
+
+const MAGIC: &[u8; 8] = b"rdsnap\x00\x01";
+const HEADER_SIZE: usize = 16;
+
+/// Extended attribute of an `Entry`
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Xattr {
+    #[serde(serialize_with = "as_base64", deserialize_with = "from_base64")]
+    name: Vec<u8>,
+    #[serde(serialize_with = "as_base64", deserialize_with = "from_base64")]
+    value: Vec<u8>,
+}
+
+/// Type specific part of an `Entry`
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(tag = "type")]
+pub(crate) enum EntryKind {
+    #[serde(rename = "dir")]
+    Dir,
+    /// Regular file of `len` bytes, stored at `content` (unless empty)
+    #[serde(rename = "file")]
+    File {
+        len: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        content: Option<StoredAddress>,
+    },
+    #[serde(rename = "symlink")]
+    Symlink {
+        #[serde(
+            serialize_with = "as_base64",
+            deserialize_with = "from_base64"
+        )]
+        target: Vec<u8>,
+    },
+}
+
+/// Single entry (file, directory, ...) of a snapshot
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Entry {
+    /// Path relative to the snapshot root; empty for the root itself
+    #[serde(serialize_with = "as_base64", deserialize_with = "from_base64")]
+    path: Vec<u8>,
+    #[serde(flatten)]
+    kind: EntryKind,
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    mtime: i64,
+    mtime_nsec: u32,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    xattrs: Vec<Xattr>,
+}
+
+impl Entry {
+    fn rel_path(&self) -> io::Result<PathBuf> {
+        let path = PathBuf::from(std::ffi::OsStr::from_bytes(&self.path));
+        // Never let a (potentially malicious) tree write outside of the
+        // restore directory
+        if path
+            .components()
+            .any(|c| !matches!(c, Component::Normal(_)))
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid path in snapshot: {}", path.display()),
+            ));
+        }
+        Ok(path)
+    }
+}
+
+/// Description of all the entries of a snapshot
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub(crate) struct Tree {
+    entries: Vec<Entry>,
+}
+
+fn read_xattrs(path: &Path) -> Vec<Xattr> {
+    let names = match xattr::list(path) {
+        Ok(names) => names,
+        // Not supported by the platform/filesystem
+        Err(_) => return vec![],
+    };
+
+    names
+        .filter_map(|name| {
+            xattr::get(path, &name).ok().flatten().map(|value| Xattr {
+                name: name.as_bytes().to_vec(),
+                value,
+            })
+        })
+        .collect()
+}
+
+impl Tree {
+    /// Walk `root` and describe everything found inside
+    pub(crate) fn from_dir(root: &Path, log: &Logger) -> io::Result<Self> {
+        let mut entries = vec![];
+
+        for dir_entry in
+            WalkDir::new(root).follow_links(false).sort_by_file_name()
+        {
+            let dir_entry = dir_entry?;
+            let path = dir_entry.path();
+            let md = dir_entry.metadata()?;
+            let file_type = md.file_type();
+
+            let kind = if file_type.is_dir() {
+                EntryKind::Dir
+            } else if file_type.is_file() {
+                EntryKind::File {
+                    len: md.len(),
+                    content: None,
+                }
+            } else if file_type.is_symlink() {
+                EntryKind::Symlink {
+                    target: fs::read_link(path)?
+                        .as_os_str()
+                        .as_bytes()
+                        .to_vec(),
+                }
+            } else {
+                warn!(log, "skipping unsupported file type"; "path" => %path.display());
+                continue;
+            };
+
+            let rel_path = path
+                .strip_prefix(root)
+                .expect("walkdir returned path outside of the root");
+
+            entries.push(Entry {
+                path: rel_path.as_os_str().as_bytes().to_vec(),
+                kind,
+                mode: md.mode(),
+                uid: md.uid(),
+                gid: md.gid(),
+                mtime: md.mtime(),
+                mtime_nsec: md.mtime_nsec() as u32,
+                xattrs: read_xattrs(path),
+            });
+        }
+
+        Ok(Tree { entries })
+    }
+
+    /// Serialize with the header, as stored
+    fn to_data(&self) -> io::Result<Vec<u8>> {
+        let tree_json = serde_json::to_vec(self).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("couldn't serialize snapshot tree: {}", e),
+            )
+        })?;
+
+        let mut data = Vec::with_capacity(HEADER_SIZE + tree_json.len());
+        data.extend_from_slice(MAGIC);
+        data.extend_from_slice(&(tree_json.len() as u64).to_le_bytes());
+        data.extend_from_slice(&tree_json);
+        Ok(data)
+    }
+
+    /// Parse and validate a tree stored by `to_data`
+    pub(crate) fn from_data(data: &[u8]) -> io::Result<Self> {
+        if data.len() < HEADER_SIZE || &data[..MAGIC.len()] != MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "name does not contain a snapshot",
+            ));
+        }
+
+        let mut len_bytes = [0u8; 8];
+        len_bytes.copy_from_slice(&data[MAGIC.len()..HEADER_SIZE]);
+        if u64::from_le_bytes(len_bytes) != (data.len() - HEADER_SIZE) as u64 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "snapshot tree has wrong length",
+            ));
+        }
+
+        let tree: Tree =
+            serde_json::from_slice(&data[HEADER_SIZE..]).map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("couldn't parse snapshot tree: {}", e),
+                )
+            })?;
+
+        for entry in &tree.entries {
+            let path = entry.rel_path()?;
+            if let EntryKind::File { len, content: None } = entry.kind {
+                if len != 0 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!(
+                            "no content of file in snapshot: {}",
+                            path.display()
+                        ),
+                    ));
+                }
+            }
+        }
+
+        Ok(tree)
+    }
+
+    /// Find the content of the regular file at `path`
+    ///
+    /// Returns `None` for an empty file.
+    pub(crate) fn file_content(
+        &self,
+        path: &Path,
+    ) -> io::Result<Option<&StoredAddress>> {
+        for entry in &self.entries {
+            if entry.rel_path()? != path {
+                continue;
+            }
+            return match entry.kind {
+                EntryKind::File { ref content, .. } => Ok(content.as_ref()),
+                _ => Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("not a regular file: {}", path.display()),
+                )),
+            };
+        }
+
+        Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no such file in snapshot: {}", path.display()),
+        ))
+    }
+}
+
+fn send_data(tx: &mpsc::SyncSender<Vec<u8>>, data: Vec<u8>) -> io::Result<()> {
+    tx.send(data).map_err(|_| {
+        io::Error::new(io::ErrorKind::BrokenPipe, "chunker tx channel closed")
+    })
+}
+
+/// Send `len` bytes of the file at `path` to the chunker
+///
+/// A file that changed its size in the meantime is truncated or padded with
+/// zeros, and a file that disappeared is stored as zeros, similarly to what
+/// `tar` does.
+fn send_file(
+    path: &Path,
+    len: u64,
+    tx: mpsc::SyncSender<Vec<u8>>,
+    log: &Logger,
+) -> io::Result<()> {
+    let mut left = len;
+    match fs::File::open(path) {
+        Ok(file) => {
+            let mut file = file.take(len);
+            loop {
+                let mut buf = vec![0u8; INGRESS_BUFFER_SIZE];
+                let read = file.read(&mut buf)?;
+                if read == 0 {
+                    break;
+                }
+                buf.truncate(read);
+                left -= read as u64;
+                send_data(&tx, buf)?;
+            }
+        }
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+            warn!(log, "file removed before it could be read"; "path" => %path.display());
+        }
+        Err(e) => return Err(e),
+    }
+
+    if left != 0 {
+        warn!(log, "file shrunk while being read; padding with zeros"; "path" => %path.display());
+    }
+    while left != 0 {
+        let size = std::cmp::min(left, INGRESS_BUFFER_SIZE as u64);
+        send_data(&tx, vec![0u8; size as usize])?;
+        left -= size;
+    }
+    Ok(())
+}
+
+/// Store the snapshot of `root` described by `tree` with `writer`
+///
+/// Files are read with the length recorded in the `tree` (see
+/// `send_file`). Returns the address and size of the data joining the tree
+/// and the content of all files, along with the address of the tree.
+pub(crate) fn write(
+    root: &Path,
+    mut tree: Tree,
+    writer: &StreamWriter<'_>,
+    log: &Logger,
+) -> io::Result<(DataAddress, u64, DataAddress)> {
+    let mut parts = vec![];
+
+    for entry in &mut tree.entries {
+        let path = root.join(entry.rel_path()?);
+        let (len, content) = match entry.kind {
+            EntryKind::File {
+                len,
+                ref mut content,
+            } if len != 0 => (len, content),
+            _ => continue,
+        };
+
+        debug!(log, "storing file"; "path" => %path.display());
+        let (address, size) =
+            writer.write_stream(|tx| send_file(&path, len, tx, log))?;
+        *content = Some(address.clone().into());
+        parts.push((address, size));
+    }
+
+    let tree_data = tree.to_data()?;
+    let (tree_address, tree_size) =
+        writer.write_stream(move |tx| send_data(&tx, tree_data))?;
+    parts.insert(0, (tree_address.clone(), tree_size));
+
+    let (address, size) = writer.write_joined(parts)?;
+    Ok((address, size, tree_address))
+}
+
+/// Apply metadata of `entry` to the restored `path`
+fn apply_metadata(path: &Path, entry: &Entry, log: &Logger) -> io::Result<()> {
+    // Only possible for privileged users; just like `tar`, keep going
+    // when not allowed to.
+    match std::os::unix::fs::lchown(path, Some(entry.uid), Some(entry.gid)) {
+        Err(ref e) if e.kind() == io::ErrorKind::PermissionDenied => {}
+        res => res?,
+    }
+
+    for xattr in &entry.xattrs {
+        let name = std::ffi::OsStr::from_bytes(&xattr.name);
+        if let Err(e) = xattr::set(path, name, &xattr.value) {
+            warn!(log, "couldn't restore xattr";
+                  "path" => %path.display(),
+                  "xattr" => %name.to_string_lossy(),
+                  "err" => %e);
+        }
+    }
+
+    // Symlinks don't have permissions of their own
+    if !matches!(entry.kind, EntryKind::Symlink { .. }) {
+        fs::set_permissions(
+            path,
+            fs::Permissions::from_mode(entry.mode & 0o7777),
+        )?;
+    }
+
+    let mtime =
+        filetime::FileTime::from_unix_time(entry.mtime, entry.mtime_nsec);
+    filetime::set_symlink_file_times(path, mtime, mtime)
+}
+
+/// Restore the snapshot described by `tree` into `root`, that must either
+/// not exist or be empty
+///
+/// `read` writes the data stored at the given address into a writer.
+pub(crate) fn restore(
+    root: &Path,
+    tree: &Tree,
+    read: &dyn Fn(DataAddress, &mut dyn Write) -> io::Result<()>,
+    log: &Logger,
+) -> io::Result<()> {
+    fs::create_dir_all(root)?;
+    if fs::read_dir(root)?.next().is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            "restore dir must not exist or be empty",
+        ));
+    }
+
+    for entry in &tree.entries {
+        if let EntryKind::Dir = entry.kind {
+            let path = root.join(entry.rel_path()?);
+            if path != root {
+                fs::create_dir(&path)?;
+            }
+        }
+    }
+
+    for entry in &tree.entries {
+        if let EntryKind::File { ref content, .. } = entry.kind {
+            let path = root.join(entry.rel_path()?);
+            debug!(log, "restoring file"; "path" => %path.display());
+            let mut file = fs::File::create_new(&path)?;
+            if let Some(content) = content {
+                read(content.clone().into(), &mut file)?;
+            }
+            drop(file);
+            apply_metadata(&path, entry, log)?;
+        }
+    }
+
+    for entry in &tree.entries {
+        if let EntryKind::Symlink { ref target } = entry.kind {
+            let path = root.join(entry.rel_path()?);
+            std::os::unix::fs::symlink(
+                std::ffi::OsStr::from_bytes(target),
+                &path,
+            )?;
+            apply_metadata(&path, entry, log)?;
+        }
+    }
+
+    // Children before parents, so that restoring content doesn't
+    // change parent mtimes or fail due to read-only permissions
+    for entry in tree.entries.iter().rev() {
+        if let EntryKind::Dir = entry.kind {
+            let path = root.join(entry.rel_path()?);
+            apply_metadata(&path, entry, log)?;
+        }
+    }
+
+    Ok(())
+}
+
+// vim: foldmethod=marker foldmarker={{{,}}}
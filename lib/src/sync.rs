@@ -0,0 +1,372 @@
+//! Copying names between repositories
+//!
+//! `Repo::sync_to` copies names along with only the chunks they reach,
+//! skipping the chunks already stored in the destination (in any of its
+//! generations). Chunk files are copied verbatim, without being decrypted
+//! (along with the zstd dictionaries, see `dict`), so both repositories must
+//! share the keys and the settings affecting the stored chunks, and no
+//! passphrase is needed.
+//!
+//! `Repo::sync_to_reencoding` lifts that restriction: data chunks are
+//! decrypted and decompressed, then compressed and encrypted again for the
+//! destination. Only the hashing (along with its key, if keyed) must be the
+//! same, as the chunks keep their digests, and so the index chunks (stored
+//! as they are) stay valid.
+//! Chunking settings don't matter, as nothing is rechunked, but names with
+//! chunk sizes in the index can't be copied to a repository older than
+//! version 5.
+//!
+//! Chunks are copied before the name referencing them is written, so an
+//! interrupted sync can be simply restarted.
+
+// {{{ use and mod
+use std::collections::HashSet;
+use std::io;
+use std::sync::Arc;
+
+use slog::{info, trace, FnValue};
+
+use crate::dict;
+use crate::name::Name;
+use crate::reading::{ReadContext, ReadRequest};
+use crate::{
+    ArcDecrypter, ArcEncrypter, DataAddressRef, DataType, DigestRef,
+    Generation, IndexFormat, Repo, SGData,
+};
+// }}}
+
+/// Names and chunks copied by `Repo::sync_to`
+#[derive(Clone, Debug, Default)]
+pub struct SyncResults {
+    /// Names copied
+    pub names: Vec<String>,
+    /// Names skipped, because they already exist in the destination
+    pub skipped_names: Vec<String>,
+    /// Number of chunks copied
+    pub chunks_copied: usize,
+    /// Number of chunks skipped, because they already exist in the
+    /// destination
+    pub chunks_skipped: usize,
+}
+
+/// Setting making the chunks of `src` unreadable in `dst`, if any
+fn format_mismatch(src: &Repo, dst: &Repo) -> Option<&'static str> {
+    let (src_config, dst_config) = (&src.config, &dst.config);
+    if src_config.tags_chunk_codec() != dst_config.tags_chunk_codec() {
+        Some("chunk format")
+    } else if !src_config.tags_chunk_codec()
+        && src_config.compression != dst_config.compression
+    {
+        Some("compression")
+    } else if src_config.encrypt_index != dst_config.encrypt_index {
+        Some("index encryption")
+    } else if !src_config.encryption.same_keys(&dst_config.encryption) {
+        Some("encryption keys")
+    } else {
+        None
+    }
+}
+
+/// How the chunks are transferred
+#[derive(Copy, Clone)]
+enum Transfer<'a> {
+    /// Copied as they are
+    Verbatim,
+    /// Data chunks decoded and encoded again for the destination
+    Reencode {
+        decrypter: &'a ArcDecrypter,
+        encrypter: &'a ArcEncrypter,
+    },
+}
+
+/// Decode a chunk of `src`, and encode it like `dst` stores it
+pub(crate) fn reencode(
+    src: &Repo,
+    dst: &Repo,
+    decrypter: &ArcDecrypter,
+    encrypter: &ArcEncrypter,
+    data: SGData,
+    digest: &[u8],
+    data_type: DataType,
+) -> io::Result<SGData> {
+    let mut data = data;
+    if src.should_encrypt(data_type) {
+        data = decrypter.decrypt(data, digest)?;
+    }
+    if data_type.should_compress() {
+        data = src.compression.decompress(data)?;
+    }
+    let hasher = src
+        .chunk_hasher(Some(decrypter))
+        .expect("decrypter holds the hashing key");
+    // Anything wrong would be stored as valid for good
+    if hasher.calculate_digest(&data) != digest {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "chunk corrupted",
+        ));
+    }
+    if data_type.should_compress() {
+        data = dst.compression.compress(data)?;
+    }
+    if dst.should_encrypt(data_type) {
+        data = encrypter.encrypt(data, digest)?;
+    }
+    Ok(data)
+}
+
+/// Copy a single chunk, unless `dst` has it already
+///
+/// Returns whether the chunk was copied. Like on `write`, chunks found in an
+/// older generation of `dst` are moved to its current one.
+fn copy_chunk(
+    src: &Repo,
+    dst: &Repo,
+    src_generations: &[Generation],
+    dst_generations: &[Generation],
+    digest: &[u8],
+    data_type: DataType,
+    transfer: Transfer<'_>,
+) -> io::Result<bool> {
+    let digest_ref = DigestRef(digest);
+    let cur_gen = dst_generations
+        .last()
+        .expect("destination has a generation")
+        .to_string();
+    let dst_path = dst.chunk_rel_path_by_digest(digest_ref, &cur_gen);
+
+    for gen in dst_generations.iter().rev() {
+        let path = dst.chunk_rel_path_by_digest(digest_ref, &gen.to_string());
+        match dst.aio.read_metadata(path.clone()).wait() {
+            Ok(_) => {
+                if path != dst_path {
+                    trace!(dst.log, "moving to the current generation";
+                           "path" => %path.display());
+                    dst.aio.rename(path, dst_path).wait()?;
+                }
+                return Ok(false);
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    for gen in src_generations.iter().rev() {
+        let path = src.chunk_rel_path_by_digest(digest_ref, &gen.to_string());
+        let data = match src.aio.read(path).wait() {
+            Ok(data) => data,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        let data = match transfer {
+            Transfer::Verbatim => data,
+            Transfer::Reencode {
+                decrypter,
+                encrypter,
+            } => reencode(
+                src, dst, decrypter, encrypter, data, digest, data_type,
+            )?,
+        };
+        trace!(dst.log, "copying chunk"; "path" => %dst_path.display());
+        dst.aio.write_idempotent(dst_path, data).wait()?;
+        return Ok(true);
+    }
+
+    Err(io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("chunk not found: {}", hex::encode(digest)),
+    ))
+}
+
+/// Digests of the chunks reachable from `name`, with their types
+pub(crate) fn reachable_chunks(
+    repo: &Repo,
+    name: &Name,
+    generations: &[Generation],
+    index_decrypter: Option<&ArcDecrypter>,
+) -> io::Result<Vec<(Vec<u8>, DataType)>> {
+    let mut reachable = HashSet::new();
+    reachable.insert(name.digest.clone());
+
+    let accessor = repo.get_recording_chunk_accessor(
+        &mut reachable,
+        index_decrypter.cloned(),
+        Arc::clone(&repo.compression),
+        generations.to_vec(),
+    );
+    ReadContext::new(&accessor).read_recursively(ReadRequest::new(
+        DataType::Data,
+        DataAddressRef {
+            index_level: name.index_level,
+            digest: DigestRef(&name.digest),
+            index_format: name.index_format,
+        },
+        None,
+        repo.log.clone(),
+    ))?;
+    let indexes = accessor.into_indexes();
+
+    Ok(reachable
+        .into_iter()
+        .map(|digest| {
+            let data_type = if indexes.contains(&digest) {
+                DataType::Index
+            } else {
+                DataType::Data
+            };
+            (digest, data_type)
+        })
+        .collect())
+}
+
+/// Copy `names` (all of them, if empty) from `src` to `dst`
+///
+/// With `reencode`, chunks are re-encoded if `dst` can't store them as they
+/// are.
+pub(crate) fn sync(
+    src: &Repo,
+    dst: &Repo,
+    names: &[String],
+    reencode: Option<(&ArcDecrypter, &ArcEncrypter)>,
+) -> io::Result<SyncResults> {
+    let (src_encryption, dst_encryption) =
+        (&src.config.encryption, &dst.config.encryption);
+    if src.config.hashing != dst.config.hashing
+        || ((src_encryption.keyed_hashing() || dst_encryption.keyed_hashing())
+            && !src_encryption.same_keys(dst_encryption))
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "repositories differ in hashing",
+        ));
+    }
+    let transfer = match (format_mismatch(src, dst), reencode) {
+        (None, _) => Transfer::Verbatim,
+        (Some(_), Some((decrypter, encrypter))) => Transfer::Reencode {
+            decrypter,
+            encrypter,
+        },
+        (Some(mismatch), None) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "repositories differ in {}, chunks must be re-encoded",
+                    mismatch
+                ),
+            ))
+        }
+    };
+
+    let src_generations = src.read_generations()?;
+    let mut dst_generations = dst.read_generations()?;
+    if dst_generations.is_empty() {
+        let gen_first = Generation::gen_first();
+        gen_first.write(&dst.aio)?;
+        dst_generations.push(gen_first);
+    }
+    let cur_gen = *dst_generations.last().unwrap();
+    if let Transfer::Verbatim = transfer {
+        // Chunks compressed against them are copied as they are too
+        dict::copy(src, dst)?;
+    }
+
+    let index_decrypter = src.index_decrypter(reencode.map(|(dec, _)| dec))?;
+    // Names are encrypted like the index chunks
+    let name_encrypter = match transfer {
+        _ if !dst.config.encrypt_index => None,
+        Transfer::Reencode { encrypter, .. } => Some(Arc::clone(encrypter)),
+        // The keys are the same
+        Transfer::Verbatim => {
+            Some(dst.encrypter_from(index_decrypter.as_ref())?)
+        }
+    };
+    let dst_names: HashSet<_> = Name::list_all(&dst_generations, &dst.aio)?
+        .into_iter()
+        .collect();
+
+    let mut names = if names.is_empty() {
+        Name::list_all(&src_generations, &src.aio)?
+    } else {
+        names.to_vec()
+    };
+    names.sort();
+    names.dedup();
+
+    let mut results = SyncResults::default();
+    // Chunks already present in, or copied to `dst`
+    let mut synced = HashSet::new();
+    for name_str in names {
+        let name = Name::load_from_any(
+            &name_str,
+            &src_generations,
+            &src.aio,
+            index_decrypter.as_ref(),
+        )?;
+        if dst_names.contains(&name_str) {
+            info!(src.log, "name already exists in the destination";
+                  "name" => &name_str);
+            results.skipped_names.push(name_str);
+            continue;
+        }
+        // Index chunks are copied as they are, never rewritten
+        if name.index_level > 0
+            && name.index_format == IndexFormat::DigestSize
+            && dst.config.index_format() == IndexFormat::Digest
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "{} has chunk sizes in its index, which the destination \
+                     is too old to store; upgrade it with `migrate` first",
+                    name_str
+                ),
+            ));
+        }
+
+        info!(src.log, "syncing"; "name" => &name_str);
+        for (digest, data_type) in reachable_chunks(
+            src,
+            &name,
+            &src_generations,
+            index_decrypter.as_ref(),
+        )? {
+            if synced.contains(&digest) {
+                continue;
+            }
+            let copied = copy_chunk(
+                src,
+                dst,
+                &src_generations,
+                &dst_generations,
+                &digest,
+                data_type,
+                transfer,
+            )
+            .map_err(|e| {
+                io::Error::new(
+                    e.kind(),
+                    format!(
+                        "couldn't copy chunk {} of {}: {}",
+                        hex::encode(&digest),
+                        name_str,
+                        e
+                    ),
+                )
+            })?;
+            if copied {
+                results.chunks_copied += 1;
+            } else {
+                results.chunks_skipped += 1;
+            }
+            synced.insert(digest);
+        }
+
+        name.write_as(&name_str, cur_gen, &dst.aio, name_encrypter.as_ref())?;
+        info!(dst.log, "name synced"; "name" => &name_str,
+              "gen" => FnValue(|_| cur_gen.to_string()));
+        results.names.push(name_str);
+    }
+    Ok(results)
+}
+
+// vim: foldmethod=marker foldmarker={{{,}}}
@@ -25,6 +25,18 @@ impl MyTryFromBytes for box_::PublicKey {
     }
 }
 
+impl MyTryFromBytes for box_::SecretKey {
+    type Err = io::Error;
+    fn try_from(slice: &[u8]) -> Result<Self, Self::Err> {
+        box_::SecretKey::from_slice(slice).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "can't derive SecretKey from invalid binary data",
+            )
+        })
+    }
+}
+
 impl MyTryFromBytes for secretbox::Nonce {
     type Err = io::Error;
     fn try_from(slice: &[u8]) -> Result<Self, Self::Err> {
@@ -49,6 +61,18 @@ impl MyTryFromBytes for pwhash::Salt {
     }
 }
 
+impl MyTryFromBytes for pwhash::argon2id13::Salt {
+    type Err = io::Error;
+    fn try_from(slice: &[u8]) -> Result<Self, Self::Err> {
+        pwhash::argon2id13::Salt::from_slice(slice).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "can't derive Salt from invalid binary data",
+            )
+        })
+    }
+}
+
 impl MyTryFromBytes for Vec<u8> {
     type Err = io::Error;
     fn try_from(slice: &[u8]) -> Result<Self, Self::Err> {
@@ -70,7 +94,7 @@ where
         })
         .and_then(|ref bytes| {
             T::try_from(bytes).map_err(|err| {
-                Error::custom(format!("{}", &err as &dyn (::std::error::Error)))
+                Error::custom(format!("{}", &err as &dyn ::std::error::Error))
             })
         })
 }
@@ -96,7 +120,7 @@ where
         })
         .and_then(|bytes: Vec<u8>| {
             T::try_from(&bytes).map_err(|err| {
-                Error::custom(format!("{}", &err as &dyn (::std::error::Error)))
+                Error::custom(format!("{}", &err as &dyn ::std::error::Error))
             })
         })
 }
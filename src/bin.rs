@@ -15,12 +15,20 @@
 //!  * flat-file synchronization friendly (Dropbox/syncthing, rsync, rclone)
 //!  * immutable data-conflict-free data store
 //!  * cloud backends are WIP
+//!    * S3-compatible object storage (`s3://bucket/prefix`, credentials
+//!      taken from the usual `AWS_*` environment variables)
+//!    * HTTP (`http://host:port/`), served by the bundled `rdedup-server`,
+//!      or read-only by any static web server
 //!  * incremental, scalable garbage collection
 //!  * variety of supported algorithms:
 //!    * chunking: fastcdc, gear, bup
 //!    * hashing: blake2b, sha256
-//!    * compression: zstd, deflate, xz2, bzip2, none
-//!    * encryption: curve25519, none
+//!    * compression: zstd, deflate, xz2, bzip2, lz4, brotli, none
+//!      (data that doesn't compress is stored as it is), and auto: zstd at
+//!      a level adjusted to how fast the backend takes the data in (within
+//!      `--auto-min-level` and `--auto-max-level`; `store` and `backup` report
+//!      how many chunks were compressed at every level)
+//!    * encryption: curve25519, xchacha20poly1305, none
 //!    * very easy to add new ones
 //!    * check `rdedup init --help` output for up-to-date list
 //!  * extreme performance and parallelism - see [Rust fearless concurrency in `rdedup`](https://dpc.pw/blog/2017/04/rusts-fearless-concurrency-in-rdedup/)
@@ -38,10 +46,9 @@
 //!
 //! ## Shortcomings and missing features:
 //!
-//! `rdedup` currently does not implement own backup/restore functionality (own
-//! directory traversal), and because of that it's typically paired with `tar`
-//! or `rdup` tools. Built-in directory traversal could improve deduplication
-//! ratio for workloads with many small, frequently changing files.
+//! `rdedup backup`/`rdedup restore` implement only basic directory traversal
+//! (no hard links, devices, fifos etc.), so for anything fancier `rdedup` is
+//! still best paired with `tar` or `rdup` tools.
 //!
 //! Cloud storage integrations are missing. The architecture to support it is
 //! mostly implemented, but the actual backends are not.
@@ -84,13 +91,72 @@
 //!   * `rdedup init --help` for repository configuration options.
 //! * `rdedup store <name>` - store data from standard input under a given
 //!   *name*.
+//!   * `--tag KEY=VALUE` (repeatable) and `--description TEXT` attach metadata
+//!     to the *name*; `backup` accepts them too.
 //! * `rdedup load <name>` - load data stored under given *name* and write it to
 //!   standard output.
+//! * `rdedup backup <name> <dir>` - store a snapshot of directory `dir` (file
+//!   contents along with their metadata) under a given *name* (unix only).
+//! * `rdedup restore <name> <dir>` - restore a snapshot stored under given
+//!   *name* into directory `dir` (unix only).
 //! * `rdedup rm <name>` - remove the given *name*.
 //! * `rdedup ls` - list all stored names.
+//!   * `--tag KEY[=VALUE]` lists only the names with matching tags, `-l` also
+//!     prints creation time, size, tags and description of every *name*
+//!     (tab-separated, with tabs, newlines and backslashes escaped).
+//! * `rdedup mount <dir>` - mount the *repo* as a read-only filesystem at `dir`,
+//!   with every *name* presented as a file (unix only; requires FUSE). Names
+//!   stored by older versions, or before `rdedup migrate` upgraded the *repo*,
+//!   can't be read from it.
+//! * `rdedup prune --keep-daily 7 --keep-weekly 4` - remove the names not kept
+//!   by a retention policy (`--dry-run` to only list them).
 //! * `rdedup gc` - remove any no longer reachable data.
+//! * `rdedup check` - check the integrity of the whole *repo*: every stored
+//!   chunk, and every *name* (`--json` for machine-readable output).
+//! * `rdedup repair --from <uri>` - replace damaged chunks with intact copies
+//!   from another *repo* with identical configuration (eg. an offsite copy).
+//! * `rdedup migrate` - rewrite all the data with other settings (eg.
+//!   `--compression zstd --compression-level 10 --nesting 3`); resumable.
+//!   Without any settings, upgrades a *repo* created by an older version.
+//! * `rdedup train-dict` - train a zstd dictionary from the stored data, to
+//!   compress new data against; much better compression of small chunks.
+//!   Writing then takes the passphrase (it can't be done with just the public
+//!   key, eg. after `key strip`).
+//! * `rdedup key add <slot>` - let another passphrase unlock the *repo*, eg.
+//!   for another person, or as a recovery key kept offline.
+//!   * `rdedup key list`, `rdedup key remove <slot>` manage the key slots, and
+//!     `rdedup change_passphrase --slot <slot>` changes the passphrase of one.
+//!   * `rdedup change_passphrase --pwhash-alg argon2id` also upgrades the
+//!     passphrase hashing, without rewriting any data.
+//! * `rdedup key export [--seal] [file]` - export the secret key, to be kept
+//!   safe apart from the *repo*, in case all the passphrases are forgotten, or
+//!   the sealed key in `config.yml` gets damaged.
+//!   * `--keyfile <file>` unlocks the *repo* with it (eg. for `restore`), and
+//!     `rdedup key import <slot> <file>` adds it as a new key slot.
+//! * `rdedup --keyfile <file> key strip` - remove all the key slots, so the
+//!   `config.yml` can be shared with backup clients that only write to the
+//!   *repo* (encrypt-only); reading it takes the key file.
+//! * `rdedup init --encryption xchacha20poly1305` - encrypt with a secret
+//!   key, which also keys the chunk digests, so the stored data doesn't
+//!   reveal whether some known file is in the *repo* (writing takes the
+//!   passphrase too).
+//! * `rdedup init --encryption curve25519 --encrypt-index` - encrypt the
+//!   index and the *names* too, hiding the structure of the stored data
+//!   (sizes, shared chunks, history), though not the *names* themselves;
+//!   `gc`, `prune`, `sync` and `ls -l` then take the passphrase.
+//! * `rdedup sync --to <uri> [name...]` - copy *names* to another *repo* with
+//!   identical configuration, transferring only the data it's missing.
+//!   * `--reencode` allows different keys and compression (but not hashing),
+//!     decrypting and encrypting the data again.
 //!
 //!
+//! Directory snapshots can be stored and restored directly:
+//!
+//! ```norust
+//! rdedup backup home "$HOME"
+//! rdedup restore home "$HOME.restored"
+//! ```
+//!
 //! In combination with [rdup][rdup] this can be used to store and restore your
 //! backup like this:
 //!
@@ -99,6 +165,15 @@
 //! rdedup load home | rdup-up "$HOME.restored"
 //! ```
 //!
+//! Single files can be retrieved from a stored archive without loading all of
+//! it, as only the chunks being read are fetched from a mounted *repo*:
+//!
+//! ```norust
+//! rdedup mount /mnt/rdedup &
+//! tar -xf /mnt/rdedup/home-tar home/user/notes.txt
+//! fusermount -u /mnt/rdedup
+//! ```
+//!
 //! `rdedup` is data agnostic, so formats like `tar`, `cpio` and other will
 //! work,
 //! but to get benefits of deduplication, archive format should not be
@@ -120,9 +195,12 @@
 
 use clap::{Parser, Subcommand};
 use slog::{info, o, Drain};
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::sync::Arc;
-use std::{env, io, path::PathBuf, process};
+use std::{env, fs, io, process};
 use url::Url;
 
 use crate::lib::settings;
@@ -142,6 +220,53 @@ fn parse_url(s: &str) -> io::Result<Url> {
     })
 }
 
+fn encryption_from_str(s: &str) -> settings::Encryption {
+    match s {
+        "curve25519" => settings::Encryption::Curve25519,
+        "xchacha20poly1305" => settings::Encryption::XChaCha20Poly1305,
+        "none" => settings::Encryption::None,
+        _ => {
+            eprintln!("unsupported encryption: {}", s);
+            process::exit(-1)
+        }
+    }
+}
+
+/// `auto_min_level` and `auto_max_level` are the bounds of `auto`, if set
+#[cfg_attr(not(feature = "with-zstd"), allow(unused_variables))]
+fn compression_from_str(
+    s: &str,
+    auto_min_level: Option<i32>,
+    auto_max_level: Option<i32>,
+) -> settings::Compression {
+    match s {
+        #[cfg(feature = "with-deflate")]
+        "deflate" => settings::Compression::Deflate,
+        #[cfg(feature = "with-xz2")]
+        "xz2" => settings::Compression::Xz2,
+        #[cfg(feature = "with-zstd")]
+        "zstd" => settings::Compression::Zstd,
+        #[cfg(feature = "with-zstd")]
+        "auto" => settings::Compression::Auto {
+            min_level: auto_min_level
+                .unwrap_or(settings::Compression::AUTO_MIN_LEVEL),
+            max_level: auto_max_level
+                .unwrap_or(settings::Compression::AUTO_MAX_LEVEL),
+        },
+        #[cfg(feature = "with-bzip2")]
+        "bzip2" => settings::Compression::Bzip2,
+        #[cfg(feature = "with-lz4")]
+        "lz4" => settings::Compression::Lz4,
+        #[cfg(feature = "with-brotli")]
+        "brotli" => settings::Compression::Brotli,
+        "none" => settings::Compression::None,
+        _ => {
+            eprintln!("unsupported compression: {}", s);
+            process::exit(-1)
+        }
+    }
+}
+
 #[derive(Clone)]
 struct Options {
     url: Url,
@@ -159,39 +284,23 @@ impl Options {
     }
 
     fn set_encryption(&mut self, s: &str) {
-        let encryption = match s {
-            "curve25519" => settings::Encryption::Curve25519,
-            "none" => settings::Encryption::None,
-            _ => {
-                eprintln!("unsupported encryption: {}", s);
-                process::exit(-1)
-            }
-        };
-
         self.settings
-            .set_encryption(encryption)
+            .set_encryption(encryption_from_str(s))
             .expect("wrong encryption");
     }
 
-    fn set_compression(&mut self, s: &str) {
-        let compression = match s {
-            #[cfg(feature = "with-deflate")]
-            "deflate" => settings::Compression::Deflate,
-            #[cfg(feature = "with-xz2")]
-            "xz2" => settings::Compression::Xz2,
-            #[cfg(feature = "with-zstd")]
-            "zstd" => settings::Compression::Zstd,
-            #[cfg(feature = "with-bzip2")]
-            "bzip2" => settings::Compression::Bzip2,
-            "none" => settings::Compression::None,
-            _ => {
-                eprintln!("unsupported compression: {}", s);
-                process::exit(-1)
-            }
-        };
-
+    fn set_compression(
+        &mut self,
+        s: &str,
+        auto_min_level: Option<i32>,
+        auto_max_level: Option<i32>,
+    ) {
         self.settings
-            .set_compression(compression)
+            .set_compression(compression_from_str(
+                s,
+                auto_min_level,
+                auto_max_level,
+            ))
             .expect("wrong compression");
     }
 
@@ -259,6 +368,64 @@ fn validate_nesting(s: &str) -> Result<(), String> {
     Ok(())
 }
 
+fn parse_tag(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((key, value)) if !key.is_empty() => {
+            Ok((key.to_owned(), value.to_owned()))
+        }
+        _ => Err("tag must be in the KEY=VALUE format".into()),
+    }
+}
+
+fn parse_tag_filter(s: &str) -> Result<(String, Option<String>), String> {
+    match s.split_once('=') {
+        Some((key, value)) if !key.is_empty() => {
+            Ok((key.to_owned(), Some(value.to_owned())))
+        }
+        None if !s.is_empty() => Ok((s.to_owned(), None)),
+        _ => Err("tag filter must be in the KEY or KEY=VALUE format".into()),
+    }
+}
+
+fn parse_group_by(s: &str) -> Result<lib::PruneGroupBy, String> {
+    match s {
+        "none" => Ok(lib::PruneGroupBy::None),
+        "prefix" => Ok(lib::PruneGroupBy::Prefix),
+        _ => match s.strip_prefix("tag:") {
+            Some(key) if !key.is_empty() => {
+                Ok(lib::PruneGroupBy::Tag(key.to_owned()))
+            }
+            _ => Err("must be `none`, `prefix` or `tag:KEY`".into()),
+        },
+    }
+}
+
+/// Escape a field of tab-separated output, so it can't span more fields or
+/// lines
+fn escape_field(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r")
+}
+
+fn name_metadata(
+    tags: Vec<(String, String)>,
+    description: Option<String>,
+) -> lib::NameMetadata {
+    lib::NameMetadata {
+        tags: tags.into_iter().collect(),
+        description,
+    }
+}
+
+/// Print how many new chunks `auto` compression stored at every level
+fn print_compression_levels(levels: &BTreeMap<i32, usize>) {
+    for (level, chunks) in levels {
+        println!("{} chunks compressed at level {}", chunks, level);
+    }
+}
+
 fn create_logger(verbosity: u32, timing_verbosity: u32) -> slog::Logger {
     match (verbosity, timing_verbosity) {
         (0, 0) => slog::Logger::root(slog::Discard, o!()),
@@ -341,6 +508,11 @@ struct CliOpts {
     /// Rdedup repository URI. Override the `RDEDUP_URI` environment variable
     repo_uri: Option<std::ffi::OsString>,
 
+    #[clap(short = 'k', long, value_name = "PATH")]
+    /// Read the secret key from a key file (see `key export`) instead of
+    /// the repository config
+    keyfile: Option<PathBuf>,
+
     #[clap(short = 'v', parse(from_occurrences))]
     /// Increase debugging level for general messages
     verbose: u8,
@@ -379,7 +551,10 @@ enum Command {
 
         #[clap(
             long,
-            possible_values = &["deflate", "xz2", "zstd", "bzip2", "none"],
+            possible_values = &[
+                "deflate", "xz2", "zstd", "bzip2", "lz4", "brotli", "auto",
+                "none"
+            ],
             default_value = "zstd",
             value_name = "SCHEME",
         )]
@@ -391,15 +566,28 @@ enum Command {
         /// positive ones "smaller"
         compression_level: i32,
 
+        #[clap(long, value_name = "N")]
+        /// Set the lowest zstd level of auto compression [default: 1]
+        auto_min_level: Option<i32>,
+
+        #[clap(long, value_name = "N")]
+        /// Set the highest zstd level of auto compression [default: 19]
+        auto_max_level: Option<i32>,
+
         #[clap(
             long,
-            possible_values = &["curve25519", "none"],
+            possible_values = &["curve25519", "xchacha20poly1305", "none"],
             default_value = "none",
             value_name = "SCHEME",
         )]
         /// Set encryptiopn scheme
         encryption: String,
 
+        #[clap(long)]
+        /// Encrypt the index and names too, hiding the structure of the
+        /// stored data (then `gc`, `prune` and `ls -l` take the passphrase)
+        encrypt_index: bool,
+
         #[clap(
             long,
             possible_values = &["sha256", "blake2b"],
@@ -421,6 +609,24 @@ enum Command {
         )]
         /// Set pwhash strength
         pwhash: String,
+
+        #[clap(
+            long,
+            possible_values = &["scrypt", "argon2id"],
+            default_value = "scrypt",
+            value_name = "ALG",
+        )]
+        /// Set pwhash algorithm
+        pwhash_alg: String,
+
+        #[clap(long, value_name = "SIZE")]
+        /// Set pwhash memory limit (instead of the one set by the strength)
+        pwhash_mem_limit: Option<String>,
+
+        #[clap(long, value_name = "N")]
+        /// Set pwhash operations limit (instead of the one set by the
+        /// strength)
+        pwhash_ops_limit: Option<u64>,
     },
 
     /// Store data to repository
@@ -428,6 +634,14 @@ enum Command {
         #[clap(name = "NAME")]
         /// Name to store to
         name: String,
+
+        #[clap(long = "tag", value_name = "KEY=VALUE", parse(try_from_str = parse_tag))]
+        /// Attach a tag to the name (can be repeated)
+        tags: Vec<(String, String)>,
+
+        #[clap(long, value_name = "TEXT")]
+        /// Attach a description to the name
+        description: Option<String>,
     },
 
     /// Load data from repository
@@ -437,9 +651,71 @@ enum Command {
         name: String,
     },
 
+    #[cfg(unix)]
+    /// Store a snapshot of a directory to repository
+    Backup {
+        #[clap(name = "NAME")]
+        /// Name to store to
+        name: String,
+
+        #[clap(name = "DIR")]
+        /// Directory to store
+        dir: PathBuf,
+
+        #[clap(long = "tag", value_name = "KEY=VALUE", parse(try_from_str = parse_tag))]
+        /// Attach a tag to the name (can be repeated)
+        tags: Vec<(String, String)>,
+
+        #[clap(long, value_name = "TEXT")]
+        /// Attach a description to the name
+        description: Option<String>,
+    },
+
+    #[cfg(unix)]
+    /// Restore a snapshot of a directory from repository
+    Restore {
+        #[clap(name = "NAME")]
+        /// Name to restore from
+        name: String,
+
+        #[clap(name = "DIR")]
+        /// Directory to restore to; must not exist or be empty
+        dir: PathBuf,
+    },
+
     #[clap(visible_alias = "ls")]
     /// List names stored in the repository
-    List,
+    List {
+        #[clap(
+            long = "tag",
+            value_name = "KEY[=VALUE]",
+            parse(try_from_str = parse_tag_filter)
+        )]
+        /// Only list names having the tag (with the value, if given); can be
+        /// repeated to require all of them
+        tags: Vec<(String, Option<String>)>,
+
+        #[clap(short = 'l', long)]
+        /// Print creation time, size, tags and description of every name
+        long: bool,
+    },
+
+    #[cfg(all(unix, feature = "fuse"))]
+    /// Mount the repository as a read-only filesystem
+    Mount {
+        #[clap(name = "DIR")]
+        /// Directory to mount at
+        mountpoint: PathBuf,
+
+        #[clap(
+            long,
+            validator = validate_chunk_size,
+            default_value = "256M",
+            value_name = "N"
+        )]
+        /// Set maximum size of decrypted data cached in memory
+        cache_size: String,
+    },
 
     #[clap(visible_alias = "rm")]
     /// Remove names stored in the repository
@@ -449,9 +725,111 @@ enum Command {
         names: Vec<String>,
     },
 
+    /// Rewrite all the data with other compression, encryption or nesting
+    ///
+    /// The repository is locked for the whole time. If interrupted, run
+    /// `migrate` again (with any options) to resume; until then the
+    /// repository can't be used. Enabling encryption generates new keys and
+    /// asks for their passphrase.
+    Migrate {
+        #[clap(
+            long,
+            possible_values = &[
+                "deflate", "xz2", "zstd", "bzip2", "lz4", "brotli", "auto",
+                "none"
+            ],
+            value_name = "SCHEME",
+        )]
+        /// Set compression scheme
+        compression: Option<String>,
+
+        #[clap(long, value_name = "N")]
+        /// Set compression level (0 if only the scheme is set)
+        compression_level: Option<i32>,
+
+        #[clap(long, value_name = "N")]
+        /// Set the lowest zstd level of auto compression [default: 1]
+        auto_min_level: Option<i32>,
+
+        #[clap(long, value_name = "N")]
+        /// Set the highest zstd level of auto compression [default: 19]
+        auto_max_level: Option<i32>,
+
+        #[clap(
+            long,
+            possible_values = &["curve25519", "xchacha20poly1305", "none"],
+            value_name = "SCHEME",
+        )]
+        /// Set encryption scheme
+        encryption: Option<String>,
+
+        #[clap(long, validator = validate_nesting, value_name = "N")]
+        /// Set level of folder nesting
+        nesting: Option<u8>,
+    },
+
+    #[cfg(feature = "with-zstd")]
+    /// Train a zstd dictionary from the stored data, to compress new data
+    /// against
+    ///
+    /// Improves the compression of small chunks (low `--chunk-size`) a lot.
+    /// Requires zstd or auto compression; data stored before keeps using the
+    /// dictionary (if any) it was compressed against.
+    TrainDict {
+        #[clap(
+            long,
+            validator = validate_chunk_size,
+            default_value = "112K",
+            value_name = "N"
+        )]
+        /// Set maximum size of the dictionary
+        size: String,
+    },
+
     #[clap(name = "change_passphrase", visible_alias = "chpasswd")]
     /// Change the passphrase protecting the encryption key (if any)
-    ChangePassphrase,
+    ///
+    /// With any of the `--pwhash*` options, the way the passphrase is hashed
+    /// is changed too (eg. upgraded to `argon2id`), and becomes the default
+    /// for new key slots.
+    ChangePassphrase {
+        #[clap(long, value_name = "NAME")]
+        /// Key slot to change (by default, the one the current passphrase
+        /// unlocks)
+        slot: Option<String>,
+
+        #[clap(
+            long,
+            possible_values = &["strong", "interactive", "weak"],
+            value_name = "STRENGTH",
+        )]
+        /// Set pwhash strength
+        pwhash: Option<String>,
+
+        #[clap(
+            long,
+            possible_values = &["scrypt", "argon2id"],
+            value_name = "ALG",
+        )]
+        /// Set pwhash algorithm (scrypt if only other options are given)
+        pwhash_alg: Option<String>,
+
+        #[clap(long, value_name = "SIZE")]
+        /// Set pwhash memory limit (instead of the one set by the strength)
+        pwhash_mem_limit: Option<String>,
+
+        #[clap(long, value_name = "N")]
+        /// Set pwhash operations limit (instead of the one set by the
+        /// strength)
+        pwhash_ops_limit: Option<u64>,
+    },
+
+    /// Manage key slots, each unlocking the repository with its own
+    /// passphrase
+    Key {
+        #[clap(subcommand)]
+        command: KeyCommand,
+    },
 
     /// Calculate disk usage due to the data stored for a set of names
     Du {
@@ -460,6 +838,43 @@ enum Command {
         names: Vec<String>,
     },
 
+    #[clap(setting = clap::AppSettings::DeriveDisplayOrder)]
+    /// Remove names according to a retention policy
+    ///
+    /// A name is kept if any of the `--keep-*` options keeps it. The data of
+    /// removed names is reclaimed by the next `gc`.
+    Prune {
+        #[clap(long, default_value = "0", value_name = "N")]
+        /// Keep N most recent names
+        keep_last: usize,
+
+        #[clap(long, default_value = "0", value_name = "N")]
+        /// Keep the most recent name of each of the last N days
+        keep_daily: usize,
+
+        #[clap(long, default_value = "0", value_name = "N")]
+        /// Keep the most recent name of each of the last N weeks
+        keep_weekly: usize,
+
+        #[clap(long, default_value = "0", value_name = "N")]
+        /// Keep the most recent name of each of the last N months
+        keep_monthly: usize,
+
+        #[clap(
+            long,
+            default_value = "none",
+            value_name = "GROUPING",
+            parse(try_from_str = parse_group_by)
+        )]
+        /// Apply the policy separately to groups of names: `none`, `prefix`
+        /// (name up to its first digit) or `tag:KEY` (value of a tag)
+        group_by: lib::PruneGroupBy,
+
+        #[clap(short = 'n', long)]
+        /// Only print the names that would be removed
+        dry_run: bool,
+    },
+
     /// Garbage collect unreferenced chunks
     Gc {
         #[clap(
@@ -477,6 +892,158 @@ enum Command {
         /// Names to verify
         names: Vec<String>,
     },
+
+    /// Check integrity of the whole repository
+    ///
+    /// Every stored chunk is verified, and every name is checked to
+    /// reference only stored chunks. Exits with an error if anything but
+    /// orphaned chunks (removed by `gc`) is found.
+    Check {
+        #[clap(long)]
+        /// Print the results as JSON
+        json: bool,
+    },
+
+    /// Replace damaged chunks with intact copies from another repository
+    ///
+    /// The other repository must have identical configuration and keys, eg.
+    /// be a copy of this one. Without names, all the chunks found damaged by
+    /// `check` are repaired.
+    Repair {
+        #[clap(long, value_name = "URI")]
+        /// Repository to copy the chunks from
+        from: String,
+
+        #[clap(name = "NAME")]
+        /// Names to repair
+        names: Vec<String>,
+    },
+
+    /// Copy names and the data they need to another repository
+    ///
+    /// Only the chunks missing in the other repository are copied. They are
+    /// copied without decrypting, so the other repository must use the same
+    /// keys and settings, eg. be created by copying `config.yml` of this one
+    /// to an empty directory, unless `--reencode` is used. Without names,
+    /// all of them are copied.
+    Sync {
+        #[clap(long, value_name = "URI")]
+        /// Repository to copy to
+        to: String,
+
+        #[clap(long)]
+        /// Decrypt and decompress the chunks, then compress and encrypt them
+        /// again, if the other repository uses different keys or compression
+        /// (it must use the same hashing)
+        reencode: bool,
+
+        #[clap(name = "NAME")]
+        /// Names to copy
+        names: Vec<String>,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+#[clap(setting = clap::AppSettings::DeriveDisplayOrder)]
+enum KeyCommand {
+    /// List the key slots
+    #[clap(visible_alias = "ls")]
+    List,
+
+    /// Add a key slot with a new passphrase
+    ///
+    /// Asks for a passphrase of any existing slot first.
+    Add {
+        #[clap(name = "NAME")]
+        /// Name of the new slot
+        name: String,
+    },
+
+    /// Add a key slot with a new passphrase, from a key file
+    ///
+    /// Restores access to the repository if all the passphrases are lost.
+    Import {
+        #[clap(name = "NAME")]
+        /// Name of the new slot
+        name: String,
+
+        #[clap(name = "FILE")]
+        /// Key file written by `key export`
+        file: PathBuf,
+    },
+
+    /// Export the secret key to a key file
+    ///
+    /// Anyone holding the key file can read the repository, so keep it
+    /// safe, and apart from the repository. Use it with `--keyfile`, or to
+    /// `key import` it.
+    Export {
+        #[clap(long)]
+        /// Seal the key with a passphrase of the key file's own
+        seal: bool,
+
+        #[clap(name = "FILE")]
+        /// File to write (must not exist); standard output if not given
+        file: Option<PathBuf>,
+    },
+
+    /// Remove all the key slots, making the repository encrypt-only
+    ///
+    /// The repository can then be written to without any passphrase, but
+    /// read only with the key file given with `--keyfile`, which must hold
+    /// its secret key. Use on repositories shared with backup clients that
+    /// shouldn't be able to read them.
+    Strip,
+
+    /// Remove a key slot
+    ///
+    /// Asks for a passphrase of any of the remaining slots, so the last way
+    /// to unlock the repository can't be removed.
+    #[clap(visible_alias = "rm")]
+    Remove {
+        #[clap(name = "NAME")]
+        /// Name of the slot to remove
+        name: String,
+    },
+}
+
+fn parse_pwhash_mem_limit(s: &str) -> u64 {
+    util::parse_size(s).expect("Invalid pwhash memory limit option")
+}
+
+/// Unlock `repo` for reading, with the key file if given
+fn unlock_decrypt(
+    repo: &Repo,
+    keyfile: &Option<PathBuf>,
+) -> io::Result<lib::DecryptHandle> {
+    match *keyfile {
+        Some(ref path) => repo.unlock_decrypt_with_keyfile(
+            &fs::read_to_string(path)?,
+            &read_passphrase,
+        ),
+        None => repo.unlock_decrypt(&read_passphrase),
+    }
+}
+
+/// `repo` able to read its index, unlocked if it's encrypted
+fn unlock_index(repo: Repo, keyfile: &Option<PathBuf>) -> io::Result<Repo> {
+    if !repo.is_index_encrypted() {
+        return Ok(repo);
+    }
+    let dec = unlock_decrypt(&repo, keyfile)?;
+    Ok(repo.unlock_index(&dec))
+}
+
+/// Write a new key file, readable only by the owner
+fn write_keyfile(path: &Path, keyfile: &str) -> io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)?.write_all(keyfile.as_bytes())
 }
 
 fn create_backend(
@@ -552,6 +1119,8 @@ fn run() -> io::Result<()> {
 
     let mut options = Options::new(url, cache_dir);
 
+    let keyfile = cli_opts.keyfile;
+
     let log =
         create_logger(cli_opts.verbose as u32, cli_opts.verbose_timings as u32);
 
@@ -560,9 +1129,15 @@ fn run() -> io::Result<()> {
             chunking,
             chunk_size,
             encryption,
+            encrypt_index,
             pwhash,
+            pwhash_alg,
+            pwhash_mem_limit,
+            pwhash_ops_limit,
             compression,
             compression_level,
+            auto_min_level,
+            auto_max_level,
             nesting,
             hashing,
         } => {
@@ -573,10 +1148,26 @@ fn run() -> io::Result<()> {
             );
             options.set_chunking(&chunking, chunk_size);
             options.set_encryption(&encryption);
+            options.settings.set_encrypt_index(encrypt_index);
             options
                 .settings
                 .set_pwhash(settings::PWHash::from(pwhash.as_str()));
-            options.set_compression(&compression);
+            options
+                .settings
+                .set_pwhash_alg(settings::PWHashAlg::from(pwhash_alg.as_str()));
+            if let Some(mem_limit) = pwhash_mem_limit {
+                options
+                    .settings
+                    .set_pwhash_mem_limit(parse_pwhash_mem_limit(&mem_limit));
+            }
+            if let Some(ops_limit) = pwhash_ops_limit {
+                options.settings.set_pwhash_ops_limit(ops_limit);
+            }
+            options.set_compression(
+                &compression,
+                auto_min_level,
+                auto_max_level,
+            );
             options.settings.set_compression_level(compression_level);
             options.set_nesting(nesting);
             options.set_hashing(&hashing);
@@ -605,26 +1196,221 @@ fn run() -> io::Result<()> {
                 log,
             )?;
         }
-        Command::Store { name } => {
+        Command::Store {
+            name,
+            tags,
+            description,
+        } => {
             let repo =
                 Repo::open(Arc::new(move || create_backend(&options)), log)?;
             let enc = repo.unlock_encrypt(&read_passphrase)?;
-            let stats = repo.write(&name, io::stdin(), &enc)?;
+            let stats = repo.write_with_metadata(
+                &name,
+                io::stdin(),
+                &enc,
+                name_metadata(tags, description),
+            )?;
             println!("{} new chunks", stats.new_chunks);
             println!("{} new bytes", stats.new_bytes);
+            print_compression_levels(&stats.compression_levels);
         }
         Command::Load { name } => {
             let repo =
                 Repo::open(Arc::new(move || create_backend(&options)), log)?;
-            let dec = repo.unlock_decrypt(&read_passphrase)?;
+            let dec = unlock_decrypt(&repo, &keyfile)?;
             repo.read(&name, &mut io::stdout(), &dec)?;
         }
-        Command::ChangePassphrase => {
+        #[cfg(unix)]
+        Command::Backup {
+            name,
+            dir,
+            tags,
+            description,
+        } => {
+            let repo =
+                Repo::open(Arc::new(move || create_backend(&options)), log)?;
+            let enc = repo.unlock_encrypt(&read_passphrase)?;
+            let stats = repo.write_snapshot_with_metadata(
+                &name,
+                &dir,
+                &enc,
+                name_metadata(tags, description),
+            )?;
+            println!("{} new chunks", stats.new_chunks);
+            println!("{} new bytes", stats.new_bytes);
+            print_compression_levels(&stats.compression_levels);
+        }
+        #[cfg(unix)]
+        Command::Restore { name, dir } => {
+            let repo =
+                Repo::open(Arc::new(move || create_backend(&options)), log)?;
+            let dec = unlock_decrypt(&repo, &keyfile)?;
+            repo.read_snapshot(&name, &dir, &dec)?;
+        }
+        #[cfg(all(unix, feature = "fuse"))]
+        Command::Mount {
+            mountpoint,
+            cache_size,
+        } => {
+            let repo =
+                Repo::open(Arc::new(move || create_backend(&options)), log)?;
+            let dec = unlock_decrypt(&repo, &keyfile)?;
+            let cache_size = util::parse_size(&cache_size)
+                .expect("Invalid cache size option");
+            repo.mount(&mountpoint, &dec, cache_size as usize)?;
+        }
+        Command::Migrate {
+            compression,
+            compression_level,
+            auto_min_level,
+            auto_max_level,
+            encryption,
+            nesting,
+        } => {
+            let mut migration = settings::Migration::new();
+            if let Some(compression) = compression {
+                migration.set_compression(compression_from_str(
+                    &compression,
+                    auto_min_level,
+                    auto_max_level,
+                ));
+            }
+            if let Some(level) = compression_level {
+                migration.set_compression_level(level);
+            }
+            if let Some(encryption) = encryption {
+                migration.set_encryption(encryption_from_str(&encryption));
+            }
+            if let Some(nesting) = nesting {
+                migration.set_nesting(nesting)?;
+            }
+            Repo::migrate(
+                Arc::new(move || create_backend(&options)),
+                &read_passphrase,
+                &read_new_passphrase,
+                &migration,
+                log,
+            )?;
+        }
+        #[cfg(feature = "with-zstd")]
+        Command::TrainDict { size } => {
+            let mut repo =
+                Repo::open(Arc::new(move || create_backend(&options)), log)?;
+            let dec = unlock_decrypt(&repo, &keyfile)?;
+            let size = util::parse_size(&size).expect("Invalid size option");
+            let id = repo.train_dictionary(&dec, size as usize)?;
+            println!("trained dictionary {:08x}", id);
+        }
+        Command::ChangePassphrase {
+            slot,
+            pwhash,
+            pwhash_alg,
+            pwhash_mem_limit,
+            pwhash_ops_limit,
+        } => {
+            let mut repo =
+                Repo::open(Arc::new(move || create_backend(&options)), log)?;
+            let new_pwhash = if pwhash.is_some()
+                || pwhash_alg.is_some()
+                || pwhash_mem_limit.is_some()
+                || pwhash_ops_limit.is_some()
+            {
+                let mut settings = settings::PWHashSettings::new();
+                if let Some(pwhash) = pwhash {
+                    settings.set_strength(settings::PWHash::from(&*pwhash));
+                }
+                if let Some(alg) = pwhash_alg {
+                    settings.set_alg(settings::PWHashAlg::from(&*alg));
+                }
+                if let Some(mem_limit) = pwhash_mem_limit {
+                    settings.set_mem_limit(parse_pwhash_mem_limit(&mem_limit));
+                }
+                if let Some(ops_limit) = pwhash_ops_limit {
+                    settings.set_ops_limit(ops_limit);
+                }
+                Some(settings)
+            } else {
+                None
+            };
+            match (slot, new_pwhash) {
+                (slot, Some(new_pwhash)) => repo
+                    .change_passphrase_with_pwhash(
+                        slot.as_deref(),
+                        &read_passphrase,
+                        &read_new_passphrase,
+                        &new_pwhash,
+                    )?,
+                (Some(slot), None) => repo.change_key_slot_passphrase(
+                    &slot,
+                    &read_passphrase,
+                    &read_new_passphrase,
+                )?,
+                (None, None) => repo
+                    .change_passphrase(&read_passphrase, &|| {
+                        read_new_passphrase()
+                    })?,
+            }
+        }
+        Command::Key { command } => {
             let mut repo =
                 Repo::open(Arc::new(move || create_backend(&options)), log)?;
-            repo.change_passphrase(&read_passphrase, &|| {
-                read_new_passphrase()
-            })?;
+            match command {
+                KeyCommand::List => {
+                    if repo.is_encrypt_only() {
+                        eprintln!(
+                            "No key slots, the repository is encrypt-only"
+                        );
+                    }
+                    for slot in repo.list_key_slots() {
+                        println!("{}", slot);
+                    }
+                }
+                KeyCommand::Strip => {
+                    let path = keyfile.ok_or_else(|| {
+                        io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            "export the key with `key export` first, and pass \
+                             the key file with `--keyfile`",
+                        )
+                    })?;
+                    repo.make_encrypt_only(
+                        &fs::read_to_string(path)?,
+                        &read_passphrase,
+                    )?;
+                }
+                KeyCommand::Add { name } => {
+                    repo.add_key_slot(
+                        &name,
+                        &read_passphrase,
+                        &read_new_passphrase,
+                    )?;
+                }
+                KeyCommand::Import { name, file } => {
+                    repo.import_key(
+                        &name,
+                        &fs::read_to_string(file)?,
+                        &read_passphrase,
+                        &read_new_passphrase,
+                    )?;
+                }
+                KeyCommand::Export { seal, file } => {
+                    let keyfile = if seal {
+                        repo.export_key(
+                            &read_passphrase,
+                            Some(&read_new_passphrase),
+                        )?
+                    } else {
+                        repo.export_key(&read_passphrase, None)?
+                    };
+                    match file {
+                        Some(path) => write_keyfile(&path, &keyfile)?,
+                        None => print!("{}", keyfile),
+                    }
+                }
+                KeyCommand::Remove { name } => {
+                    repo.remove_key_slot(&name, &read_passphrase)?;
+                }
+            }
         }
         Command::Remove { names } => {
             let repo =
@@ -636,7 +1422,7 @@ fn run() -> io::Result<()> {
         Command::Du { names } => {
             let repo =
                 Repo::open(Arc::new(move || create_backend(&options)), log)?;
-            let dec = repo.unlock_decrypt(&read_passphrase)?;
+            let dec = unlock_decrypt(&repo, &keyfile)?;
 
             for name in names {
                 let result = repo.du(&name, &dec)?;
@@ -644,24 +1430,82 @@ fn run() -> io::Result<()> {
                 println!("{} bytes", result.bytes);
             }
         }
+        Command::Prune {
+            keep_last,
+            keep_daily,
+            keep_weekly,
+            keep_monthly,
+            group_by,
+            dry_run,
+        } => {
+            let repo = unlock_index(
+                Repo::open(Arc::new(move || create_backend(&options)), log)?,
+                &keyfile,
+            )?;
+            let policy = lib::PrunePolicy {
+                keep_last,
+                keep_daily,
+                keep_weekly,
+                keep_monthly,
+                group_by,
+            };
+            if dry_run {
+                for name in repo.plan_prune(&policy)?.remove {
+                    println!("would remove {}", name);
+                }
+            } else {
+                for name in repo.prune(&policy)?.remove {
+                    println!("removed {}", name);
+                }
+            }
+        }
         Command::Gc { grace_time } => {
-            let repo =
-                Repo::open(Arc::new(move || create_backend(&options)), log)?;
+            let repo = unlock_index(
+                Repo::open(Arc::new(move || create_backend(&options)), log)?,
+                &keyfile,
+            )?;
 
             repo.gc(grace_time)?;
         }
-        Command::List => {
+        Command::List { tags, long } => {
             let repo =
                 Repo::open(Arc::new(move || create_backend(&options)), log)?;
 
-            for name in repo.list_names()? {
-                println!("{}", name);
+            if tags.is_empty() && !long {
+                for name in repo.list_names()? {
+                    println!("{}", name);
+                }
+                return Ok(());
+            }
+
+            let repo = unlock_index(repo, &keyfile)?;
+            for info in repo.list_names_info(&tags)? {
+                if !long {
+                    println!("{}", info.name);
+                    continue;
+                }
+                let tags: Vec<_> = info
+                    .metadata
+                    .tags
+                    .iter()
+                    .map(|(k, v)| format!("{}={}", k, v))
+                    .collect();
+                println!(
+                    "{}\t{}\t{}\t{}\t{}",
+                    info.name,
+                    info.created.to_rfc3339(),
+                    info.size.map(|s| s.to_string()).unwrap_or_default(),
+                    escape_field(&tags.join(",")),
+                    escape_field(
+                        &info.metadata.description.unwrap_or_default()
+                    ),
+                );
             }
         }
         Command::Verify { names } => {
             let repo =
                 Repo::open(Arc::new(move || create_backend(&options)), log)?;
-            let dec = repo.unlock_decrypt(&read_passphrase)?;
+            let dec = unlock_decrypt(&repo, &keyfile)?;
             for name in names {
                 let results = repo.verify(&name, &dec)?;
                 println!("scanned {} chunk(s)", results.scanned);
@@ -671,6 +1515,75 @@ fn run() -> io::Result<()> {
                 }
             }
         }
+        Command::Check { json } => {
+            let repo =
+                Repo::open(Arc::new(move || create_backend(&options)), log)?;
+            let dec = unlock_decrypt(&repo, &keyfile)?;
+            let results = repo.check(&dec)?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&results)?);
+            } else {
+                for issue in &results.issues {
+                    println!("{}", issue);
+                }
+                println!(
+                    "checked {} chunk(s) and {} name(s)",
+                    results.chunks, results.names
+                );
+                println!(
+                    "found {} problem(s)",
+                    results.issues.iter().filter(|i| i.is_error()).count()
+                );
+            }
+            if results.has_errors() {
+                process::exit(1);
+            }
+        }
+        Command::Repair { from, names } => {
+            let source =
+                Repo::open_from_url(Arc::new(parse_url(&from)?), log.clone())?;
+            let repo =
+                Repo::open(Arc::new(move || create_backend(&options)), log)?;
+            let dec = unlock_decrypt(&repo, &keyfile)?;
+
+            let results = repo.repair_from(&source, &names, &dec)?;
+            for digest in results.repaired {
+                println!("repaired chunk {}", hex::encode(digest));
+            }
+            for (digest, err) in &results.errors {
+                println!("chunk {} - {}", hex::encode(digest), err);
+            }
+            if !results.errors.is_empty() {
+                process::exit(1);
+            }
+        }
+        Command::Sync {
+            to,
+            reencode,
+            names,
+        } => {
+            let dst =
+                Repo::open_from_url(Arc::new(parse_url(&to)?), log.clone())?;
+            let repo =
+                Repo::open(Arc::new(move || create_backend(&options)), log)?;
+            let results = if reencode {
+                let dec = unlock_decrypt(&repo, &keyfile)?;
+                let dst_enc = dst.unlock_encrypt(&read_passphrase)?;
+                repo.sync_to_reencoding(&dst, &names, &dec, &dst_enc)?
+            } else {
+                unlock_index(repo, &keyfile)?.sync_to(&dst, &names)?
+            };
+            for name in &results.names {
+                println!("synced {}", name);
+            }
+            for name in &results.skipped_names {
+                println!("skipped {} (already exists)", name);
+            }
+            println!(
+                "copied {} chunk(s), {} already present",
+                results.chunks_copied, results.chunks_skipped
+            );
+        }
     }
 
     Ok(())
@@ -0,0 +1,75 @@
+//! `rdedup-server` serves a local `rdedup` repository over HTTP.
+//!
+//! Clients use it as an ordinary repository, by its url:
+//!
+//! ```norust
+//! rdedup-server --listen 0.0.0.0:8080 /srv/rdedup &
+//! rdedup -u http://backup-host:8080/ store home < home.tar
+//! ```
+//!
+//! Multiple hosts can store to the same repository at the same time, while
+//! `rdedup gc` and `rdedup rm` get exclusive access, just like with a
+//! repository on a local disk.
+//!
+//! There's no authentication or encryption of the transport, so run it only
+//! in a trusted network, or behind a reverse proxy taking care of it.
+
+use clap::Parser;
+use slog::{o, Drain};
+use std::{path::PathBuf, process};
+
+use rdedup_lib as lib;
+
+#[derive(Debug, Parser)]
+#[clap(author, about = "Serve rdedup repository over HTTP")]
+struct CliOpts {
+    #[clap(
+        short = 'l',
+        long,
+        default_value = "127.0.0.1:8080",
+        value_name = "ADDR"
+    )]
+    /// Address to listen on
+    listen: String,
+
+    #[clap(short = 'v', parse(from_occurrences))]
+    /// Increase debugging level
+    verbose: u8,
+
+    #[clap(value_name = "DIR")]
+    /// Path to rdedup repository
+    dir: PathBuf,
+}
+
+fn create_logger(verbosity: u8) -> slog::Logger {
+    let level = match verbosity {
+        0 => slog::Level::Info,
+        1 => slog::Level::Debug,
+        _ => slog::Level::Trace,
+    };
+    let drain = slog_term::term_full();
+    let drain = slog_async::Async::default(drain.fuse());
+    let drain = slog::LevelFilter::new(drain, level);
+    slog::Logger::root(drain.fuse(), o!())
+}
+
+fn main() {
+    let cli_opts = CliOpts::parse();
+    let log = create_logger(cli_opts.verbose);
+
+    if !cli_opts.dir.join("config.yml").exists() {
+        eprintln!(
+            "Error: {} is not an rdedup repository",
+            cli_opts.dir.display()
+        );
+        process::exit(-1);
+    }
+
+    match lib::server::Server::bind(cli_opts.dir, cli_opts.listen, log) {
+        Ok(server) => server.run(),
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            process::exit(-1);
+        }
+    }
+}